@@ -15,6 +15,7 @@ object-words = build construct object create make
 document-words = picture image video report document file
 description-words = describe modification alteration
 comparison-words = compar differ detect update change
+marked-words = marked annotat
 last-words = last previous recent
 new-words = new latest
 all-words = all every entire complete
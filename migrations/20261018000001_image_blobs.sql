@@ -0,0 +1,52 @@
+-- Content-addressed image blobs, shared by every ImageLeaf with the same hash
+CREATE TABLE IF NOT EXISTS image_blobs (
+    hash         TEXT PRIMARY KEY,
+    storage_path TEXT        NOT NULL,
+    size         BIGINT      NOT NULL,
+    mime_type    TEXT        NOT NULL,
+    ref_count    INTEGER     NOT NULL DEFAULT 0 CHECK (ref_count >= 0),
+    created_at   TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS tree_nodes_image_hash_idx
+    ON tree_nodes ((data ->> 'hash'))
+    WHERE node_type = 'ImageLeaf';
+
+-- Reference counts follow ImageLeaf rows, including cascaded deletes
+CREATE OR REPLACE FUNCTION image_blobs_track_refs() RETURNS trigger AS
+$$
+BEGIN
+    IF TG_OP = 'INSERT' AND NEW.node_type = 'ImageLeaf' AND NEW.data ? 'hash' THEN
+        UPDATE image_blobs SET ref_count = ref_count + 1 WHERE hash = NEW.data ->> 'hash';
+    ELSIF TG_OP = 'DELETE' AND OLD.node_type = 'ImageLeaf' AND OLD.data ? 'hash' THEN
+        UPDATE image_blobs SET ref_count = ref_count - 1
+        WHERE hash = OLD.data ->> 'hash' AND ref_count > 0;
+    END IF;
+    RETURN NULL;
+END
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS tree_nodes_blob_refs ON tree_nodes;
+CREATE TRIGGER tree_nodes_blob_refs
+    AFTER INSERT OR DELETE ON tree_nodes
+    FOR EACH ROW EXECUTE FUNCTION image_blobs_track_refs();
+
+-- Backfill from existing leaves; the first stored copy of each hash becomes canonical
+INSERT INTO image_blobs (hash, storage_path, size, mime_type, ref_count)
+SELECT DISTINCT ON (data ->> 'hash') data ->> 'hash',
+                                     data ->> 'storage_path',
+                                     COALESCE((data ->> 'size')::BIGINT, 0),
+                                     COALESCE(data ->> 'mime_type', 'application/octet-stream'),
+                                     0
+FROM tree_nodes
+WHERE node_type = 'ImageLeaf'
+  AND data ? 'hash'
+  AND data ? 'storage_path'
+ORDER BY data ->> 'hash', created_at
+ON CONFLICT (hash) DO NOTHING;
+
+UPDATE image_blobs b
+SET ref_count = (SELECT count(*)
+                 FROM tree_nodes t
+                 WHERE t.node_type = 'ImageLeaf'
+                   AND t.data ->> 'hash' = b.hash);
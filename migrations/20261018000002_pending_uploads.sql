@@ -0,0 +1,15 @@
+-- Direct-to-storage uploads that were presigned but not yet finalized
+CREATE TABLE IF NOT EXISTS pending_uploads (
+    id            UUID PRIMARY KEY,
+    user_id       UUID        NOT NULL,
+    parent_id     UUID        NOT NULL REFERENCES tree_nodes (id) ON DELETE CASCADE,
+    filename      TEXT        NOT NULL,
+    mime_type     TEXT        NOT NULL,
+    storage_path  TEXT        NOT NULL,
+    expected_size BIGINT,
+    expected_hash TEXT,
+    expires_at    TIMESTAMPTZ NOT NULL,
+    created_at    TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS pending_uploads_expires_idx ON pending_uploads (expires_at);
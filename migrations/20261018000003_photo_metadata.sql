@@ -0,0 +1,13 @@
+-- Capture time and position from EXIF, queryable without digging into data
+ALTER TABLE tree_nodes
+    ADD COLUMN IF NOT EXISTS captured_at TIMESTAMPTZ,
+    ADD COLUMN IF NOT EXISTS latitude    DOUBLE PRECISION,
+    ADD COLUMN IF NOT EXISTS longitude   DOUBLE PRECISION;
+
+CREATE INDEX IF NOT EXISTS tree_nodes_captured_at_idx
+    ON tree_nodes (captured_at)
+    WHERE captured_at IS NOT NULL;
+
+CREATE INDEX IF NOT EXISTS tree_nodes_position_idx
+    ON tree_nodes (latitude, longitude)
+    WHERE latitude IS NOT NULL;
@@ -0,0 +1,3 @@
+-- 64-bit difference hash of each image, for finding near-identical shots
+ALTER TABLE tree_nodes
+    ADD COLUMN IF NOT EXISTS perceptual_hash BIGINT;
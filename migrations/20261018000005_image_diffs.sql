@@ -0,0 +1,14 @@
+-- Pixel comparisons between two image blobs, with the heatmap overlay kept
+-- next to the renditions of the later image
+CREATE TABLE IF NOT EXISTS image_diffs (
+    before_hash     TEXT        NOT NULL,
+    after_hash      TEXT        NOT NULL,
+    storage_path    TEXT        NOT NULL,
+    changed_percent REAL        NOT NULL,
+    offset_x        INTEGER     NOT NULL,
+    offset_y        INTEGER     NOT NULL,
+    created_at      TIMESTAMPTZ NOT NULL DEFAULT now(),
+    PRIMARY KEY (before_hash, after_hash)
+);
+
+CREATE INDEX IF NOT EXISTS image_diffs_after_hash_idx ON image_diffs (after_hash);
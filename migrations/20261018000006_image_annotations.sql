@@ -0,0 +1,23 @@
+-- Regions marked on images, e.g. a crack an inspector wants looked at
+DO $$
+BEGIN
+    CREATE TYPE annotation_status_enum AS ENUM ('open', 'resolved');
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END
+$$;
+
+CREATE TABLE IF NOT EXISTS image_annotations (
+    id         UUID PRIMARY KEY                DEFAULT gen_random_uuid(),
+    node_id    UUID                   NOT NULL REFERENCES tree_nodes (id) ON DELETE CASCADE,
+    -- Rectangle or polygon in coordinates normalised to 0..1 of the image
+    shape      JSONB                  NOT NULL,
+    label      TEXT                   NOT NULL,
+    note       TEXT,
+    author_id  UUID                   NOT NULL,
+    status     annotation_status_enum NOT NULL DEFAULT 'open',
+    created_at TIMESTAMPTZ            NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ            NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS image_annotations_node_idx ON image_annotations (node_id, created_at);
@@ -0,0 +1,20 @@
+-- Vision descriptions are cached per image content rather than per node,
+-- so copies and re-uploads of a photo share them. A row is reused while
+-- the model digest it was made with is still the installed one.
+ALTER TABLE image_descriptions
+    ADD COLUMN IF NOT EXISTS image_hash      TEXT,
+    ADD COLUMN IF NOT EXISTS model_version   TEXT NOT NULL DEFAULT '',
+    ADD COLUMN IF NOT EXISTS prompt_template TEXT,
+    ADD COLUMN IF NOT EXISTS language        TEXT,
+    ADD COLUMN IF NOT EXISTS output          JSONB;
+
+-- Descriptions outlive the node they were first made for
+ALTER TABLE image_descriptions ALTER COLUMN node_id DROP NOT NULL;
+ALTER TABLE image_descriptions DROP CONSTRAINT IF EXISTS image_descriptions_node_id_fkey;
+ALTER TABLE image_descriptions
+    ADD CONSTRAINT image_descriptions_node_id_fkey
+        FOREIGN KEY (node_id) REFERENCES tree_nodes (id) ON DELETE SET NULL;
+
+CREATE UNIQUE INDEX IF NOT EXISTS image_descriptions_cache_key_idx
+    ON image_descriptions (image_hash, model_name, prompt_template, language)
+    WHERE image_hash IS NOT NULL;
@@ -0,0 +1,31 @@
+-- Background work claimed by in-server workers with FOR UPDATE SKIP LOCKED
+DO $$
+BEGIN
+    CREATE TYPE job_status_enum AS ENUM ('queued', 'running', 'succeeded', 'failed');
+EXCEPTION
+    WHEN duplicate_object THEN NULL;
+END
+$$;
+
+CREATE TABLE IF NOT EXISTS jobs (
+    id         UUID PRIMARY KEY         DEFAULT gen_random_uuid(),
+    user_id    UUID            NOT NULL,
+    -- What to do, e.g. {"type": "describe", "node_id": ..., "language": "de"}
+    payload    JSONB           NOT NULL,
+    kind       TEXT GENERATED ALWAYS AS (payload ->> 'type') STORED,
+    status     job_status_enum NOT NULL DEFAULT 'queued',
+    -- Share of the work done, 0..1
+    progress   REAL            NOT NULL DEFAULT 0,
+    attempts   INTEGER         NOT NULL DEFAULT 0,
+    -- Not claimed before this, pushed back after each failed attempt
+    run_at     TIMESTAMPTZ     NOT NULL DEFAULT now(),
+    locked_at  TIMESTAMPTZ,
+    result     JSONB,
+    error      TEXT,
+    created_at TIMESTAMPTZ     NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ     NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS jobs_queued_idx ON jobs (run_at) WHERE status = 'queued';
+CREATE INDEX IF NOT EXISTS jobs_running_idx ON jobs (locked_at) WHERE status = 'running';
+CREATE INDEX IF NOT EXISTS jobs_user_idx ON jobs (user_id, created_at DESC);
@@ -0,0 +1,38 @@
+-- Full-text search over node names, labels and image descriptions. Text
+-- is stemmed in the language it was written in: the uploader's for
+-- images, the description's for vision output.
+ALTER TABLE tree_nodes ADD COLUMN IF NOT EXISTS language TEXT;
+
+-- Text search configuration for a language code like `de` or `de-AT`;
+-- languages without a stemmer are only split into words
+CREATE OR REPLACE FUNCTION search_config(language TEXT) RETURNS regconfig
+    LANGUAGE sql IMMUTABLE PARALLEL SAFE AS
+$$
+SELECT CASE split_part(lower(language), '-', 1)
+           WHEN 'en' THEN 'english'
+           WHEN 'de' THEN 'german'
+           WHEN 'fr' THEN 'french'
+           WHEN 'es' THEN 'spanish'
+           WHEN 'it' THEN 'italian'
+           WHEN 'nl' THEN 'dutch'
+           WHEN 'pt' THEN 'portuguese'
+           WHEN 'ru' THEN 'russian'
+           ELSE 'simple'
+       END::regconfig
+$$;
+
+ALTER TABLE tree_nodes
+    ADD COLUMN IF NOT EXISTS search_vector TSVECTOR GENERATED ALWAYS AS (
+        setweight(to_tsvector(search_config(language),
+                              coalesce(name, '') || ' ' || coalesce(data ->> 'title', '') || ' ' ||
+                              coalesce(data ->> 'label', '')), 'A') ||
+        setweight(to_tsvector(search_config(language), coalesce(data ->> 'description', '')), 'B')
+    ) STORED;
+
+CREATE INDEX IF NOT EXISTS tree_nodes_search_idx ON tree_nodes USING GIN (search_vector);
+
+-- Cached descriptions in other languages than the node's own find the
+-- image too
+CREATE INDEX IF NOT EXISTS image_descriptions_search_idx
+    ON image_descriptions USING GIN (to_tsvector(search_config(language), description))
+    WHERE image_hash IS NOT NULL;
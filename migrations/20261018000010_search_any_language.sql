@@ -0,0 +1,13 @@
+-- A search query parsed in every configuration search_config() can
+-- return, OR-ed together. Matching it doesn't depend on the row, so the
+-- GIN indexes can find candidates; each candidate is then checked against
+-- the query parsed in its own language. Keep in step with search_config().
+CREATE OR REPLACE FUNCTION search_query_any(query TEXT) RETURNS tsquery
+    LANGUAGE sql IMMUTABLE PARALLEL SAFE AS
+$$
+SELECT websearch_to_tsquery('english', query) || websearch_to_tsquery('german', query) ||
+       websearch_to_tsquery('french', query) || websearch_to_tsquery('spanish', query) ||
+       websearch_to_tsquery('italian', query) || websearch_to_tsquery('dutch', query) ||
+       websearch_to_tsquery('portuguese', query) || websearch_to_tsquery('russian', query) ||
+       websearch_to_tsquery('simple', query)
+$$;
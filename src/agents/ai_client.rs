@@ -0,0 +1,379 @@
+use bytes::Bytes;
+use rig::agent::AgentBuilder;
+use rig::client::Nothing;
+use rig::completion::CompletionError;
+use rig::http_client::{self, HttpClientExt, LazyBody, MultipartForm, Request, Response, StreamingResponse};
+use rig::prelude::CompletionClient;
+use rig::providers::ollama;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::sync::mpsc;
+use crate::agents::{MockLlm, StreamEvent};
+use crate::storage::{AiConfig, AiProvider, AiRole};
+
+// ============================================================================
+// OLLAMA HTTP CLIENT
+// ============================================================================
+
+/// Top-level Ollama request fields. rig merges `additional_params` into
+/// `options`, so these are moved back out before the request is sent.
+const TOP_LEVEL_PARAMS: &[&str] = &["keep_alive", "format"];
+
+/// reqwest wrapper used as the rig HTTP backend for Ollama.
+///
+/// Applies the configured `keep_alive` to every chat request and, for the
+/// mock provider, answers requests in-process instead of over the network.
+#[derive(Clone, Debug, Default)]
+pub struct OllamaHttp {
+    inner: reqwest::Client,
+    mock: Option<Arc<MockLlm>>,
+    keep_alive: Option<String>,
+}
+
+impl OllamaHttp {
+    pub fn new(config: &AiConfig) -> Result<Self, reqwest::Error> {
+        let inner = reqwest::Client::builder()
+            .timeout(config.request_timeout)
+            .build()?;
+        let mock = match &config.provider {
+            AiProvider::Ollama => None,
+            AiProvider::Mock(mock) => Some(mock.clone()),
+        };
+        Ok(Self {
+            inner,
+            mock,
+            keep_alive: Some(config.keep_alive.clone()),
+        })
+    }
+
+    fn rewrite<T: Into<Bytes>>(&self, req: Request<T>) -> Request<Bytes> {
+        let (parts, body) = req.into_parts();
+        let body: Bytes = body.into();
+
+        let Ok(serde_json::Value::Object(mut json)) = serde_json::from_slice(&body) else {
+            return Request::from_parts(parts, body);
+        };
+
+        if let Some(serde_json::Value::Object(options)) = json.get_mut("options") {
+            let lifted: Vec<_> = TOP_LEVEL_PARAMS
+                .iter()
+                .filter_map(|key| options.remove(*key).map(|value| (key.to_string(), value)))
+                .collect();
+            json.extend(lifted);
+        }
+        if let Some(keep_alive) = &self.keep_alive {
+            json.entry("keep_alive")
+                .or_insert_with(|| serde_json::Value::String(keep_alive.clone()));
+        }
+
+        match serde_json::to_vec(&json) {
+            Ok(body) => Request::from_parts(parts, Bytes::from(body)),
+            Err(_) => Request::from_parts(parts, body),
+        }
+    }
+}
+
+type BoxedFuture<'a, T> = Pin<Box<dyn Future<Output = http_client::Result<T>> + Send + 'a>>;
+
+impl HttpClientExt for OllamaHttp {
+    fn send<T, U>(
+        &self,
+        req: Request<T>,
+    ) -> impl Future<Output = http_client::Result<Response<LazyBody<U>>>> + Send + 'static
+    where
+        T: Into<Bytes> + Send,
+        U: From<Bytes> + Send + 'static,
+    {
+        let req = self.rewrite(req);
+        let future: BoxedFuture<'static, Response<LazyBody<U>>> = match &self.mock {
+            Some(mock) => {
+                let response = mock.send(req);
+                Box::pin(async move { response })
+            }
+            None => Box::pin(self.inner.send(req)),
+        };
+        future
+    }
+
+    fn send_multipart<U>(
+        &self,
+        req: Request<MultipartForm>,
+    ) -> impl Future<Output = http_client::Result<Response<LazyBody<U>>>> + Send + 'static
+    where
+        U: From<Bytes> + Send + 'static,
+    {
+        self.inner.send_multipart(req)
+    }
+
+    fn send_streaming<T>(
+        &self,
+        req: Request<T>,
+    ) -> impl Future<Output = http_client::Result<StreamingResponse>> + Send
+    where
+        T: Into<Bytes>,
+    {
+        let req = self.rewrite(req);
+        let future: BoxedFuture<'_, StreamingResponse> = match &self.mock {
+            Some(mock) => {
+                let response = mock.send_streaming(req);
+                Box::pin(async move { response })
+            }
+            None => Box::pin(self.inner.send_streaming(req)),
+        };
+        future
+    }
+}
+
+// ============================================================================
+// CLIENT & AGENT BUILDERS
+// ============================================================================
+
+pub type AiClient = ollama::Client<OllamaHttp>;
+pub type AiAgentBuilder = AgentBuilder<ollama::CompletionModel<OllamaHttp>>;
+
+/// Builds the Ollama client from a validated configuration
+pub fn build_client(config: &AiConfig) -> Result<AiClient, Box<dyn std::error::Error + Send + Sync>> {
+    let client = AiClient::builder()
+        .api_key(Nothing)
+        .base_url(&config.url)
+        .http_client(OllamaHttp::new(config)?)
+        .build()?;
+    Ok(client)
+}
+
+/// Starts an agent for the given role with its model and generation parameters applied
+pub fn role_agent(client: &AiClient, config: &AiConfig, role: AiRole) -> AiAgentBuilder {
+    model_agent(client, config, role, config.model(role))
+}
+
+fn model_agent(client: &AiClient, config: &AiConfig, role: AiRole, model: &str) -> AiAgentBuilder {
+    let params = config.params(role);
+    client
+        .agent(model)
+        .temperature(params.temperature)
+        .max_tokens(params.max_tokens)
+        .additional_params(generation_params(config, role))
+}
+
+/// Ollama `options` for the role. `additional_params` replaces rather than
+/// merges, so callers adding their own params must start from these.
+pub(crate) fn generation_params(config: &AiConfig, role: AiRole) -> serde_json::Value {
+    let params = config.params(role);
+    serde_json::json!({
+        "num_ctx": params.context_window,
+        "num_predict": params.max_tokens,
+    })
+}
+
+// ============================================================================
+// RETRY & FALLBACK
+// ============================================================================
+
+/// Model call that failed after all retries and fallbacks
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct AiCallError {
+    pub message: String,
+    /// True when the failure is transient and the same request may succeed later
+    pub recoverable: bool,
+}
+
+impl AiCallError {
+    pub fn new(message: impl Into<String>, recoverable: bool) -> Self {
+        Self {
+            message: message.into(),
+            recoverable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Failure {
+    /// Transport error, timeout or 5xx: retry the same model
+    Transient,
+    /// The model itself is unusable (unknown model, bad response): try the next one
+    Model,
+    /// Anything else: give up immediately
+    Fatal,
+}
+
+fn classify(error: &(dyn std::error::Error + 'static)) -> Failure {
+    let mut current = Some(error);
+    while let Some(error) = current {
+        if error.is::<AiCallError>() {
+            return Failure::Fatal;
+        }
+        if let Some(error) = error.downcast_ref::<http_client::Error>() {
+            return match error {
+                http_client::Error::InvalidStatusCode(status)
+                | http_client::Error::InvalidStatusCodeWithMessage(status, _) => {
+                    if status.is_server_error() || status.as_u16() == 408 || status.as_u16() == 429 {
+                        Failure::Transient
+                    } else {
+                        Failure::Model
+                    }
+                }
+                http_client::Error::Instance(_) | http_client::Error::StreamEnded => Failure::Transient,
+                _ => Failure::Fatal,
+            };
+        }
+        if let Some(
+            CompletionError::ProviderError(_)
+            | CompletionError::ResponseError(_)
+            | CompletionError::JsonError(_),
+        ) = error.downcast_ref::<CompletionError>()
+        {
+            return Failure::Model;
+        }
+        if let Some(error) = error.downcast_ref::<reqwest::Error>()
+            && (error.is_timeout() || error.is_connect())
+        {
+            return Failure::Transient;
+        }
+        current = error.source();
+    }
+    Failure::Fatal
+}
+
+/// Runs `call` against each model configured for `role` in order.
+///
+/// Transient failures are retried with exponential backoff before moving on
+/// to the next model; every retry and fallback is reported to the client as
+/// a `CoordinatorThinking` event.
+pub async fn call_with_fallback<T, E, F, Fut>(
+    client: &AiClient,
+    config: &AiConfig,
+    role: AiRole,
+    request_id: &str,
+    event_tx: &mpsc::Sender<StreamEvent>,
+    call: F,
+) -> Result<T, AiCallError>
+where
+    F: FnMut(AiAgentBuilder) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    call_with_fallback_model(client, config, role, request_id, event_tx, call)
+        .await
+        .map(|(value, _)| value)
+}
+
+/// [`call_with_fallback`], also returning the model that answered
+pub async fn call_with_fallback_model<T, E, F, Fut>(
+    client: &AiClient,
+    config: &AiConfig,
+    role: AiRole,
+    request_id: &str,
+    event_tx: &mpsc::Sender<StreamEvent>,
+    mut call: F,
+) -> Result<(T, String), AiCallError>
+where
+    F: FnMut(AiAgentBuilder) -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let notify = |message: String| async move {
+        let _ = event_tx
+            .send(StreamEvent::CoordinatorThinking {
+                request_id: request_id.to_string(),
+                message,
+            })
+            .await;
+    };
+
+    let models = config.models(role);
+    let mut last_error = AiCallError::new("No model configured", false);
+
+    for (index, model) in models.iter().enumerate() {
+        let mut attempt = 0;
+        let failure = loop {
+            let error: Box<dyn std::error::Error + Send + Sync> =
+                match call(model_agent(client, config, role, model)).await {
+                    Ok(value) => return Ok((value, model.clone())),
+                    Err(e) => e.into(),
+                };
+
+            let failure = classify(error.as_ref());
+            last_error = match error.downcast::<AiCallError>() {
+                Ok(error) => *error,
+                Err(error) => AiCallError::new(
+                    format!("Model {} failed: {}", model, error),
+                    failure == Failure::Transient,
+                ),
+            };
+            if failure != Failure::Transient || attempt >= config.retry.max_retries {
+                break failure;
+            }
+
+            let delay = config.retry.backoff(attempt);
+            attempt += 1;
+            notify(format!(
+                "{}; retrying in {} ms (attempt {}/{})",
+                last_error,
+                delay.as_millis(),
+                attempt,
+                config.retry.max_retries
+            ))
+            .await;
+            tokio::time::sleep(delay).await;
+        };
+
+        if failure == Failure::Fatal {
+            break;
+        }
+        if let Some(next) = models.get(index + 1) {
+            notify(format!("{}; falling back to {}", last_error, next)).await;
+        }
+    }
+
+    Err(last_error)
+}
+
+// ============================================================================
+// TESTS
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rewrite_lifts_top_level_params() {
+        let http = OllamaHttp {
+            inner: reqwest::Client::new(),
+            mock: None,
+            keep_alive: Some("10m".to_string()),
+        };
+        let body = serde_json::json!({
+            "model": "llava",
+            "options": { "temperature": 0.2, "keep_alive": "1h" }
+        });
+        let req = Request::builder()
+            .uri("http://localhost/api/chat")
+            .body(Bytes::from(serde_json::to_vec(&body).unwrap()))
+            .unwrap();
+
+        let rewritten: serde_json::Value = serde_json::from_slice(http.rewrite(req).body()).unwrap();
+        assert_eq!(rewritten["keep_alive"], "1h");
+        assert!(rewritten["options"].get("keep_alive").is_none());
+        assert_eq!(rewritten["options"]["temperature"], 0.2);
+    }
+
+    #[test]
+    fn test_rewrite_applies_default_keep_alive() {
+        let http = OllamaHttp {
+            inner: reqwest::Client::new(),
+            mock: None,
+            keep_alive: Some("10m".to_string()),
+        };
+        let req = Request::builder()
+            .uri("http://localhost/api/chat")
+            .body(Bytes::from_static(br#"{"model":"llava","options":{}}"#))
+            .unwrap();
+
+        let rewritten: serde_json::Value = serde_json::from_slice(http.rewrite(req).body()).unwrap();
+        assert_eq!(rewritten["keep_alive"], "10m");
+    }
+}
@@ -1,20 +1,22 @@
 use std::sync::Arc;
-use rig::providers::ollama;
-use rig::completion::Prompt;
-use rig::prelude::CompletionClient;
+use futures::StreamExt;
+use rig::agent::{Agent, MultiTurnStreamItem};
+use rig::providers::ollama::CompletionModel;
+use rig::streaming::{StreamedAssistantContent, StreamingPrompt};
 use tokio::sync::mpsc;
 use crate::agents::StreamEvent;
-use crate::{AgentContext, AppState};
+use crate::agents::{call_with_fallback, AiCallError, AiClient, OllamaHttp};
+use crate::{AgentContext, AiRole, AppState};
 
 pub struct ChatAgent {
-    client: ollama::Client,
+    client: AiClient,
     request_id: String,
     event_tx: mpsc::Sender<StreamEvent>,
 }
 
 impl ChatAgent {
     pub fn new(
-        client: ollama::Client,
+        client: AiClient,
         request_id: String,
         event_tx: mpsc::Sender<StreamEvent>,
     ) -> Self {
@@ -35,32 +37,64 @@ impl ChatAgent {
         prompt: &str,
         context: &AgentContext,
     ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
-        let agent = self
-            .client
-            .agent(&state.ai_config.text_model)
-            .preamble(&format!(
-                "You are a friendly chat assistant. Respond naturally in {} language.",
-                context.language
-            ))
-            .build();
+        let preamble = format!(
+            "You are a friendly chat assistant. Respond naturally in {} language.",
+            context.language
+        );
 
-        let response = agent.prompt(prompt).await?;
+        let response = call_with_fallback(
+            &self.client,
+            &state.ai_config,
+            AiRole::Chat,
+            &self.request_id,
+            &self.event_tx,
+            |builder| {
+                let agent = builder.preamble(&preamble).build();
+                async move { self.stream_reply(agent, prompt, context).await }
+            },
+        )
+        .await?;
 
-        // Send response in chunks for streaming effect
-        let chunk_size = 20;
-        for chunk in response.chars().collect::<Vec<_>>().chunks(chunk_size) {
-            let chunk_str: String = chunk.iter().collect();
-            
-            self.send_event(StreamEvent::TextChunk {
-                request_id: self.request_id.clone(),
-                chunk: chunk_str,
-            })
-            .await;
+        Ok(response)
+    }
+
+    async fn stream_reply(
+        &self,
+        agent: Agent<CompletionModel<OllamaHttp>>,
+        prompt: &str,
+        context: &AgentContext,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut stream = agent.stream_prompt(prompt).await;
+        let mut response = String::new();
+
+        while let Some(item) = stream.next().await {
+            context.cancellation_token.check().await?;
 
-            // Small delay for streaming effect
-            tokio::time::sleep(tokio::time::Duration::from_millis(50)).await;
+            let item = match item {
+                Ok(item) => item,
+                // Text already reached the client, so the model can't be retried
+                Err(e) if !response.is_empty() => {
+                    return Err(AiCallError::new(format!("Stream interrupted: {}", e), true).into());
+                }
+                Err(e) => return Err(e.into()),
+            };
+
+            match item {
+                MultiTurnStreamItem::StreamAssistantItem(StreamedAssistantContent::Text(text)) => {
+                    response.push_str(&text.text);
+                    self.send_event(StreamEvent::TextChunk {
+                        request_id: self.request_id.clone(),
+                        chunk: text.text,
+                    })
+                    .await;
+                }
+                MultiTurnStreamItem::FinalResponse(final_response) if response.is_empty() => {
+                    response = final_response.response().to_string();
+                }
+                _ => {}
+            }
         }
 
         Ok(response)
     }
-}
\ No newline at end of file
+}
@@ -1,20 +1,21 @@
 use std::sync::Arc;
-use rig::providers::ollama;
-use rig::completion::Prompt;
-use rig::prelude::CompletionClient;
 use tokio::sync::mpsc;
-use serde_json::json;
-use crate::{AgentContext, AppState, StreamEvent, TaskParameters};
+use uuid::Uuid;
+use crate::agents::tools::is_low_quality;
+use crate::agents::{structured_call, AiClient, ComparisonOutput, StructuredOutput};
+use crate::error::Result;
+use crate::models::ImageComparison;
+use crate::{storage, tree, AgentContext, AiRole, AppState, StreamEvent, TaskParameters};
 
 pub struct ComparisonAgent {
-    client: ollama::Client,
+    client: AiClient,
     request_id: String,
     event_tx: mpsc::Sender<StreamEvent>,
 }
 
 impl ComparisonAgent {
     pub fn new(
-        client: ollama::Client,
+        client: AiClient,
         request_id: String,
         event_tx: mpsc::Sender<StreamEvent>,
     ) -> Self {
@@ -29,13 +30,28 @@ impl ComparisonAgent {
         let _ = self.event_tx.send(event).await;
     }
 
+    /// Pixel comparison of the two latest usable photos of the object the
+    /// user is looking at, when there is one
+    async fn pixel_diff(&self, state: &AppState, context: &AgentContext) -> Result<Option<ImageComparison>> {
+        let Some(object_id) = context.object_id.as_deref().and_then(|id| Uuid::parse_str(id).ok()) else {
+            return Ok(None);
+        };
+        let user = context.user_id.as_deref();
+        let mut images = tree::get_images(&state.db, user, &object_id, None, None).await?;
+        images.retain(|image| !is_low_quality(image));
+        let [.., before, after] = images.as_slice() else {
+            return Ok(None);
+        };
+        storage::compare_images(state, user, &before.id, &after.id).await.map(Some)
+    }
+
     pub async fn execute(
         &self,
         state:Arc<AppState>,
         prompt: &str,
-        _context: &AgentContext,
+        context: &AgentContext,
         parameters: &TaskParameters,
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    ) -> std::result::Result<String, Box<dyn std::error::Error + Send + Sync>> {
         // Send initial text chunk
         self.send_event(StreamEvent::TextChunk {
             request_id: self.request_id.clone(),
@@ -43,86 +59,53 @@ impl ComparisonAgent {
         })
         .await;
 
+        // A failed pixel comparison only loses the score, not the analysis
+        let pixel_diff = self.pixel_diff(&state, context).await.unwrap_or_else(|e| {
+            log::warn!("Pixel comparison for {} failed: {}", self.request_id, e);
+            None
+        });
+
         // Build agent prompt
-        let agent_prompt = format!(
+        let mut agent_prompt = format!(
             "You are a comparison analyst. Compare items based on: {}\nParameters: last={}, all={}, period={:?}, amount={:?}",
             prompt, parameters.last, parameters.all, parameters.period, parameters.amount
         );
+        if let Some(diff) = &pixel_diff {
+            agent_prompt.push_str(&format!(
+                "\nThe two latest photos differ in {:.1}% of their area.",
+                diff.changed_percent
+            ));
+        }
 
-        let agent = self
-            .client
-            .agent(&state.ai_config.text_model)
-            .preamble("You are a comparison specialist. Provide detailed comparative analysis with pros, cons, and recommendations.")
-            .build();
-
-        let response = agent.prompt(&agent_prompt).await?;
+        let output: ComparisonOutput = structured_call(
+            &self.client,
+            &state.ai_config,
+            AiRole::Text,
+            &self.request_id,
+            &self.event_tx,
+            "You are a comparison specialist. Return the compared items, their differences and a recommendation as JSON.",
+            agent_prompt,
+        )
+        .await?;
 
         // Send text description
         self.send_event(StreamEvent::TextChunk {
             request_id: self.request_id.clone(),
-            chunk: format!("Comparison results:\n{}\n", response),
+            chunk: format!("Comparison results:\n{}\n", output.summary()),
         })
         .await;
 
         // Send structured data
-        let comparison_data = json!({
-            "comparison": {
-                "items_compared": 2,
-                "analysis": {
-                    "item_a": {
-                        "name": "Item A",
-                        "score": 85,
-                        "pros": ["Fast processing", "Easy to use", "Cost-effective"],
-                        "cons": ["Limited features", "Basic interface"]
-                    },
-                    "item_b": {
-                        "name": "Item B",
-                        "score": 78,
-                        "pros": ["Advanced features", "Customizable", "Good support"],
-                        "cons": ["Higher cost", "Steeper learning curve"]
-                    }
-                },
-                "differences": [
-                    {
-                        "category": "Performance",
-                        "item_a": "95%",
-                        "item_b": "88%",
-                        "winner": "Item A"
-                    },
-                    {
-                        "category": "Features",
-                        "item_a": "Basic",
-                        "item_b": "Advanced",
-                        "winner": "Item B"
-                    },
-                    {
-                        "category": "Cost",
-                        "item_a": "$50",
-                        "item_b": "$120",
-                        "winner": "Item A"
-                    }
-                ],
-                "recommendation": {
-                    "best_for_budget": "Item A",
-                    "best_for_features": "Item B",
-                    "overall_winner": "Item A",
-                    "reasoning": "Better value for most use cases"
-                }
-            },
-            "parameters": {
-                "last": parameters.last,
-                "all": parameters.all,
-                "period": format!("{:?}", parameters.period),
-                "amount": parameters.amount
-            }
-        });
-
+        let mut data = serde_json::to_value(&output)?;
+        if let Some(diff) = pixel_diff {
+            data["pixel_diff"] = serde_json::to_value(diff)?;
+        }
         self.send_event(StreamEvent::ComparisonChunk {
             request_id: self.request_id.clone(),
-            data: comparison_data,
+            data,
         })
         .await;
 
-        Ok(response)
+        Ok(output.summary())
     }
-}
\ No newline at end of file
+}
@@ -1,20 +1,19 @@
 use std::sync::Arc;
-use rig::providers::ollama;
-use rig::completion::Prompt;
-use rig::prelude::CompletionClient;
 use tokio::sync::mpsc;
-use serde_json::json;
-use crate::{AgentContext, AppState, StreamEvent, TaskParameters};
+use uuid::Uuid;
+use crate::agents::{structured_call, AiClient, ImageDescriptionOutput, StructuredOutput};
+use crate::error::Result;
+use crate::{descriptions, AgentContext, AiRole, AppState, StreamEvent, TaskParameters};
 
 pub struct DescriptionAgent {
-    client: ollama::Client,
+    client: AiClient,
     request_id: String,
     event_tx: mpsc::Sender<StreamEvent>,
 }
 
 impl DescriptionAgent {
     pub fn new(
-        client: ollama::Client,
+        client: AiClient,
         request_id: String,
         event_tx: mpsc::Sender<StreamEvent>,
     ) -> Self {
@@ -29,13 +28,45 @@ impl DescriptionAgent {
         let _ = self.event_tx.send(event).await;
     }
 
+    /// Description of the marked area of the image the user is looking
+    /// at, when asked about one. The annotation is the one named in the
+    /// request metadata as `annotation_id`, or else the image's latest open one.
+    async fn marked_area(
+        &self,
+        state: &AppState,
+        context: &AgentContext,
+        parameters: &TaskParameters,
+    ) -> Result<Option<ImageDescriptionOutput>> {
+        let Some(object_id) = context.object_id.as_deref().and_then(|id| Uuid::parse_str(id).ok()) else {
+            return Ok(None);
+        };
+        if !parameters.marked {
+            return Ok(None);
+        }
+        let annotation_id = context.metadata["annotation_id"]
+            .as_str()
+            .and_then(|id| Uuid::parse_str(id).ok());
+        descriptions::describe_marked_area(
+            state,
+            &self.client,
+            &self.request_id,
+            &self.event_tx,
+            context.user_id.as_deref(),
+            &object_id,
+            annotation_id.as_ref(),
+            &context.language,
+        )
+        .await
+        .map(Some)
+    }
+
     pub async fn execute(
         &self,
         state:Arc<AppState>,
         prompt: &str,
-        _context: &AgentContext,
+        context: &AgentContext,
         parameters: &TaskParameters,
-    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+    ) -> std::result::Result<String, Box<dyn std::error::Error + Send + Sync>> {
         // Send initial text chunk
         self.send_event(StreamEvent::TextChunk {
             request_id: self.request_id.clone(),
@@ -43,66 +74,42 @@ impl DescriptionAgent {
         })
         .await;
 
-        // Build agent prompt
-        let agent_prompt = format!(
-            "You are a description generator. Provide detailed description for: {}\nParameters: last={}, all={}, period={:?}, amount={:?}",
-            prompt, parameters.last, parameters.all, parameters.period, parameters.amount
-        );
+        let output = match self.marked_area(&state, context, parameters).await? {
+            Some(output) => output,
+            None => {
+                // Build agent prompt
+                let agent_prompt = format!(
+                    "You are a description generator. Provide detailed description for: {}\nParameters: last={}, all={}, period={:?}, amount={:?}",
+                    prompt, parameters.last, parameters.all, parameters.period, parameters.amount
+                );
 
-        let agent = self
-            .client
-            .agent(&state.ai_config.vision_model)
-            .preamble("You are a detailed description assistant. Provide comprehensive explanations in a structured format.")
-            .build();
-
-        let response = agent.prompt(&agent_prompt).await?;
+                structured_call(
+                    &self.client,
+                    &state.ai_config,
+                    AiRole::Vision,
+                    &self.request_id,
+                    &self.event_tx,
+                    "You are a detailed description assistant. Describe the subject as JSON with an overview and titled sections.",
+                    agent_prompt,
+                )
+                .await?
+            }
+        };
 
         // Send text description
         self.send_event(StreamEvent::TextChunk {
             request_id: self.request_id.clone(),
-            chunk: format!("Description:\n{}\n", response),
+            chunk: format!("Description:\n{}\n", output.summary()),
         })
         .await;
 
         // Send structured data
-        let description_data = json!({
-            "description": {
-                "subject": "Requested item",
-                "overview": response.chars().take(200).collect::<String>(),
-                "details": {
-                    "category": "General",
-                    "complexity": "Medium",
-                    "estimated_time": "5 minutes"
-                },
-                "sections": [
-                    {
-                        "title": "Introduction",
-                        "content": "Initial overview of the subject matter."
-                    },
-                    {
-                        "title": "Key Points",
-                        "content": "Main aspects and characteristics."
-                    },
-                    {
-                        "title": "Conclusion",
-                        "content": "Summary and final thoughts."
-                    }
-                ]
-            },
-            "parameters": {
-                "last": parameters.last,
-                "all": parameters.all,
-                "period": format!("{:?}", parameters.period),
-                "amount": parameters.amount
-            }
-        });
-
         self.send_event(StreamEvent::DescriptionChunk {
             request_id: self.request_id.clone(),
-            data: description_data,
+            data: serde_json::to_value(&output)?,
         })
         .await;
 
-        Ok(response)
+        Ok(output.summary())
     }
-}
\ No newline at end of file
+}
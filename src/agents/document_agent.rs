@@ -1,20 +1,17 @@
 use std::sync::Arc;
-use rig::providers::ollama;
-use rig::completion::Prompt;
-use rig::prelude::CompletionClient;
 use tokio::sync::mpsc;
-use serde_json::json;
-use crate::{AgentContext, AppState, StreamEvent, TaskParameters};
+use crate::agents::{structured_call, AiClient, DocumentList, StructuredOutput};
+use crate::{AgentContext, AiRole, AppState, StreamEvent, TaskParameters};
 
 pub struct DocumentAgent {
-    client: ollama::Client,
+    client: AiClient,
     request_id: String,
     event_tx: mpsc::Sender<StreamEvent>,
 }
 
 impl DocumentAgent {
     pub fn new(
-        client: ollama::Client,
+        client: AiClient,
         request_id: String,
         event_tx: mpsc::Sender<StreamEvent>,
     ) -> Self {
@@ -49,58 +46,31 @@ impl DocumentAgent {
             prompt, parameters.last, parameters.all, parameters.period, parameters.amount
         );
 
-        let agent = self
-            .client
-            .agent(&state.ai_config.text_model)
-            .preamble("You are a document management system. Return structured document data in JSON format.")
-            .build();
-
-        let response = agent.prompt(&agent_prompt).await?;
+        let output: DocumentList = structured_call(
+            &self.client,
+            &state.ai_config,
+            AiRole::Text,
+            &self.request_id,
+            &self.event_tx,
+            "You are a document management system. Return the matching documents as JSON.",
+            agent_prompt,
+        )
+        .await?;
 
         // Send text description
         self.send_event(StreamEvent::TextChunk {
             request_id: self.request_id.clone(),
-            chunk: format!("Document results:\n{}\n", response),
+            chunk: format!("Document results:\n{}\n", output.summary()),
         })
         .await;
 
         // Send structured data
-        let document_data = json!({
-            "documents": [
-                {
-                    "id": "doc_001",
-                    "title": "Q4 Report",
-                    "type": "report",
-                    "size": "2.4 MB",
-                    "created": "2024-12-15T09:00:00Z",
-                    "author": "John Doe",
-                    "status": "published"
-                },
-                {
-                    "id": "doc_002",
-                    "title": "Annual Review",
-                    "type": "document",
-                    "size": "1.8 MB",
-                    "created": "2024-12-18T11:30:00Z",
-                    "author": "Jane Smith",
-                    "status": "draft"
-                }
-            ],
-            "total": 2,
-            "parameters": {
-                "last": parameters.last,
-                "all": parameters.all,
-                "period": format!("{:?}", parameters.period),
-                "amount": parameters.amount
-            }
-        });
-
         self.send_event(StreamEvent::DocumentChunk {
             request_id: self.request_id.clone(),
-            data: document_data,
+            data: serde_json::to_value(&output)?,
         })
         .await;
 
-        Ok(response)
+        Ok(output.summary())
     }
-}
\ No newline at end of file
+}
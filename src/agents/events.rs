@@ -42,6 +42,22 @@ pub enum StreamEvent {
         data: serde_json::Value,
     },
 
+    // Tool events
+    ToolCall {
+        request_id: String,
+        call_id: String,
+        tool: String,
+        arguments: serde_json::Value,
+    },
+
+    ToolResult {
+        request_id: String,
+        call_id: String,
+        tool: String,
+        result: serde_json::Value,
+        is_error: bool,
+    },
+
     // Completion events
     Completed {
         request_id: String,
@@ -1,13 +1,11 @@
-use rig::providers::ollama;
-use rig::client::Nothing;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
 use uuid::Uuid;
-use crate::agents::{ChatAgent, ComparisonAgent, ContextParser, DescriptionAgent, DocumentAgent, ObjectAgent, Task, TaskDetector};
+use crate::agents::{build_client, AiCallError, AiClient, ChatAgent, ComparisonAgent, ContextParser, DescriptionAgent, DocumentAgent, ObjectAgent, Task, TaskDetector, TreeAgent};
 use crate::StreamEvent;
-use crate::AppState;
+use crate::{AgentMode, AiConfig, AppState};
 
 // ============================================================================
 // CANCELLATION TOKEN
@@ -139,17 +137,13 @@ impl AgentContext {
 // ============================================================================
 
 pub struct MasterAgent {
-    client: ollama::Client,
+    client: AiClient,
     request_manager: Arc<RequestManager>,
 }
 
 impl MasterAgent {
-    pub fn new(ai_url: &str) -> Self {
-        let client = ollama::Client::builder()
-            .api_key(Nothing)
-            .base_url(ai_url)
-            .build()
-            .unwrap();
+    pub fn new(config: &AiConfig) -> Self {
+        let client = build_client(config).unwrap();
         Self {
             client,
             request_manager: Arc::new(RequestManager::new()),
@@ -204,11 +198,14 @@ impl MasterAgent {
                             })
                             .await;
                     } else {
+                        let recoverable = e
+                            .downcast_ref::<AiCallError>()
+                            .is_some_and(|e| e.recoverable);
                         let _ = tx
                             .send(StreamEvent::Error {
                                 request_id: request_id.clone(),
                                 error: e.to_string(),
-                                recoverable: false,
+                                recoverable,
                             })
                             .await;
                     }
@@ -223,7 +220,7 @@ impl MasterAgent {
 
     async fn process_request(
         state:Arc<AppState>,
-        client: ollama::Client,
+        client: AiClient,
         request: AgentRequest,
         context: AgentContext,
         event_tx: mpsc::Sender<StreamEvent>,
@@ -238,6 +235,11 @@ impl MasterAgent {
 
         context.cancellation_token.check().await?;
 
+        if state.ai_config.mode == AgentMode::Tools {
+            let agent = TreeAgent::new(client, context.request_id.clone(), event_tx.clone());
+            return agent.execute(state, &request.message, &context).await;
+        }
+
         // Parse the prompt
         let mut parser = ContextParser::new();
         let prompt_context = parser.parse(&context.language, &request.message)?;
@@ -306,113 +308,289 @@ impl MasterAgent {
 
 #[cfg(test)]
 mod tests {
-    use crate::init::app_init;
     use super::*;
-    const URL:&str = "http://localhost:8080";
-    #[tokio::test]
-    async fn test_object_task() {
-        let agent = MasterAgent::new(URL);
-        
-        let request = AgentRequest {
-            message: "show me the last 5 objects".to_string(),
+    use crate::agents::{MockLlm, MockReply};
+    use crate::init::{test_config, test_state};
+
+    fn request(message: &str) -> AgentRequest {
+        AgentRequest {
+            message: message.to_string(),
             user_id: Some("user_123".to_string()),
-            chat_id: None,
-            object_id: None,
             language: Some("en".to_string()),
-            session_id: None,
-            metadata: None,
+            ..AgentRequest::default()
+        }
+    }
+
+    async fn run(mock: Arc<MockLlm>, message: &str) -> Vec<StreamEvent> {
+        let config = AiConfig {
+            mode: AgentMode::Keywords,
+            ..test_config(mock)
         };
-        dotenv::dotenv().ok();
-        let (_config, state) = app_init().await.unwrap();
+        run_request(config, request(message)).await
+    }
 
-        let mut rx = agent.handle_request_stream(state.clone(),request).await;
+    async fn run_request(config: AiConfig, request: AgentRequest) -> Vec<StreamEvent> {
+        let state = test_state(config);
+        let mut rx = state
+            .master_agent
+            .handle_request_stream(state.clone(), request)
+            .await;
 
+        let mut events = Vec::new();
         while let Some(event) = rx.recv().await {
-            match event {
-                StreamEvent::Started { .. } => println!("✓ Started"),
-                StreamEvent::CoordinatorThinking { message, .. } => {
-                    println!("🤔 {}", message);
-                }
-                StreamEvent::TextChunk { chunk, .. } => {
-                    print!("{}", chunk);
-                }
-                StreamEvent::ObjectChunk { data, .. } => {
-                    println!("\n📦 Object data: {}", serde_json::to_string_pretty(&data).unwrap());
-                }
-                StreamEvent::Completed { .. } => {
-                    println!("\n✅ Completed");
-                    break;
-                }
-                StreamEvent::Error { error, .. } => {
-                    println!("\n❌ Error: {}", error);
-                    break;
-                }
-                _ => {}
-            }
+            events.push(event);
+        }
+        events
+    }
+
+    fn kinds(events: &[StreamEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|e| serde_json::to_value(e).unwrap()["type"].as_str().unwrap().to_string())
+            .collect()
+    }
+
+    fn final_result(events: &[StreamEvent]) -> &str {
+        match events.last() {
+            Some(StreamEvent::Completed { final_result, .. }) => final_result,
+            other => panic!("Expected Completed, got {:?}", other),
         }
     }
 
+    const OBJECTS: &str = r#"{"summary":"Two objects found.","objects":[
+        {"id":"1","name":"Beam","object_type":"structure","status":"active","created":null},
+        {"id":"2","name":"Pipe","object_type":"plumbing","status":null,"created":null}]}"#;
+
+    fn object_chunk(events: &[StreamEvent]) -> serde_json::Value {
+        events
+            .iter()
+            .find_map(|e| match e {
+                StreamEvent::ObjectChunk { data, .. } => Some(data.clone()),
+                _ => None,
+            })
+            .expect("object_chunk event")
+    }
+
     #[tokio::test]
-    async fn test_chat_task() {
-        let agent = MasterAgent::new(URL);
-        
-        let request = AgentRequest {
-            message: "hello, how are you?".to_string(),
-            user_id: Some("user_456".to_string()),
-            chat_id: Some("chat_789".to_string()),
-            object_id: None,
-            language: Some("en".to_string()),
-            session_id: None,
-            metadata: None,
-        };
-        dotenv::dotenv().ok();
-        let (_config, state) = app_init().await.unwrap();
+    async fn test_object_task() {
+        let mock = Arc::new(MockLlm::new().on("object retrieval", MockReply::text(OBJECTS)));
+        let events = run(mock.clone(), "show me the last 5 objects").await;
+
+        assert_eq!(
+            kinds(&events),
+            vec!["started", "coordinator_thinking", "text_chunk", "text_chunk", "object_chunk", "completed"]
+        );
+        assert_eq!(final_result(&events), "Two objects found.");
+        assert_eq!(object_chunk(&events)["objects"][1]["name"], "Pipe");
+
+        let format = &mock.requests()[0]["format"];
+        assert_eq!(format["type"], "object");
+        assert!(format["required"].as_array().unwrap().contains(&"objects".into()));
+    }
 
-        let mut rx = agent.handle_request_stream(state.clone(),request).await;
+    #[tokio::test]
+    async fn test_invalid_output_is_reprompted() {
+        let mock = Arc::new(MockLlm::new());
+        mock.push_reply(MockReply::text("Here are your objects: none"));
+        mock.push_reply(MockReply::text(OBJECTS));
+        let events = run(mock.clone(), "show me the last 5 objects").await;
+
+        assert!(thinking(&events).iter().any(|m| m.contains("rejected")));
+        assert_eq!(object_chunk(&events)["objects"][0]["name"], "Beam");
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 2);
+        let messages = requests[1]["messages"].as_array().unwrap();
+        assert!(messages.iter().any(|m| m["role"] == "assistant"));
+    }
 
-        while let Some(event) = rx.recv().await {
-            match event {
-                StreamEvent::TextChunk { chunk, .. } => {
-                    print!("{}", chunk);
-                }
-                StreamEvent::Completed { .. } => {
-                    println!("\n✅ Completed");
-                    break;
-                }
-                _ => {}
-            }
-        }
+    #[tokio::test]
+    async fn test_persistently_invalid_output_falls_back() {
+        let mock = Arc::new(MockLlm::new().on("", MockReply::text("not json")));
+        let events = run(mock.clone(), "describe the last image").await;
+
+        // 3 attempts per vision model, then give up without retrying transiently
+        assert_eq!(mock.requests().len(), 6);
+        assert!(thinking(&events).iter().any(|m| m.contains("falling back to llava")));
+        assert!(matches!(events.last(), Some(StreamEvent::Error { recoverable: false, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_chat_task() {
+        let mock = Arc::new(MockLlm::new().on("hello", MockReply::text("Hi there, all good here.")));
+        let events = run(mock, "hello, how are you?").await;
+
+        let streamed: String = events
+            .iter()
+            .filter_map(|e| match e {
+                StreamEvent::TextChunk { chunk, .. } => Some(chunk.as_str()),
+                _ => None,
+            })
+            .collect();
+        let chunks = kinds(&events).iter().filter(|k| *k == "text_chunk").count();
+
+        assert!(chunks > 1);
+        assert_eq!(streamed, "Hi there, all good here.");
+        assert_eq!(final_result(&events), "Hi there, all good here.");
     }
 
     #[tokio::test]
     async fn test_comparison_task() {
-        let agent = MasterAgent::new(URL);
-        
+        let reply = r#"{"summary":"The second one is newer.","items":[],"differences":[
+            {"category":"Date","before":"2024","after":"2025","significance":"low"}],"recommendation":null}"#;
+        let mock = Arc::new(MockLlm::new().on("compare", MockReply::text(reply)));
+        let events = run(mock, "compare the last 2 documents").await;
+
+        assert!(kinds(&events).contains(&"comparison_chunk".to_string()));
+        assert_eq!(final_result(&events), "The second one is newer.");
+    }
+
+    fn thinking(events: &[StreamEvent]) -> Vec<&str> {
+        events
+            .iter()
+            .filter_map(|e| match e {
+                StreamEvent::CoordinatorThinking { message, .. } => Some(message.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_vision_model_fallback() {
+        let mock = Arc::new(MockLlm::new().on_model("llama3.2-vision", MockReply::error(404, "model not found")));
+        let events = run(mock, "describe the last image").await;
+
+        let thinking = thinking(&events);
+        assert!(thinking.iter().any(|m| m.contains("falling back to llava")));
+        assert!(!thinking.iter().any(|m| m.contains("retrying")));
+        assert!(matches!(events.last(), Some(StreamEvent::Completed { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let mock = Arc::new(MockLlm::new().on_model("llava", MockReply::Timeout));
+        let events = run(mock.clone(), "show the last object").await;
+
+        assert_eq!(mock.requests().len(), 3);
+        assert_eq!(thinking(&events).iter().filter(|m| m.contains("retrying")).count(), 2);
+        assert!(matches!(events.last(), Some(StreamEvent::Error { recoverable: true, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_model_errors_are_not_recoverable() {
+        let mock = Arc::new(MockLlm::new().on_model("llava", MockReply::error(404, "model not found")));
+        let events = run(mock, "show the last object").await;
+
+        assert!(matches!(events.last(), Some(StreamEvent::Error { recoverable: false, .. })));
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_marked_area_is_cropped_without_tools() {
+        let description = r#"{"subject":"Crack","overview":"A hairline crack in the plaster.","sections":[]}"#;
+        let mock = Arc::new(MockLlm::new().on_image(MockReply::text(description)));
+        let state = crate::init::test_db_state(AiConfig {
+            mode: AgentMode::Keywords,
+            ..test_config(mock.clone())
+        })
+        .await;
+        let user_id = Uuid::now_v7();
+        let root_id = crate::init::test_root(&state.db, &user_id).await;
+        let mut png = std::io::Cursor::new(Vec::new());
+        image::RgbImage::new(64, 64)
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let storage_path = format!("images/{}.png", Uuid::now_v7());
+        state
+            .storage
+            .upload_blob(&storage_path, png.into_inner().into(), "image/png")
+            .await
+            .unwrap();
+        let leaf_id = sqlx::query_scalar!(
+            "INSERT INTO tree_nodes (user_id, parent_id, node_type, data) VALUES ($1, $2, 'ImageLeaf', $3) RETURNING id",
+            user_id,
+            root_id,
+            serde_json::json!({ "storage_path": storage_path, "mime_type": "image/png" })
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        let marked = crate::models::CreateAnnotationRequest {
+            shape: crate::models::AnnotationShape::Rectangle { x: 0.25, y: 0.25, width: 0.5, height: 0.5 },
+            label: "Crack".to_string(),
+            note: None,
+        };
+        crate::annotations::create(&state.db, &user_id, &leaf_id, marked).await.unwrap();
+
         let request = AgentRequest {
-            message: "compare the last 2 documents".to_string(),
-            user_id: Some("user_789".to_string()),
-            chat_id: None,
-            object_id: None,
-            language: Some("en".to_string()),
-            session_id: None,
-            metadata: None,
+            user_id: Some(user_id.to_string()),
+            object_id: Some(leaf_id.to_string()),
+            ..request("what is in the marked area?")
         };
-        dotenv::dotenv().ok();
-        let (_config, state) = app_init().await.unwrap();
+        let mut rx = state.master_agent.handle_request_stream(state.clone(), request).await;
+        let mut events = Vec::new();
+        while let Some(event) = rx.recv().await {
+            events.push(event);
+        }
 
-        let mut rx = agent.handle_request_stream(state.clone(),request).await;
+        assert!(final_result(&events).contains("A hairline crack in the plaster."));
+        let requests = mock.requests();
+        let prompt = requests[0]["messages"].as_array().unwrap().last().unwrap().clone();
+        assert!(prompt["content"].as_str().unwrap().contains("marked as \"Crack\""));
+        assert_eq!(prompt["images"].as_array().unwrap().len(), 1);
 
-        while let Some(event) = rx.recv().await {
-            match event {
-                StreamEvent::ComparisonChunk { data, .. } => {
-                    println!("\n🔄 Comparison: {}", serde_json::to_string_pretty(&data).unwrap());
-                }
-                StreamEvent::Completed { .. } => {
-                    println!("\n✅ Completed");
-                    break;
-                }
-                _ => {}
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tool_calls_are_streamed() {
+        let mock = Arc::new(MockLlm::new());
+        mock.push_reply(MockReply::tool_call("find_nodes", serde_json::json!({ "name": "Room 11" })));
+        mock.push_reply(MockReply::text("I could not look that up."));
+        let anonymous = AgentRequest {
+            user_id: None,
+            ..request("which photos do we have of room 11?")
+        };
+        let config = AiConfig {
+            mode: AgentMode::Tools,
+            ..test_config(mock.clone())
+        };
+        let events = run_request(config, anonymous).await;
+
+        assert_eq!(
+            kinds(&events),
+            vec!["started", "coordinator_thinking", "tool_call", "tool_result", "text_chunk", "completed"]
+        );
+        match &events[2] {
+            StreamEvent::ToolCall { tool, arguments, .. } => {
+                assert_eq!(tool, "find_nodes");
+                assert_eq!(arguments["name"], "Room 11");
             }
+            other => panic!("Expected ToolCall, got {:?}", other),
+        }
+        match &events[3] {
+            StreamEvent::ToolResult { result, is_error, .. } => {
+                assert!(is_error);
+                assert_eq!(result["code"], "UNAUTHORIZED");
+            }
+            other => panic!("Expected ToolResult, got {:?}", other),
+        }
+        assert_eq!(final_result(&events), "I could not look that up.");
+
+        let requests = mock.requests();
+        let tools: Vec<&str> = requests[0]["tools"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter_map(|t| t["function"]["name"].as_str())
+            .collect();
+        for name in ["list_children", "find_nodes", "get_images", "describe_image", "compare_images"] {
+            assert!(tools.contains(&name), "{} missing from {:?}", name, tools);
         }
+        let messages = requests[1]["messages"].as_array().unwrap();
+        assert!(messages.iter().any(|m| m["role"] == "tool"));
     }
 }
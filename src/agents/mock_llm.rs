@@ -0,0 +1,342 @@
+use axum::http::StatusCode;
+use bytes::Bytes;
+use rig::http_client::{self, LazyBody, Request, Response, StreamingResponse};
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+// ============================================================================
+// MOCK REPLIES
+// ============================================================================
+
+/// A single assistant turn produced by [`MockLlm`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockReply {
+    Text(String),
+    ToolCall { name: String, arguments: Value },
+    /// HTTP error status from the server, e.g. 404 for an unknown model
+    Error { status: u16, message: String },
+    /// Transport failure, as if the request timed out
+    Timeout,
+}
+
+impl MockReply {
+    pub fn text(text: impl Into<String>) -> Self {
+        Self::Text(text.into())
+    }
+
+    pub fn tool_call(name: impl Into<String>, arguments: Value) -> Self {
+        Self::ToolCall {
+            name: name.into(),
+            arguments,
+        }
+    }
+
+    pub fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::Error {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    /// Last user message contains the text (case-insensitive)
+    Contains(String),
+    /// Last user message carries at least one image
+    Image,
+    /// Request is for this model
+    Model(String),
+}
+
+// ============================================================================
+// MOCK LLM
+// ============================================================================
+
+/// Deterministic in-process stand-in for the Ollama HTTP API.
+///
+/// Replies are taken from the script queue first, then from the first
+/// matching rule, and otherwise echo the prompt (or, for requests with a
+/// JSON-schema `format`, return a minimal conforming instance). Every request body is
+/// recorded so tests can assert on what the agents sent.
+#[derive(Debug, Default)]
+pub struct MockLlm {
+    script: Mutex<VecDeque<MockReply>>,
+    rules: Vec<(Matcher, MockReply)>,
+    requests: Mutex<Vec<Value>>,
+}
+
+impl MockLlm {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replies with `reply` when the last user message contains `needle`
+    pub fn on(mut self, needle: impl Into<String>, reply: MockReply) -> Self {
+        self.rules
+            .push((Matcher::Contains(needle.into().to_lowercase()), reply));
+        self
+    }
+
+    /// Replies with `reply` when the last user message carries images
+    pub fn on_image(mut self, reply: MockReply) -> Self {
+        self.rules.push((Matcher::Image, reply));
+        self
+    }
+
+    /// Replies with `reply` to every request for `model`
+    pub fn on_model(mut self, model: impl Into<String>, reply: MockReply) -> Self {
+        self.rules.push((Matcher::Model(model.into()), reply));
+        self
+    }
+
+    /// Queues a reply for the next request, ahead of any rule
+    pub fn push_reply(&self, reply: MockReply) {
+        self.script.lock().unwrap().push_back(reply);
+    }
+
+    /// Request bodies received so far, oldest first
+    pub fn requests(&self) -> Vec<Value> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    fn reply_for(&self, request: &Value) -> MockReply {
+        if let Some(reply) = self.script.lock().unwrap().pop_front() {
+            return reply;
+        }
+
+        let (content, images) = last_user_message(request);
+        let content_lower = content.to_lowercase();
+        let matched = self.rules.iter().find(|(matcher, _)| match matcher {
+            Matcher::Contains(needle) => content_lower.contains(needle),
+            Matcher::Image => images > 0,
+            Matcher::Model(model) => request["model"] == model.as_str(),
+        });
+
+        match matched {
+            Some((_, reply)) => reply.clone(),
+            None if request["format"].is_object() => {
+                MockReply::text(schema_instance(&request["format"]).to_string())
+            }
+            None if images > 0 => {
+                MockReply::text(format!("Mock description of {} image(s).", images))
+            }
+            None => MockReply::text(format!("Mock response to: {}", content)),
+        }
+    }
+
+    fn chat(&self, body: &Bytes) -> http_client::Result<(Value, Vec<Value>)> {
+        let request: Value = serde_json::from_slice(body)
+            .map_err(|e| http_client::Error::Instance(Box::new(e)))?;
+        self.requests.lock().unwrap().push(request.clone());
+
+        let model = request["model"].as_str().unwrap_or("mock").to_string();
+        let reply = self.reply_for(&request);
+
+        let message = |content: &str, tool_calls: Value| {
+            json!({
+                "model": model,
+                "created_at": "2025-01-01T00:00:00Z",
+                "message": { "role": "assistant", "content": content, "tool_calls": tool_calls },
+                "done": false,
+            })
+        };
+        let (content, tool_calls) = match reply {
+            MockReply::Text(text) => (text, json!([])),
+            MockReply::ToolCall { name, arguments } => (
+                String::new(),
+                json!([{ "function": { "name": name, "arguments": arguments } }]),
+            ),
+            MockReply::Error { status, message } => {
+                let status = StatusCode::from_u16(status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+                return Err(http_client::Error::InvalidStatusCodeWithMessage(status, message));
+            }
+            MockReply::Timeout => {
+                let timeout = std::io::Error::new(std::io::ErrorKind::TimedOut, "mock request timed out");
+                return Err(http_client::Error::Instance(Box::new(timeout)));
+            }
+        };
+        let chunks = if content.is_empty() {
+            vec![message("", tool_calls.clone())]
+        } else {
+            content
+                .split_inclusive(' ')
+                .map(|word| message(word, json!([])))
+                .collect()
+        };
+
+        let mut full = message(&content, tool_calls);
+        full["done"] = json!(true);
+        full["done_reason"] = json!("stop");
+        full["prompt_eval_count"] = json!(0);
+        full["eval_count"] = json!(0);
+
+        Ok((full, chunks))
+    }
+
+    pub(crate) fn send<U>(&self, req: Request<Bytes>) -> http_client::Result<Response<LazyBody<U>>>
+    where
+        U: From<Bytes> + Send + 'static,
+    {
+        let body = if req.uri().path().ends_with("/api/chat") {
+            let (full, _) = self.chat(req.body())?;
+            serde_json::to_vec(&full).map_err(|e| http_client::Error::Instance(Box::new(e)))?
+        } else if req.uri().path().ends_with("/api/tags") {
+            br#"{"models":[]}"#.to_vec()
+        } else {
+            return Err(http_client::Error::InvalidStatusCode(StatusCode::NOT_FOUND));
+        };
+
+        let body: LazyBody<U> = Box::pin(async move { Ok(U::from(Bytes::from(body))) });
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(body)
+            .map_err(http_client::Error::Protocol)
+    }
+
+    pub(crate) fn send_streaming(&self, req: Request<Bytes>) -> http_client::Result<StreamingResponse> {
+        if !req.uri().path().ends_with("/api/chat") {
+            return Err(http_client::Error::InvalidStatusCode(StatusCode::NOT_FOUND));
+        }
+
+        let (mut full, chunks) = self.chat(req.body())?;
+        full["message"]["content"] = json!("");
+        full["message"]["tool_calls"] = json!([]);
+
+        let lines: Vec<http_client::Result<Bytes>> = chunks
+            .iter()
+            .chain(std::iter::once(&full))
+            .map(|line| {
+                let mut line = serde_json::to_vec(line)
+                    .map_err(|e| http_client::Error::Instance(Box::new(e)))?;
+                line.push(b'\n');
+                Ok(Bytes::from(line))
+            })
+            .collect();
+
+        Response::builder()
+            .status(StatusCode::OK)
+            .body(Box::pin(futures::stream::iter(lines)) as _)
+            .map_err(http_client::Error::Protocol)
+    }
+}
+
+/// Smallest value matching a JSON schema: required properties only,
+/// `"mock"` strings, zeros, empty arrays and `null` where allowed.
+fn schema_instance(schema: &Value) -> Value {
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(t) => vec![t.as_str()],
+        Value::Array(t) => t.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+    };
+    if types.contains(&"null") {
+        return Value::Null;
+    }
+    match types.first().copied() {
+        Some("object") => {
+            let required = schema["required"].as_array().cloned().unwrap_or_default();
+            let object = required
+                .iter()
+                .filter_map(Value::as_str)
+                .map(|key| (key.to_string(), schema_instance(&schema["properties"][key])))
+                .collect();
+            Value::Object(object)
+        }
+        Some("array") => json!([]),
+        Some("number" | "integer") => json!(0),
+        Some("boolean") => json!(false),
+        _ => json!("mock"),
+    }
+}
+
+fn last_user_message(request: &Value) -> (String, usize) {
+    request["messages"]
+        .as_array()
+        .and_then(|messages| messages.iter().rev().find(|m| m["role"] == "user"))
+        .map(|message| {
+            let content = message["content"].as_str().unwrap_or_default().to_string();
+            let images = message["images"].as_array().map(|i| i.len()).unwrap_or(0);
+            (content, images)
+        })
+        .unwrap_or_default()
+}
+
+// ============================================================================
+// TESTS
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::agents::{build_client, role_agent};
+    use crate::{AiConfig, AiRole};
+    use crate::storage::AiProvider;
+    use rig::completion::Prompt;
+    use rig::message::{ImageMediaType, Message, UserContent};
+    use rig::OneOrMany;
+    use std::sync::Arc;
+
+    fn mock_config(mock: Arc<MockLlm>) -> AiConfig {
+        AiConfig {
+            provider: AiProvider::Mock(mock),
+            ..AiConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rule_and_script_replies() {
+        let mock = Arc::new(MockLlm::new().on("weather", MockReply::text("Sunny.")));
+        let config = mock_config(mock.clone());
+        let client = build_client(&config).unwrap();
+        let agent = role_agent(&client, &config, AiRole::Text).build();
+
+        assert_eq!(agent.prompt("What is the WEATHER?").await.unwrap(), "Sunny.");
+        assert_eq!(agent.prompt("hello").await.unwrap(), "Mock response to: hello");
+
+        mock.push_reply(MockReply::text("Scripted."));
+        assert_eq!(agent.prompt("weather").await.unwrap(), "Scripted.");
+
+        let requests = mock.requests();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[0]["model"], config.model(AiRole::Text));
+        assert_eq!(requests[0]["keep_alive"], config.keep_alive);
+    }
+
+    #[tokio::test]
+    async fn test_vision_reply() {
+        let mock = Arc::new(MockLlm::new().on_image(MockReply::text("A wall with a crack.")));
+        let config = mock_config(mock.clone());
+        let client = build_client(&config).unwrap();
+        let agent = role_agent(&client, &config, AiRole::Vision).build();
+
+        let message = Message::User {
+            content: OneOrMany::many(vec![
+                UserContent::text("Describe the photo"),
+                UserContent::image_base64("aGVsbG8=", Some(ImageMediaType::JPEG), None),
+            ])
+            .unwrap(),
+        };
+
+        assert_eq!(agent.prompt(message).await.unwrap(), "A wall with a crack.");
+        assert_eq!(mock.requests()[0]["model"], config.model(AiRole::Vision));
+    }
+
+    #[test]
+    fn test_schema_instance() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "summary": { "type": "string" },
+                "items": { "type": "array", "items": { "type": "string" } },
+                "score": { "type": ["number", "null"] },
+                "extra": { "type": "string" }
+            },
+            "required": ["summary", "items", "score"]
+        });
+        assert_eq!(
+            schema_instance(&schema),
+            json!({ "summary": "mock", "items": [], "score": null })
+        );
+    }
+}
@@ -9,6 +9,11 @@ pub mod description_agent;
 pub mod comparison_agent;
 pub mod chat_agent;
 pub mod lang;
+pub mod ai_client;
+pub mod mock_llm;
+pub mod outputs;
+pub mod tools;
+pub mod tree_agent;
 // Re-export main types for convenience
 pub use events::StreamEvent;
 pub use lang::TextManager;
@@ -19,4 +24,8 @@ pub use document_agent::DocumentAgent;
 pub use description_agent::DescriptionAgent;
 pub use comparison_agent::ComparisonAgent;
 pub use chat_agent::ChatAgent;
+pub use tree_agent::TreeAgent;
+pub use mock_llm::{MockLlm, MockReply};
+pub use outputs::{StructuredOutput, ObjectList, DocumentList, ImageDescriptionOutput, ComparisonOutput, structured_call, structured_call_model};
+pub use ai_client::{AiClient, AiAgentBuilder, AiCallError, OllamaHttp, build_client, call_with_fallback, call_with_fallback_model, role_agent};
 pub use master_agent::{AgentRequest,AgentContext,CancellationToken,RequestManager,MasterAgent};
\ No newline at end of file
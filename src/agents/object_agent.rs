@@ -1,19 +1,16 @@
 use std::sync::Arc;
-use rig::providers::ollama;
-use rig::completion::Prompt;
 use tokio::sync::mpsc;
-use serde_json::json;
-use rig::prelude::CompletionClient;
-use crate::{AgentContext, AppState, StreamEvent, TaskParameters};
+use crate::agents::{structured_call, AiClient, ObjectList, StructuredOutput};
+use crate::{AgentContext, AiRole, AppState, StreamEvent, TaskParameters};
 pub struct ObjectAgent {
-    client: ollama::Client,
+    client: AiClient,
     request_id: String,
     event_tx: mpsc::Sender<StreamEvent>,
 }
 
 impl ObjectAgent {
     pub fn new(
-        client: ollama::Client,
+        client: AiClient,
         request_id: String,
         event_tx: mpsc::Sender<StreamEvent>,
     ) -> Self {
@@ -48,54 +45,31 @@ impl ObjectAgent {
             prompt, parameters.last, parameters.all, parameters.period, parameters.amount
         );
 
-        let agent = self
-            .client
-            .agent(&state.ai_config.text_model)
-            .preamble("You are an object management system. Return structured object data in JSON format.")
-            .build();
-
-        let response = agent.prompt(&agent_prompt).await?;
+        let output: ObjectList = structured_call(
+            &self.client,
+            &state.ai_config,
+            AiRole::Text,
+            &self.request_id,
+            &self.event_tx,
+            "You are an object management system. Return the matching objects as JSON.",
+            agent_prompt,
+        )
+        .await?;
 
         // Send text description
         self.send_event(StreamEvent::TextChunk {
             request_id: self.request_id.clone(),
-            chunk: format!("Found objects:\n{}\n", response),
+            chunk: format!("Found objects:\n{}\n", output.summary()),
         })
         .await;
 
         // Send structured data
-        let object_data = json!({
-            "objects": [
-                {
-                    "id": "obj_001",
-                    "name": "Sample Object 1",
-                    "type": "document",
-                    "created": "2024-12-20T10:00:00Z",
-                    "status": "active"
-                },
-                {
-                    "id": "obj_002",
-                    "name": "Sample Object 2",
-                    "type": "record",
-                    "created": "2024-12-22T14:30:00Z",
-                    "status": "pending"
-                }
-            ],
-            "total": 2,
-            "parameters": {
-                "last": parameters.last,
-                "all": parameters.all,
-                "period": format!("{:?}", parameters.period),
-                "amount": parameters.amount
-            }
-        });
-
         self.send_event(StreamEvent::ObjectChunk {
             request_id: self.request_id.clone(),
-            data: object_data,
+            data: serde_json::to_value(&output)?,
         })
         .await;
 
-        Ok(response)
+        Ok(output.summary())
     }
-}
\ No newline at end of file
+}
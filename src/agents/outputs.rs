@@ -0,0 +1,307 @@
+use rig::completion::{Chat, CompletionError, Message};
+use schemars::generate::SchemaSettings;
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use crate::agents::ai_client::generation_params;
+use crate::agents::{call_with_fallback_model, AiCallError, AiClient, StreamEvent};
+use crate::{AiConfig, AiRole};
+
+// ============================================================================
+// OUTPUT CONTRACT
+// ============================================================================
+
+/// Typed model output requested through a JSON-schema `format` constraint
+pub trait StructuredOutput: Serialize + DeserializeOwned + JsonSchema + Send {
+    /// Semantic checks the schema itself can't express
+    fn validate(&self) -> Result<(), String>;
+
+    /// Human-readable text streamed alongside the structured payload
+    fn summary(&self) -> String;
+}
+
+/// JSON schema with all subschemas inlined, as Ollama expects for `format`
+/// and tool parameters
+pub fn inline_schema<T: JsonSchema>() -> serde_json::Value {
+    let schema = SchemaSettings::draft2020_12()
+        .with(|s| s.inline_subschemas = true)
+        .into_generator()
+        .into_root_schema_for::<T>();
+    serde_json::to_value(schema).unwrap_or_default()
+}
+
+/// Parses and validates a model reply, tolerating Markdown code fences
+pub fn parse_output<T: StructuredOutput>(reply: &str) -> Result<T, String> {
+    let json = reply
+        .trim()
+        .trim_start_matches("```json")
+        .trim_start_matches("```")
+        .trim_end_matches("```")
+        .trim();
+    let output: T = serde_json::from_str(json).map_err(|e| format!("invalid JSON: {}", e))?;
+    output.validate()?;
+    Ok(output)
+}
+
+fn require(value: &str, field: &str) -> Result<(), String> {
+    if value.trim().is_empty() {
+        Err(format!("`{}` must not be empty", field))
+    } else {
+        Ok(())
+    }
+}
+
+// ============================================================================
+// OUTPUT TYPES
+// ============================================================================
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ObjectItem {
+    pub id: String,
+    pub name: String,
+    pub object_type: String,
+    pub status: Option<String>,
+    /// RFC 3339 timestamp, if known
+    pub created: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ObjectList {
+    pub summary: String,
+    pub objects: Vec<ObjectItem>,
+}
+
+impl StructuredOutput for ObjectList {
+    fn validate(&self) -> Result<(), String> {
+        require(&self.summary, "summary")?;
+        self.objects
+            .iter()
+            .try_for_each(|o| require(&o.name, "objects[].name"))
+    }
+
+    fn summary(&self) -> String {
+        self.summary.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DocumentItem {
+    pub id: String,
+    pub title: String,
+    pub document_type: String,
+    pub status: Option<String>,
+    /// RFC 3339 timestamp, if known
+    pub created: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DocumentList {
+    pub summary: String,
+    pub documents: Vec<DocumentItem>,
+}
+
+impl StructuredOutput for DocumentList {
+    fn validate(&self) -> Result<(), String> {
+        require(&self.summary, "summary")?;
+        self.documents
+            .iter()
+            .try_for_each(|d| require(&d.title, "documents[].title"))
+    }
+
+    fn summary(&self) -> String {
+        self.summary.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct DescriptionSection {
+    pub title: String,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ImageDescriptionOutput {
+    pub subject: String,
+    pub overview: String,
+    pub sections: Vec<DescriptionSection>,
+    /// Model confidence between 0 and 1
+    pub confidence: Option<f32>,
+}
+
+impl StructuredOutput for ImageDescriptionOutput {
+    fn validate(&self) -> Result<(), String> {
+        require(&self.subject, "subject")?;
+        require(&self.overview, "overview")?;
+        match self.confidence {
+            Some(c) if !(0.0..=1.0).contains(&c) => {
+                Err("`confidence` must be between 0 and 1".to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn summary(&self) -> String {
+        self.overview.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ComparedItem {
+    pub name: String,
+    pub summary: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct Difference {
+    pub category: String,
+    pub before: String,
+    pub after: String,
+    /// One of `low`, `medium`, `high`
+    pub significance: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct ComparisonOutput {
+    pub summary: String,
+    pub items: Vec<ComparedItem>,
+    pub differences: Vec<Difference>,
+    pub recommendation: Option<String>,
+}
+
+impl StructuredOutput for ComparisonOutput {
+    fn validate(&self) -> Result<(), String> {
+        require(&self.summary, "summary")?;
+        self.differences.iter().try_for_each(|d| {
+            require(&d.category, "differences[].category")?;
+            match d.significance.as_str() {
+                "low" | "medium" | "high" => Ok(()),
+                other => Err(format!(
+                    "`differences[].significance` must be low, medium or high, got '{}'",
+                    other
+                )),
+            }
+        })
+    }
+
+    fn summary(&self) -> String {
+        self.summary.clone()
+    }
+}
+
+// ============================================================================
+// STRUCTURED CALL
+// ============================================================================
+
+/// Prompts the role's models for a `T`, re-prompting with the validation
+/// error when a reply doesn't parse or validate.
+pub async fn structured_call<T: StructuredOutput>(
+    client: &AiClient,
+    config: &AiConfig,
+    role: AiRole,
+    request_id: &str,
+    event_tx: &mpsc::Sender<StreamEvent>,
+    preamble: &str,
+    prompt: impl Into<Message>,
+) -> Result<T, AiCallError> {
+    structured_call_model(client, config, role, request_id, event_tx, preamble, prompt)
+        .await
+        .map(|(output, _)| output)
+}
+
+/// [`structured_call`], also returning the model that answered
+pub async fn structured_call_model<T: StructuredOutput>(
+    client: &AiClient,
+    config: &AiConfig,
+    role: AiRole,
+    request_id: &str,
+    event_tx: &mpsc::Sender<StreamEvent>,
+    preamble: &str,
+    prompt: impl Into<Message>,
+) -> Result<(T, String), AiCallError> {
+    let prompt: Message = prompt.into();
+    let schema = inline_schema::<T>();
+    let mut params = generation_params(config, role);
+    params["format"] = schema;
+
+    call_with_fallback_model(client, config, role, request_id, event_tx, |builder| {
+        let agent = builder
+            .preamble(preamble)
+            .additional_params(params.clone())
+            .build();
+        let prompt = prompt.clone();
+
+        async move {
+            let mut history = Vec::new();
+            let mut prompt = prompt;
+            let mut attempt = 0;
+
+            loop {
+                let reply = agent.chat(prompt.clone(), history.clone()).await?;
+                let error = match parse_output::<T>(&reply) {
+                    Ok(output) => return Ok::<T, Box<dyn std::error::Error + Send + Sync>>(output),
+                    Err(error) => error,
+                };
+                if attempt >= config.schema_retries {
+                    return Err(CompletionError::ResponseError(format!(
+                        "Invalid structured output: {}",
+                        error
+                    ))
+                    .into());
+                }
+
+                attempt += 1;
+                let _ = event_tx
+                    .send(StreamEvent::CoordinatorThinking {
+                        request_id: request_id.to_string(),
+                        message: format!(
+                            "Model output rejected ({}); asking again ({}/{})",
+                            error, attempt, config.schema_retries
+                        ),
+                    })
+                    .await;
+                history.push(prompt);
+                history.push(Message::assistant(reply));
+                prompt = Message::user(format!(
+                    "Your previous answer was rejected: {}. Reply again with only a JSON object that matches the required schema.",
+                    error
+                ));
+            }
+        }
+    })
+    .await
+}
+
+// ============================================================================
+// TESTS
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_fenced_output() {
+        let reply = "```json\n{\"summary\":\"One object\",\"objects\":[{\"id\":\"1\",\"name\":\"Wall\",\"object_type\":\"structure\",\"status\":null,\"created\":null}]}\n```";
+        let output: ObjectList = parse_output(reply).unwrap();
+        assert_eq!(output.objects[0].name, "Wall");
+    }
+
+    #[test]
+    fn test_validation_rejects_bad_values() {
+        let reply = r#"{"subject":"Room","overview":"Drywall","sections":[],"confidence":1.5}"#;
+        let err = parse_output::<ImageDescriptionOutput>(reply).unwrap_err();
+        assert!(err.contains("confidence"));
+
+        let err = parse_output::<ObjectList>("not json").unwrap_err();
+        assert!(err.starts_with("invalid JSON"));
+    }
+
+    #[test]
+    fn test_schema_is_inlined() {
+        let schema = inline_schema::<ComparisonOutput>();
+        assert_eq!(schema["type"], "object");
+        assert!(schema.get("$defs").is_none());
+        assert_eq!(schema["properties"]["differences"]["items"]["type"], "object");
+    }
+}
@@ -35,6 +35,8 @@ pub enum PromptKey {
     Document,
     Description,
     Comparison,
+    /// Asks about an area marked on an image
+    Marked,
     Last,
     New,
     All,
@@ -16,6 +16,8 @@ pub struct TaskParameters {
     pub all: bool,
     pub period: Option<Period>,
     pub amount: Option<usize>,
+    /// Only the marked area of the image is asked about
+    pub marked: bool,
 }
 
 pub struct TaskDetector;
@@ -44,7 +46,7 @@ impl TaskDetector {
             return Ok(Task::Comparison { parameters });
         }
 
-        if prompt_context.keys.contains(&PromptKey::Description) {
+        if prompt_context.keys.contains(&PromptKey::Description) || prompt_context.keys.contains(&PromptKey::Marked) {
             return Ok(Task::Description { parameters });
         }
 
@@ -66,6 +68,7 @@ impl TaskDetector {
             all: context.keys.contains(&PromptKey::All),
             period: context.period,
             amount: context.amount,
+            marked: context.keys.contains(&PromptKey::Marked),
         }
     }
 }
@@ -126,6 +129,20 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_detect_marked_area_task() {
+        let mut parser = ContextParser::new();
+        let context = parser.parse("en", "what is in the marked area?").unwrap();
+
+        let detector = TaskDetector::new();
+        let task = detector.detect_task(&context, "what is in the marked area?").unwrap();
+
+        match task {
+            Task::Description { parameters } => assert!(parameters.marked),
+            _ => panic!("Expected Description task"),
+        }
+    }
+
     #[test]
     fn test_detect_chat_task() {
         let mut parser = ContextParser::new();
@@ -0,0 +1,438 @@
+use std::future::Future;
+use std::sync::Arc;
+use base64::Engine;
+use rig::completion::ToolDefinition;
+use rig::message::{ImageMediaType, Message, MimeType, UserContent};
+use rig::tool::Tool;
+use rig::OneOrMany;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::agents::outputs::inline_schema;
+use crate::agents::{structured_call, AiClient, ComparisonOutput, ImageDescriptionOutput, StreamEvent};
+use crate::error::{AppError, ErrorCode, Result};
+use crate::image_quality::ImageQuality;
+use crate::models::{Annotation, NodeSummary, NodeType, SearchHit};
+use crate::storage::PREVIEW_RENDITION;
+use crate::{annotations, descriptions, search, tree, AiRole, AppState};
+
+// ============================================================================
+// TOOL CONTEXT
+// ============================================================================
+
+/// Longest side of the crop vision models get when asked about a marked area
+const CROP_SIZE: u32 = 1280;
+
+/// Per-request state shared by every tool: who is asking and where to
+/// report tool activity.
+#[derive(Clone)]
+pub struct ToolContext {
+    pub state: Arc<AppState>,
+    pub client: AiClient,
+    pub user_id: Option<String>,
+    pub language: String,
+    pub request_id: String,
+    pub event_tx: mpsc::Sender<StreamEvent>,
+}
+
+impl ToolContext {
+    fn user(&self) -> Option<&str> {
+        self.user_id.as_deref()
+    }
+
+    /// Runs a tool body between `ToolCall` and `ToolResult` events
+    async fn run<A, T>(&self, tool: &str, args: &A, body: impl Future<Output = Result<T>>) -> Result<T>
+    where
+        A: Serialize,
+        T: Serialize,
+    {
+        let call_id = Uuid::now_v7().to_string();
+        let _ = self
+            .event_tx
+            .send(StreamEvent::ToolCall {
+                request_id: self.request_id.clone(),
+                call_id: call_id.clone(),
+                tool: tool.to_string(),
+                arguments: serde_json::to_value(args).unwrap_or_default(),
+            })
+            .await;
+
+        let result = body.await;
+        let (value, is_error) = match &result {
+            Ok(output) => (serde_json::to_value(output), false),
+            Err(error) => (serde_json::to_value(error), true),
+        };
+        let _ = self
+            .event_tx
+            .send(StreamEvent::ToolResult {
+                request_id: self.request_id.clone(),
+                call_id,
+                tool: tool.to_string(),
+                result: value.unwrap_or_default(),
+                is_error,
+            })
+            .await;
+
+        result
+    }
+
+    /// Loads an image leaf the user can access as vision model input
+    async fn load_image(&self, node_id: &Uuid) -> Result<(NodeSummary, UserContent)> {
+        let node = tree::get_node(&self.state.db, self.user(), node_id).await?;
+        let image = vision_input(&self.state, &node).await?;
+        Ok((node, image))
+    }
+}
+
+/// An image leaf as vision model input, without access checks
+pub(crate) async fn vision_input(state: &AppState, node: &NodeSummary) -> Result<UserContent> {
+    if node.node_type != NodeType::ImageLeaf {
+        return Err(AppError::bad_request(format!("Node {} is not an image", node.id)));
+    }
+    // The preview rendition is plenty for vision models and much smaller
+    let preview = &node.data["renditions"][PREVIEW_RENDITION];
+    let source = if preview.is_object() { preview } else { &node.data };
+    let storage_path = source["storage_path"]
+        .as_str()
+        .ok_or_else(|| AppError::bad_request("No storage path"))?;
+
+    let bytes = state.storage.download_image(storage_path).await?;
+    let media_type = source["mime_type"]
+        .as_str()
+        .and_then(ImageMediaType::from_mime_type);
+    Ok(UserContent::image_base64(
+        base64::engine::general_purpose::STANDARD.encode(&bytes),
+        media_type,
+        None,
+    ))
+}
+
+/// The area of an image marked by `annotation`, with some surroundings,
+/// as vision model input
+pub(crate) async fn marked_area_input(state: &AppState, user: Option<&str>, annotation: &Annotation) -> Result<UserContent> {
+    let node = tree::get_node(&state.db, user, &annotation.node_id).await?;
+    // Crops come from the original, the preview may be too coarse for a small defect
+    let storage_path = node.data["storage_path"]
+        .as_str()
+        .ok_or_else(|| AppError::bad_request("No storage path"))?;
+
+    let bytes = state.storage.download_image(storage_path).await?;
+    let processor = state.image_processor.clone();
+    let shape = annotation.shape.clone();
+    let cropped = tokio::task::spawn_blocking(move || processor.crop(&bytes, &shape, CROP_SIZE))
+        .await
+        .map_err(|e| AppError::internal(format!("Crop task failed: {}", e)))??;
+    Ok(UserContent::image_base64(
+        base64::engine::general_purpose::STANDARD.encode(&cropped),
+        Some(ImageMediaType::JPEG),
+        None,
+    ))
+}
+
+/// EXIF capture time when known, upload time otherwise
+fn taken_at(node: &NodeSummary) -> &str {
+    node.data["exif"]["captured_at"]
+        .as_str()
+        .unwrap_or(&node.created_at)
+}
+
+/// Whether upload analysis flagged the image; unanalysed images pass
+pub(crate) fn is_low_quality(node: &NodeSummary) -> bool {
+    serde_json::from_value::<ImageQuality>(node.data["quality"].clone()).is_ok_and(|quality| quality.is_low())
+}
+
+pub(crate) fn vision_prompt(text: String, images: Vec<UserContent>) -> Message {
+    let content: Vec<_> = std::iter::once(UserContent::text(text)).chain(images).collect();
+    Message::User {
+        content: OneOrMany::many(content).expect("prompt text is always present"),
+    }
+}
+
+fn definition<A: JsonSchema>(name: &str, description: &str) -> ToolDefinition {
+    ToolDefinition {
+        name: name.to_string(),
+        description: description.to_string(),
+        parameters: inline_schema::<A>(),
+    }
+}
+
+// ============================================================================
+// TOOL ARGUMENTS
+// ============================================================================
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct NodeArgs {
+    /// Id of the tree node
+    #[schemars(with = "String")]
+    pub node_id: Uuid,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DescribeImageArgs {
+    /// Id of the image
+    #[schemars(with = "String")]
+    pub node_id: Uuid,
+    /// Id of an annotation on the image, to describe only the area it
+    /// marks, e.g. when asked about "the marked area"
+    #[schemars(with = "Option<String>")]
+    #[serde(default)]
+    pub annotation_id: Option<Uuid>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct FindNodesArgs {
+    /// Part of the node name, branch label or root title
+    pub name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct SearchArgs {
+    /// Words to look for in names, labels and image descriptions, e.g.
+    /// `drywall`; quote phrases and prefix words to exclude with `-`
+    pub query: String,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct GetImagesArgs {
+    /// Id of the node whose subtree is searched
+    #[schemars(with = "String")]
+    pub node_id: Uuid,
+    /// Earliest capture time, `YYYY-MM-DD` or RFC 3339
+    pub from: Option<String>,
+    /// Latest capture time, `YYYY-MM-DD` or RFC 3339
+    pub to: Option<String>,
+    /// Keep only the first of each group of near-identical shots, e.g.
+    /// before describing or comparing them
+    #[serde(default)]
+    pub skip_near_duplicates: bool,
+    /// Also return blurred, badly exposed or noisy photos, which are
+    /// left out by default
+    #[serde(default)]
+    pub include_low_quality: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct CompareImagesArgs {
+    /// Id of the earlier image
+    #[schemars(with = "String")]
+    pub a: Uuid,
+    /// Id of the later image
+    #[schemars(with = "String")]
+    pub b: Uuid,
+}
+
+// ============================================================================
+// TOOLS
+// ============================================================================
+
+pub struct ListChildren(pub ToolContext);
+
+impl Tool for ListChildren {
+    const NAME: &'static str = "list_children";
+    type Error = AppError;
+    type Args = NodeArgs;
+    type Output = Vec<NodeSummary>;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        definition::<NodeArgs>(Self::NAME, "List the direct children of a tree node.")
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output> {
+        let ctx = &self.0;
+        ctx.run(Self::NAME, &args, tree::list_children(&ctx.state.db, ctx.user(), &args.node_id))
+            .await
+    }
+}
+
+pub struct FindNodes(pub ToolContext);
+
+impl Tool for FindNodes {
+    const NAME: &'static str = "find_nodes";
+    type Error = AppError;
+    type Args = FindNodesArgs;
+    type Output = Vec<NodeSummary>;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        definition::<FindNodesArgs>(
+            Self::NAME,
+            "Find objects, rooms and images visible to the user by name.",
+        )
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output> {
+        let ctx = &self.0;
+        ctx.run(Self::NAME, &args, tree::find_nodes(&ctx.state.db, ctx.user(), &args.name))
+            .await
+    }
+}
+
+pub struct Search(pub ToolContext);
+
+impl Tool for Search {
+    const NAME: &'static str = "search";
+    type Error = AppError;
+    type Args = SearchArgs;
+    type Output = Vec<SearchHit>;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        definition::<SearchArgs>(
+            Self::NAME,
+            "Search nodes visible to the user by content, e.g. photos showing drywall. \
+             Matches names, labels and what images were described to show, best matches first.",
+        )
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output> {
+        let ctx = &self.0;
+        ctx.run(Self::NAME, &args, search::search(&ctx.state.db, ctx.user(), &args.query))
+            .await
+    }
+}
+
+pub struct GetImages(pub ToolContext);
+
+impl Tool for GetImages {
+    const NAME: &'static str = "get_images";
+    type Error = AppError;
+    type Args = GetImagesArgs;
+    type Output = Vec<NodeSummary>;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        definition::<GetImagesArgs>(
+            Self::NAME,
+            "List the images under a node, oldest first, optionally within a date range. \
+             Images include EXIF capture time, camera and GPS position when known. \
+             Blurred, badly exposed or noisy photos are left out unless asked for.",
+        )
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output> {
+        let ctx = &self.0;
+        ctx.run(Self::NAME, &args, async {
+            let from = args.from.as_deref().map(tree::parse_time).transpose()?;
+            let to = args.to.as_deref().map(tree::parse_time).transpose()?;
+            let mut images = tree::get_images(&ctx.state.db, ctx.user(), &args.node_id, from, to).await?;
+            if !args.include_low_quality {
+                images.retain(|image| !is_low_quality(image));
+            }
+            if !args.skip_near_duplicates {
+                return Ok(images);
+            }
+            let distance = ctx.state.upload_config.near_duplicate_distance;
+            tree::without_near_duplicates(&ctx.state.db, images, distance).await
+        })
+        .await
+    }
+}
+
+pub struct ListAnnotations(pub ToolContext);
+
+impl Tool for ListAnnotations {
+    const NAME: &'static str = "list_annotations";
+    type Error = AppError;
+    type Args = NodeArgs;
+    type Output = Vec<Annotation>;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        definition::<NodeArgs>(
+            Self::NAME,
+            "List the areas marked on an image, with their labels, notes and whether they are resolved.",
+        )
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output> {
+        let ctx = &self.0;
+        ctx.run(Self::NAME, &args, annotations::list(&ctx.state.db, ctx.user(), &args.node_id))
+            .await
+    }
+}
+
+pub struct DescribeImage(pub ToolContext);
+
+impl Tool for DescribeImage {
+    const NAME: &'static str = "describe_image";
+    type Error = AppError;
+    type Args = DescribeImageArgs;
+    type Output = ImageDescriptionOutput;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        definition::<DescribeImageArgs>(
+            Self::NAME,
+            "Describe what an image shows, or only the area marked by one of its annotations.",
+        )
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output> {
+        let ctx = &self.0;
+        ctx.run(Self::NAME, &args, async {
+            if args.annotation_id.is_some() {
+                return descriptions::describe_marked_area(
+                    &ctx.state,
+                    &ctx.client,
+                    &ctx.request_id,
+                    &ctx.event_tx,
+                    ctx.user(),
+                    &args.node_id,
+                    args.annotation_id.as_ref(),
+                    &ctx.language,
+                )
+                .await;
+            }
+            let node = tree::get_node(&ctx.state.db, ctx.user(), &args.node_id).await?;
+            descriptions::describe_image(
+                &ctx.state,
+                &ctx.client,
+                &ctx.request_id,
+                &ctx.event_tx,
+                ctx.user(),
+                &node,
+                &ctx.language,
+            )
+            .await
+        })
+        .await
+    }
+}
+
+pub struct CompareImages(pub ToolContext);
+
+impl Tool for CompareImages {
+    const NAME: &'static str = "compare_images";
+    type Error = AppError;
+    type Args = CompareImagesArgs;
+    type Output = ComparisonOutput;
+
+    async fn definition(&self, _prompt: String) -> ToolDefinition {
+        definition::<CompareImagesArgs>(Self::NAME, "Compare two images and list what changed.")
+    }
+
+    async fn call(&self, args: Self::Args) -> Result<Self::Output> {
+        let ctx = &self.0;
+        ctx.run(Self::NAME, &args, async {
+            let (before, first) = ctx.load_image(&args.a).await?;
+            let (after, second) = ctx.load_image(&args.b).await?;
+            let prompt = vision_prompt(
+                format!(
+                    "The first photo was taken at {}, the second at {}. Compare them in {} language.",
+                    taken_at(&before),
+                    taken_at(&after),
+                    ctx.language
+                ),
+                vec![first, second],
+            );
+            structured_call(
+                &ctx.client,
+                &ctx.state.ai_config,
+                AiRole::Vision,
+                &ctx.request_id,
+                &ctx.event_tx,
+                "You are an inspection assistant comparing construction site photos over time.",
+                prompt,
+            )
+            .await
+            .map_err(|e| AppError::new(ErrorCode::ModelError, e.message))
+        })
+        .await
+    }
+}
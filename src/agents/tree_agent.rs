@@ -0,0 +1,93 @@
+use std::sync::Arc;
+use rig::completion::Prompt;
+use tokio::sync::mpsc;
+use crate::agents::tools::{
+    CompareImages, DescribeImage, FindNodes, GetImages, ListAnnotations, ListChildren, Search, ToolContext,
+};
+use crate::agents::{call_with_fallback, AiClient, StreamEvent};
+use crate::{AgentContext, AiRole, AppState};
+
+/// Upper bound on model turns spent calling tools before answering
+const MAX_TOOL_TURNS: usize = 8;
+
+/// Tool-calling agent that looks up nodes and images in the user's tree
+/// instead of relying on keyword routing.
+pub struct TreeAgent {
+    client: AiClient,
+    request_id: String,
+    event_tx: mpsc::Sender<StreamEvent>,
+}
+
+impl TreeAgent {
+    pub fn new(
+        client: AiClient,
+        request_id: String,
+        event_tx: mpsc::Sender<StreamEvent>,
+    ) -> Self {
+        Self {
+            client,
+            request_id,
+            event_tx,
+        }
+    }
+
+    async fn send_event(&self, event: StreamEvent) {
+        let _ = self.event_tx.send(event).await;
+    }
+
+    pub async fn execute(
+        &self,
+        state: Arc<AppState>,
+        prompt: &str,
+        context: &AgentContext,
+    ) -> Result<String, Box<dyn std::error::Error + Send + Sync>> {
+        let mut preamble = format!(
+            "You help users inspect their objects, rooms and photos. \
+             Use the tools to look up nodes and images instead of guessing; node ids are UUIDs. \
+             Respond in {} language.",
+            context.language
+        );
+        if let Some(object_id) = &context.object_id {
+            preamble.push_str(&format!(" The user is currently looking at node {}.", object_id));
+        }
+
+        let tools = ToolContext {
+            state: state.clone(),
+            client: self.client.clone(),
+            user_id: context.user_id.clone(),
+            language: context.language.clone(),
+            request_id: self.request_id.clone(),
+            event_tx: self.event_tx.clone(),
+        };
+
+        let response = call_with_fallback(
+            &self.client,
+            &state.ai_config,
+            AiRole::Text,
+            &self.request_id,
+            &self.event_tx,
+            |builder| {
+                let agent = builder
+                    .preamble(&preamble)
+                    .tool(ListChildren(tools.clone()))
+                    .tool(FindNodes(tools.clone()))
+                    .tool(Search(tools.clone()))
+                    .tool(GetImages(tools.clone()))
+                    .tool(ListAnnotations(tools.clone()))
+                    .tool(DescribeImage(tools.clone()))
+                    .tool(CompareImages(tools.clone()))
+                    .build();
+                async move { agent.prompt(prompt).multi_turn(MAX_TOOL_TURNS).await }
+            },
+        )
+        .await?;
+
+        self.send_event(StreamEvent::TextChunk {
+            request_id: self.request_id.clone(),
+            chunk: response.clone(),
+        })
+        .await;
+
+        Ok(response)
+    }
+}
@@ -0,0 +1,212 @@
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use crate::error::{AppError, Result};
+use crate::models::{
+    Annotation, AnnotationStatus, CreateAnnotationRequest, NodeData, NodeType, TreeNode, UpdateAnnotationRequest,
+};
+use crate::tree;
+
+// ============================================================================
+// Queries
+// ============================================================================
+
+struct AnnotationRow {
+    id: Uuid,
+    node_id: Uuid,
+    shape: serde_json::Value,
+    label: String,
+    note: Option<String>,
+    author_id: Uuid,
+    status: AnnotationStatus,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<AnnotationRow> for Annotation {
+    type Error = AppError;
+
+    fn try_from(row: AnnotationRow) -> Result<Self> {
+        Ok(Self {
+            id: row.id,
+            node_id: row.node_id,
+            shape: serde_json::from_value(row.shape)?,
+            label: row.label,
+            note: row.note,
+            author_id: row.author_id,
+            status: row.status,
+            created_at: row.created_at.to_rfc3339(),
+            updated_at: row.updated_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Annotations of several images, oldest first, without access checks
+async fn for_nodes(db: &sqlx::PgPool, node_ids: &[Uuid]) -> Result<Vec<Annotation>> {
+    let rows = sqlx::query_as!(
+        AnnotationRow,
+        r#"
+        SELECT id, node_id, shape, label, note, author_id,
+               status as "status: AnnotationStatus", created_at, updated_at
+        FROM image_annotations
+        WHERE node_id = ANY($1)
+        ORDER BY created_at, id
+        "#,
+        node_ids
+    )
+    .fetch_all(db)
+    .await?;
+
+    rows.into_iter().map(Annotation::try_from).collect()
+}
+
+/// Fails unless `node_id` is an image `user` can see
+async fn require_image(db: &sqlx::PgPool, user: Option<&str>, node_id: &Uuid) -> Result<()> {
+    let node = tree::get_node(db, user, node_id).await?;
+    if node.node_type != NodeType::ImageLeaf {
+        return Err(AppError::bad_request(format!("Node {} is not an image", node_id)));
+    }
+    Ok(())
+}
+
+pub async fn list(db: &sqlx::PgPool, user: Option<&str>, node_id: &Uuid) -> Result<Vec<Annotation>> {
+    require_image(db, user, node_id).await?;
+    for_nodes(db, &[*node_id]).await
+}
+
+pub async fn get(db: &sqlx::PgPool, user: Option<&str>, node_id: &Uuid, id: &Uuid) -> Result<Annotation> {
+    require_image(db, user, node_id).await?;
+    sqlx::query_as!(
+        AnnotationRow,
+        r#"
+        SELECT id, node_id, shape, label, note, author_id,
+               status as "status: AnnotationStatus", created_at, updated_at
+        FROM image_annotations
+        WHERE id = $1 AND node_id = $2
+        "#,
+        id,
+        node_id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::not_found("Annotation"))?
+    .try_into()
+}
+
+pub async fn create(
+    db: &sqlx::PgPool,
+    author_id: &Uuid,
+    node_id: &Uuid,
+    request: CreateAnnotationRequest,
+) -> Result<Annotation> {
+    require_image(db, Some(&author_id.to_string()), node_id).await?;
+    request.shape.validate()?;
+    let label = required_label(&request.label)?;
+
+    sqlx::query_as!(
+        AnnotationRow,
+        r#"
+        INSERT INTO image_annotations (node_id, shape, label, note, author_id)
+        VALUES ($1, $2, $3, $4, $5)
+        RETURNING id, node_id, shape, label, note, author_id,
+                  status as "status: AnnotationStatus", created_at, updated_at
+        "#,
+        node_id,
+        serde_json::to_value(&request.shape)?,
+        label,
+        request.note,
+        author_id
+    )
+    .fetch_one(db)
+    .await?
+    .try_into()
+}
+
+/// Applies `request` to an annotation. Anyone who can see the image may
+/// open or resolve it; only its author may change what it marks.
+pub async fn update(
+    db: &sqlx::PgPool,
+    user_id: &Uuid,
+    node_id: &Uuid,
+    id: &Uuid,
+    request: UpdateAnnotationRequest,
+) -> Result<Annotation> {
+    let current = get(db, Some(&user_id.to_string()), node_id, id).await?;
+    let edits_content = request.shape.is_some() || request.label.is_some() || request.note.is_some();
+    if edits_content && current.author_id != *user_id {
+        return Err(AppError::forbidden("Only the author can change an annotation"));
+    }
+    if let Some(shape) = &request.shape {
+        shape.validate()?;
+    }
+    let label = request.label.as_deref().map(required_label).transpose()?;
+    let shape = request.shape.as_ref().map(serde_json::to_value).transpose()?;
+
+    sqlx::query_as!(
+        AnnotationRow,
+        r#"
+        UPDATE image_annotations
+        SET shape = COALESCE($3, shape),
+            label = COALESCE($4, label),
+            note = COALESCE($5, note),
+            status = COALESCE($6, status),
+            updated_at = now()
+        WHERE id = $1 AND node_id = $2
+        RETURNING id, node_id, shape, label, note, author_id,
+                  status as "status: AnnotationStatus", created_at, updated_at
+        "#,
+        id,
+        node_id,
+        shape,
+        label,
+        request.note,
+        request.status as Option<AnnotationStatus>
+    )
+    .fetch_one(db)
+    .await?
+    .try_into()
+}
+
+/// Deletes an annotation; only its author may
+pub async fn delete(db: &sqlx::PgPool, user_id: &Uuid, node_id: &Uuid, id: &Uuid) -> Result<()> {
+    let current = get(db, Some(&user_id.to_string()), node_id, id).await?;
+    if current.author_id != *user_id {
+        return Err(AppError::forbidden("Only the author can delete an annotation"));
+    }
+    sqlx::query!("DELETE FROM image_annotations WHERE id = $1", id)
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// Fills in the annotations of every image in a loaded tree
+pub async fn attach(db: &sqlx::PgPool, node: &mut TreeNode) -> Result<()> {
+    let leaf_ids: Vec<Uuid> = node.collect_leaves().iter().map(|leaf| leaf.id).collect();
+    if leaf_ids.is_empty() {
+        return Ok(());
+    }
+
+    let mut by_node: HashMap<Uuid, Vec<Annotation>> = HashMap::new();
+    for annotation in for_nodes(db, &leaf_ids).await? {
+        by_node.entry(annotation.node_id).or_default().push(annotation);
+    }
+    fill(node, &mut by_node);
+    Ok(())
+}
+
+fn fill(node: &mut TreeNode, by_node: &mut HashMap<Uuid, Vec<Annotation>>) {
+    if let NodeData::Image { annotations, .. } = &mut node.data {
+        *annotations = by_node.remove(&node.id).unwrap_or_default();
+    }
+    for child in &mut node.children {
+        fill(child, by_node);
+    }
+}
+
+fn required_label(label: &str) -> Result<&str> {
+    let label = label.trim();
+    if label.is_empty() {
+        return Err(AppError::validation("Annotation label is required"));
+    }
+    Ok(label)
+}
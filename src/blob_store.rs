@@ -0,0 +1,801 @@
+use async_trait::async_trait;
+use axum::http::{header, HeaderMap, HeaderValue};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use s3::bucket::Bucket;
+use s3::creds::Credentials;
+use s3::region::Region;
+use s3::serde_types::Part;
+use sha2::Sha256;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+use crate::error::{AppError, ErrorCode, Result};
+use crate::models::ImageMetadata;
+
+// ============================================================================
+// BlobStore trait
+// ============================================================================
+
+/// Object storage used for image blobs. Paths are `/`-separated keys such
+/// as `images/{user}/{node}/{hash}.jpg`.
+#[async_trait]
+pub trait BlobStore: Send + Sync {
+    async fn upload(&self, path: &str, data: Bytes, content_type: &str) -> Result<()>;
+
+    async fn download(&self, path: &str) -> Result<Bytes>;
+
+    /// Streams a blob, or only the inclusive byte range `range` of it
+    async fn download_stream(&self, path: &str, range: Option<(u64, u64)>) -> Result<BlobStream>;
+
+    async fn delete(&self, path: &str) -> Result<()>;
+
+    /// Copies a blob within the store without moving it through this process
+    async fn copy(&self, from: &str, to: &str) -> Result<()>;
+
+    async fn exists(&self, path: &str) -> Result<bool>;
+
+    async fn metadata(&self, path: &str) -> Result<ImageMetadata>;
+
+    /// All keys starting with `prefix`
+    async fn list(&self, prefix: &str) -> Result<Vec<String>>;
+
+    /// Time-limited download URL
+    async fn presign(&self, path: &str, expires_in_secs: u32) -> Result<String>;
+
+    /// Time-limited URL a client can `PUT` the blob to directly. The request
+    /// must carry `content_type` as its `Content-Type`.
+    async fn presign_put(&self, path: &str, content_type: &str, expires_in_secs: u32) -> Result<String>;
+
+    /// Long-lived URL stored with the node
+    fn public_url(&self, path: &str) -> String;
+
+    /// Starts a streamed upload whose final key is chosen on commit
+    async fn begin_upload(&self, content_type: &str) -> Result<Box<dyn BlobUpload>>;
+
+    /// Staging data of streamed uploads started before `before` that were
+    /// never committed or aborted. Removes it unless `dry_run`.
+    async fn purge_staging(&self, before: DateTime<Utc>, dry_run: bool) -> Result<Vec<String>>;
+}
+
+/// A streamed upload. Chunks go to a staging location until [`commit`]
+/// moves them to their key, which is usually only known once the content
+/// hash is. Dropping an upload without committing or aborting it leaves
+/// the staged data behind.
+///
+/// [`commit`]: BlobUpload::commit
+#[async_trait]
+pub trait BlobUpload: Send {
+    async fn write(&mut self, chunk: Bytes) -> Result<()>;
+
+    async fn commit(self: Box<Self>, path: &str) -> Result<()>;
+
+    async fn abort(self: Box<Self>) -> Result<()>;
+}
+
+pub type BlobStream = BoxStream<'static, Result<Bytes>>;
+
+fn storage_error(action: &str, e: impl std::fmt::Display) -> AppError {
+    AppError::new(ErrorCode::StorageError, format!("{} failed: {}", action, e))
+}
+
+// ============================================================================
+// S3
+// ============================================================================
+
+pub struct S3Store {
+    bucket: Bucket,
+    public_url_base: String,
+}
+
+impl S3Store {
+    pub fn new(
+        bucket_name: String,
+        region: String,
+        access_key: String,
+        secret_key: String,
+        public_url_base: String,
+        endpoint: Option<String>,
+    ) -> Result<Self> {
+        let region = if let Some(ep) = endpoint {
+            Region::Custom {
+                region: region.clone(),
+                endpoint: ep,
+            }
+        } else {
+            Region::from_str(&region)
+                .map_err(|e| AppError::internal(format!("Invalid region: {}", e)))?
+        };
+
+        let credentials = Credentials::new(Some(&access_key), Some(&secret_key), None, None, None)
+            .map_err(|e| AppError::internal(format!("Credentials error: {}", e)))?;
+
+        let bucket = Bucket::new(&bucket_name, region, credentials)
+            .map_err(|e| AppError::internal(format!("Bucket creation failed: {}", e)))?;
+
+        // Use path-style for compatibility with MinIO/LocalStack
+        Ok(Self {
+            bucket: *bucket.with_path_style(),
+            public_url_base,
+        })
+    }
+}
+
+#[async_trait]
+impl BlobStore for S3Store {
+    async fn upload(&self, path: &str, data: Bytes, content_type: &str) -> Result<()> {
+        self.bucket
+            .put_object_with_content_type(path, &data, content_type)
+            .await
+            .map_err(|e| storage_error("S3 upload", e))?;
+        Ok(())
+    }
+
+    async fn download(&self, path: &str) -> Result<Bytes> {
+        let response = self
+            .bucket
+            .get_object(path)
+            .await
+            .map_err(|e| storage_error("S3 download", e))?;
+        Ok(Bytes::from(response.bytes().to_vec()))
+    }
+
+    async fn download_stream(&self, path: &str, range: Option<(u64, u64)>) -> Result<BlobStream> {
+        if let Some((start, end)) = range {
+            // Ranges are requested by players and viewers in small pieces
+            let response = self
+                .bucket
+                .get_object_range(path, start, Some(end))
+                .await
+                .map_err(|e| storage_error("S3 download", e))?;
+            let data = Bytes::from(response.bytes().to_vec());
+            return Ok(stream::once(async move { Ok(data) }).boxed());
+        }
+
+        let response = self
+            .bucket
+            .get_object_stream(path)
+            .await
+            .map_err(|e| storage_error("S3 download", e))?;
+        match response.status_code {
+            404 => Err(AppError::not_found("File")),
+            200..=299 => Ok(response
+                .bytes
+                .map(|chunk| chunk.map_err(|e| storage_error("S3 download", e)))
+                .boxed()),
+            status => Err(storage_error("S3 download", format!("status {}", status))),
+        }
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        self.bucket
+            .delete_object(path)
+            .await
+            .map_err(|e| storage_error("S3 delete", e))?;
+        Ok(())
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        // A single CopyObject handles sources up to 5 GiB, far above any image limit
+        let status = self
+            .bucket
+            .copy_object_internal(from, to)
+            .await
+            .map_err(|e| storage_error("S3 copy", e))?;
+        match status {
+            200..=299 => Ok(()),
+            404 => Err(AppError::not_found("File")),
+            status => Err(storage_error("S3 copy", format!("status {}", status))),
+        }
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        match self.bucket.head_object(path).await {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                let err_str = e.to_string();
+                if err_str.contains("404") || err_str.contains("NotFound") {
+                    Ok(false)
+                } else {
+                    Err(storage_error("S3 head", e))
+                }
+            }
+        }
+    }
+
+    async fn metadata(&self, path: &str) -> Result<ImageMetadata> {
+        let (head, _) = self
+            .bucket
+            .head_object(path)
+            .await
+            .map_err(|e| storage_error("S3 head", e))?;
+
+        Ok(ImageMetadata {
+            size: head.content_length.unwrap_or(0) as u64,
+            content_type: head.content_type,
+            last_modified: head.last_modified,
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        let results = self
+            .bucket
+            .list(prefix.to_string(), None)
+            .await
+            .map_err(|e| storage_error("S3 list", e))?;
+
+        Ok(results
+            .into_iter()
+            .flat_map(|result| result.contents)
+            .map(|object| object.key)
+            .collect())
+    }
+
+    async fn presign(&self, path: &str, expires_in_secs: u32) -> Result<String> {
+        self.bucket
+            .presign_get(path, expires_in_secs, None)
+            .await
+            .map_err(|e| AppError::internal(format!("Presigned URL failed: {}", e)))
+    }
+
+    async fn presign_put(&self, path: &str, content_type: &str, expires_in_secs: u32) -> Result<String> {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_str(content_type)
+                .map_err(|_| AppError::bad_request(format!("Invalid content type '{}'", content_type)))?,
+        );
+        self.bucket
+            .presign_put(path, expires_in_secs, Some(headers), None)
+            .await
+            .map_err(|e| AppError::internal(format!("Presigned URL failed: {}", e)))
+    }
+
+    fn public_url(&self, path: &str) -> String {
+        format!("{}/{}", self.public_url_base.trim_end_matches('/'), path)
+    }
+
+    async fn begin_upload(&self, content_type: &str) -> Result<Box<dyn BlobUpload>> {
+        Ok(Box::new(S3Upload {
+            bucket: self.bucket.clone(),
+            content_type: content_type.to_string(),
+            staging: format!("uploads/{}", Uuid::now_v7()),
+            upload_id: None,
+            parts: Vec::new(),
+            buffer: Vec::new(),
+        }))
+    }
+
+    async fn purge_staging(&self, before: DateTime<Utc>, dry_run: bool) -> Result<Vec<String>> {
+        let results = self
+            .bucket
+            .list_multiparts_uploads(Some("uploads/"), None)
+            .await
+            .map_err(|e| storage_error("S3 list uploads", e))?;
+
+        let mut purged = Vec::new();
+        for upload in results.into_iter().flat_map(|result| result.uploads) {
+            let initiated = DateTime::parse_from_rfc3339(&upload.initiated).map(|t| t.with_timezone(&Utc));
+            if !initiated.is_ok_and(|t| t < before) {
+                continue;
+            }
+            if !dry_run {
+                self.bucket
+                    .abort_upload(&upload.key, &upload.id)
+                    .await
+                    .map_err(|e| storage_error("S3 abort upload", e))?;
+            }
+            purged.push(upload.key);
+        }
+        Ok(purged)
+    }
+}
+
+/// Size of each S3 multipart part; S3 requires at least 5 MiB for all but the last
+const S3_PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// Streams parts to a multipart upload under `uploads/`, then copies the
+/// result to its key server-side. Uploads that fit in one part skip the
+/// multipart upload and go straight to their key.
+struct S3Upload {
+    bucket: Bucket,
+    content_type: String,
+    staging: String,
+    upload_id: Option<String>,
+    parts: Vec<Part>,
+    buffer: Vec<u8>,
+}
+
+impl S3Upload {
+    async fn flush_part(&mut self, chunk: Vec<u8>) -> Result<()> {
+        let upload_id = match &self.upload_id {
+            Some(upload_id) => upload_id.clone(),
+            None => {
+                let initiated = self
+                    .bucket
+                    .initiate_multipart_upload(&self.staging, &self.content_type)
+                    .await
+                    .map_err(|e| storage_error("S3 multipart start", e))?;
+                self.upload_id.insert(initiated.upload_id).clone()
+            }
+        };
+        let part_number = self.parts.len() as u32 + 1;
+        let part = self
+            .bucket
+            .put_multipart_chunk(chunk, &self.staging, part_number, &upload_id, &self.content_type)
+            .await
+            .map_err(|e| storage_error("S3 multipart part", e))?;
+        self.parts.push(part);
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobUpload for S3Upload {
+    async fn write(&mut self, chunk: Bytes) -> Result<()> {
+        self.buffer.extend_from_slice(&chunk);
+        while self.buffer.len() >= S3_PART_SIZE {
+            let rest = self.buffer.split_off(S3_PART_SIZE);
+            let part = std::mem::replace(&mut self.buffer, rest);
+            self.flush_part(part).await?;
+        }
+        Ok(())
+    }
+
+    async fn commit(mut self: Box<Self>, path: &str) -> Result<()> {
+        if self.upload_id.is_none() {
+            self.bucket
+                .put_object_with_content_type(path, &self.buffer, &self.content_type)
+                .await
+                .map_err(|e| storage_error("S3 upload", e))?;
+            return Ok(());
+        }
+
+        if !self.buffer.is_empty() {
+            let last = std::mem::take(&mut self.buffer);
+            self.flush_part(last).await?;
+        }
+        let upload_id = self.upload_id.clone().unwrap_or_default();
+        self.bucket
+            .complete_multipart_upload(&self.staging, &upload_id, std::mem::take(&mut self.parts))
+            .await
+            .map_err(|e| storage_error("S3 multipart complete", e))?;
+        self.bucket
+            .copy_object_internal(&self.staging, path)
+            .await
+            .map_err(|e| storage_error("S3 copy", e))?;
+        self.bucket
+            .delete_object(&self.staging)
+            .await
+            .map_err(|e| storage_error("S3 delete", e))?;
+        Ok(())
+    }
+
+    async fn abort(self: Box<Self>) -> Result<()> {
+        if let Some(upload_id) = &self.upload_id {
+            self.bucket
+                .abort_upload(&self.staging, upload_id)
+                .await
+                .map_err(|e| storage_error("S3 multipart abort", e))?;
+        }
+        Ok(())
+    }
+}
+
+// ============================================================================
+// Local directory
+// ============================================================================
+
+/// Maps a key to a file under `root`, rejecting anything that could escape it
+fn resolve_under(root: &Path, path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let safe = !path.is_empty()
+        && relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)));
+    if !safe {
+        return Err(AppError::bad_request(format!("Invalid storage path '{}'", path)));
+    }
+    Ok(root.join(relative))
+}
+
+/// Stores blobs under a local directory. Files are served by the `/files/`
+/// route, which only accepts URLs signed by this store.
+pub struct LocalStore {
+    root: PathBuf,
+    public_url_base: String,
+    secret: Vec<u8>,
+}
+
+impl LocalStore {
+    pub fn new(root: impl Into<PathBuf>, public_url_base: String, secret: Vec<u8>) -> Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        if secret.is_empty() {
+            return Err(AppError::internal("Local storage secret must not be empty"));
+        }
+        Ok(Self {
+            root,
+            public_url_base,
+            secret,
+        })
+    }
+
+    fn resolve(&self, path: &str) -> Result<PathBuf> {
+        resolve_under(&self.root, path)
+    }
+
+    fn mac(&self, method: &str, path: &str, expires: Option<i64>) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).expect("HMAC accepts any key length");
+        // GET signatures stay unprefixed so URLs already stored with nodes keep working
+        if method != "GET" {
+            mac.update(method.as_bytes());
+            mac.update(b"\n");
+        }
+        mac.update(path.as_bytes());
+        mac.update(b"\n");
+        mac.update(expires.map(|e| e.to_string()).unwrap_or_default().as_bytes());
+        mac
+    }
+
+    /// `/files/` URL for `path`, valid until `expires` (unix seconds) or forever
+    pub fn signed_url(&self, path: &str, expires: Option<i64>) -> String {
+        self.sign("GET", path, expires)
+    }
+
+    /// `/files/` URL accepting a `PUT` of `path` until `expires`
+    pub fn signed_upload_url(&self, path: &str, expires: i64) -> String {
+        self.sign("PUT", path, Some(expires))
+    }
+
+    fn sign(&self, method: &str, path: &str, expires: Option<i64>) -> String {
+        let signature = hex::encode(self.mac(method, path, expires).finalize().into_bytes());
+        let base = self.public_url_base.trim_end_matches('/');
+        match expires {
+            Some(expires) => format!("{}/files/{}?expires={}&signature={}", base, path, expires, signature),
+            None => format!("{}/files/{}?signature={}", base, path, signature),
+        }
+    }
+
+    /// Checks a signature produced by [`LocalStore::signed_url`]
+    pub fn verify(&self, path: &str, expires: Option<i64>, signature: &str) -> Result<()> {
+        self.check("GET", path, expires, signature)
+    }
+
+    /// Checks a signature produced by [`LocalStore::signed_upload_url`]
+    pub fn verify_upload(&self, path: &str, expires: Option<i64>, signature: &str) -> Result<()> {
+        if expires.is_none() {
+            return Err(AppError::forbidden("Upload URL has no expiry"));
+        }
+        self.check("PUT", path, expires, signature)
+    }
+
+    fn check(&self, method: &str, path: &str, expires: Option<i64>, signature: &str) -> Result<()> {
+        let signature = hex::decode(signature).map_err(|_| AppError::forbidden("Invalid signature"))?;
+        self.mac(method, path, expires)
+            .verify_slice(&signature)
+            .map_err(|_| AppError::forbidden("Invalid signature"))?;
+        if expires.is_some_and(|e| e < chrono::Utc::now().timestamp()) {
+            return Err(AppError::forbidden("URL has expired"));
+        }
+        Ok(())
+    }
+
+    fn collect(&self, dir: &Path, prefix: &str, keys: &mut Vec<String>) -> Result<()> {
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let path = entry?.path();
+            if path.is_dir() {
+                self.collect(&path, prefix, keys)?;
+            } else if let Ok(relative) = path.strip_prefix(&self.root) {
+                let key = relative
+                    .components()
+                    .map(|c| c.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                if key.starts_with(prefix) && !key.contains(".tmp-") {
+                    keys.push(key);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BlobStore for LocalStore {
+    async fn upload(&self, path: &str, data: Bytes, _content_type: &str) -> Result<()> {
+        let target = self.resolve(path)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write to a sibling temp file first so readers never see partial blobs
+        let temp = target.with_extension(format!("tmp-{}", Uuid::now_v7()));
+        tokio::fs::write(&temp, &data).await?;
+        tokio::fs::rename(&temp, &target).await?;
+        Ok(())
+    }
+
+    async fn download(&self, path: &str) -> Result<Bytes> {
+        match tokio::fs::read(self.resolve(path)?).await {
+            Ok(data) => Ok(Bytes::from(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(AppError::not_found("File")),
+            Err(e) => Err(storage_error("Local read", e)),
+        }
+    }
+
+    async fn download_stream(&self, path: &str, range: Option<(u64, u64)>) -> Result<BlobStream> {
+        let mut file = match tokio::fs::File::open(self.resolve(path)?).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(AppError::not_found("File")),
+            Err(e) => return Err(storage_error("Local read", e)),
+        };
+
+        let reader: Box<dyn AsyncRead + Send + Unpin> = match range {
+            Some((start, end)) => {
+                file.seek(std::io::SeekFrom::Start(start)).await?;
+                Box::new(file.take(end - start + 1))
+            }
+            None => Box::new(file),
+        };
+        Ok(ReaderStream::new(reader).map(|chunk| chunk.map_err(AppError::from)).boxed())
+    }
+
+    async fn delete(&self, path: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.resolve(path)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(storage_error("Local delete", e)),
+        }
+    }
+
+    async fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let source = self.resolve(from)?;
+        let target = self.resolve(to)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        match tokio::fs::copy(source, target).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(AppError::not_found("File")),
+            Err(e) => Err(storage_error("Local copy", e)),
+        }
+    }
+
+    async fn exists(&self, path: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.resolve(path)?).await?)
+    }
+
+    async fn metadata(&self, path: &str) -> Result<ImageMetadata> {
+        let metadata = match tokio::fs::metadata(self.resolve(path)?).await {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Err(AppError::not_found("File")),
+            Err(e) => return Err(storage_error("Local stat", e)),
+        };
+
+        Ok(ImageMetadata {
+            size: metadata.len(),
+            content_type: Some(mime_guess::from_path(path).first_or_octet_stream().to_string()),
+            last_modified: metadata
+                .modified()
+                .ok()
+                .map(|t| chrono::DateTime::<chrono::Utc>::from(t).to_rfc3339()),
+        })
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+        // Start from the deepest directory fully named by the prefix
+        let dir = match prefix.rfind('/') {
+            Some(end) => self.resolve(&prefix[..end])?,
+            None => self.root.clone(),
+        };
+        let mut keys = Vec::new();
+        self.collect(&dir, prefix, &mut keys)?;
+        keys.sort();
+        Ok(keys)
+    }
+
+    async fn presign(&self, path: &str, expires_in_secs: u32) -> Result<String> {
+        self.resolve(path)?;
+        let expires = chrono::Utc::now().timestamp() + i64::from(expires_in_secs);
+        Ok(self.signed_url(path, Some(expires)))
+    }
+
+    async fn presign_put(&self, path: &str, _content_type: &str, expires_in_secs: u32) -> Result<String> {
+        self.resolve(path)?;
+        let expires = chrono::Utc::now().timestamp() + i64::from(expires_in_secs);
+        Ok(self.signed_upload_url(path, expires))
+    }
+
+    fn public_url(&self, path: &str) -> String {
+        self.signed_url(path, None)
+    }
+
+    async fn begin_upload(&self, _content_type: &str) -> Result<Box<dyn BlobUpload>> {
+        let temp = self.root.join(format!(".tmp-{}", Uuid::now_v7()));
+        let file = tokio::fs::File::create(&temp).await?;
+        Ok(Box::new(LocalUpload {
+            target_root: self.root.clone(),
+            temp,
+            file,
+        }))
+    }
+
+    async fn purge_staging(&self, before: DateTime<Utc>, dry_run: bool) -> Result<Vec<String>> {
+        let mut entries = tokio::fs::read_dir(&self.root).await?;
+        let mut purged = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(".tmp-") {
+                continue;
+            }
+            let modified = entry.metadata().await?.modified()?;
+            if DateTime::<Utc>::from(modified) >= before {
+                continue;
+            }
+            if !dry_run {
+                tokio::fs::remove_file(entry.path()).await?;
+            }
+            purged.push(name);
+        }
+        Ok(purged)
+    }
+}
+
+/// Streams into a temp file under the root, renamed into place on commit
+struct LocalUpload {
+    target_root: PathBuf,
+    temp: PathBuf,
+    file: tokio::fs::File,
+}
+
+#[async_trait]
+impl BlobUpload for LocalUpload {
+    async fn write(&mut self, chunk: Bytes) -> Result<()> {
+        self.file
+            .write_all(&chunk)
+            .await
+            .map_err(|e| storage_error("Local write", e))
+    }
+
+    async fn commit(mut self: Box<Self>, path: &str) -> Result<()> {
+        self.file.sync_all().await?;
+        let target = resolve_under(&self.target_root, path)?;
+        if let Some(parent) = target.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        tokio::fs::rename(&self.temp, &target)
+            .await
+            .map_err(|e| storage_error("Local commit", e))
+    }
+
+    async fn abort(self: Box<Self>) -> Result<()> {
+        drop(self.file);
+        match tokio::fs::remove_file(&self.temp).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(storage_error("Local delete", e)),
+        }
+    }
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_store() -> LocalStore {
+        let root = std::env::temp_dir().join(format!("cx58-blobs-{}", Uuid::now_v7()));
+        LocalStore::new(root, "http://localhost:3000".to_string(), b"secret".to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_local_round_trip() {
+        let store = temp_store();
+        let data = Bytes::from_static(b"image bytes");
+
+        store.upload("images/u/n/abc.jpg", data.clone(), "image/jpeg").await.unwrap();
+        store.upload("images/u/m/def.png", data.clone(), "image/png").await.unwrap();
+
+        assert!(store.exists("images/u/n/abc.jpg").await.unwrap());
+        assert_eq!(store.download("images/u/n/abc.jpg").await.unwrap(), data);
+        let range: Vec<Bytes> = store
+            .download_stream("images/u/n/abc.jpg", Some((6, 9)))
+            .await
+            .unwrap()
+            .map(|chunk| chunk.unwrap())
+            .collect()
+            .await;
+        assert_eq!(range.concat(), b"byte");
+        store.copy("images/u/n/abc.jpg", "images/u/copy/abc.jpg").await.unwrap();
+        assert_eq!(store.download("images/u/copy/abc.jpg").await.unwrap(), data);
+        assert_eq!(store.copy("images/u/x.jpg", "images/u/y.jpg").await.unwrap_err().code, ErrorCode::NotFound);
+        assert_eq!(store.metadata("images/u/m/def.png").await.unwrap().content_type.as_deref(), Some("image/png"));
+        assert_eq!(
+            store.list("images/u/").await.unwrap(),
+            vec!["images/u/copy/abc.jpg", "images/u/m/def.png", "images/u/n/abc.jpg"]
+        );
+
+        store.delete("images/u/n/abc.jpg").await.unwrap();
+        assert!(!store.exists("images/u/n/abc.jpg").await.unwrap());
+        assert_eq!(store.download("images/u/n/abc.jpg").await.unwrap_err().code, ErrorCode::NotFound);
+
+        std::fs::remove_dir_all(&store.root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_streamed_upload() {
+        let store = temp_store();
+
+        let mut upload = store.begin_upload("image/jpeg").await.unwrap();
+        upload.write(Bytes::from_static(b"image ")).await.unwrap();
+        upload.write(Bytes::from_static(b"bytes")).await.unwrap();
+        upload.commit("blobs/ab/abc.jpg").await.unwrap();
+        assert_eq!(store.download("blobs/ab/abc.jpg").await.unwrap(), Bytes::from_static(b"image bytes"));
+
+        let mut aborted = store.begin_upload("image/jpeg").await.unwrap();
+        aborted.write(Bytes::from_static(b"partial")).await.unwrap();
+        aborted.abort().await.unwrap();
+
+        let leftovers = std::fs::read_dir(&store.root).unwrap().count();
+        assert_eq!(leftovers, 1, "only the blobs directory should remain");
+        assert_eq!(store.list("").await.unwrap(), vec!["blobs/ab/abc.jpg"]);
+
+        let mut dropped = store.begin_upload("image/jpeg").await.unwrap();
+        dropped.write(Bytes::from_static(b"partial")).await.unwrap();
+        drop(dropped);
+        let an_hour_ago = Utc::now() - chrono::Duration::hours(1);
+        assert!(store.purge_staging(an_hour_ago, false).await.unwrap().is_empty());
+        let later = Utc::now() + chrono::Duration::seconds(1);
+        assert_eq!(store.purge_staging(later, true).await.unwrap().len(), 1);
+        assert_eq!(store.purge_staging(later, false).await.unwrap().len(), 1);
+        assert_eq!(std::fs::read_dir(&store.root).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(&store.root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_local_rejects_escaping_paths() {
+        let store = temp_store();
+        for path in ["../etc/passwd", "/etc/passwd", "images/../../x", ""] {
+            assert!(store.download(path).await.is_err(), "{}", path);
+        }
+        std::fs::remove_dir_all(&store.root).unwrap();
+    }
+
+    #[test]
+    fn test_signed_urls() {
+        let store = temp_store();
+        let url = store.public_url("images/a.jpg");
+        let signature = url.split("signature=").nth(1).unwrap();
+        assert!(url.starts_with("http://localhost:3000/files/images/a.jpg?"));
+        assert!(store.verify("images/a.jpg", None, signature).is_ok());
+        assert!(store.verify("images/b.jpg", None, signature).is_err());
+
+        let past = chrono::Utc::now().timestamp() - 1;
+        let expired = store.signed_url("images/a.jpg", Some(past));
+        let signature = expired.split("signature=").nth(1).unwrap();
+        assert_eq!(store.verify("images/a.jpg", Some(past), signature).unwrap_err().message, "URL has expired");
+        assert!(store.verify("images/a.jpg", Some(past + 3600), signature).is_err());
+
+        let future = chrono::Utc::now().timestamp() + 60;
+        let upload = store.signed_upload_url("uploads/x.jpg", future);
+        let signature = upload.split("signature=").nth(1).unwrap();
+        assert!(store.verify_upload("uploads/x.jpg", Some(future), signature).is_ok());
+        assert!(store.verify("uploads/x.jpg", Some(future), signature).is_err());
+
+        std::fs::remove_dir_all(&store.root).unwrap();
+    }
+}
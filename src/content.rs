@@ -0,0 +1,333 @@
+use std::sync::Arc;
+use axum::{
+    Json,
+    extract::{Path, Query, State},
+    body::Body,
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Redirect, Response},
+};
+use futures::StreamExt;
+use serde::Deserialize;
+use uuid::Uuid;
+use crate::error::*;
+use crate::handlers::CurrentUser;
+use crate::storage::*;
+use crate::blob_store::BlobStore;
+use crate::{annotations, tree};
+
+// ============================================================================
+// Image Content
+// ============================================================================
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ImageQuery {
+    /// Rendition name, or `original`; redirects to that image instead of
+    /// returning the node data
+    pub size: Option<String>,
+}
+
+pub async fn get_image_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+    Query(query): Query<ImageQuery>,
+) -> Result<Response> {
+    tree::require_access(&state.db, Some(&user_id.to_string()), &node_id).await?;
+
+    let node = sqlx::query!(
+        r#"SELECT data FROM tree_nodes WHERE id = $1 AND node_type = 'ImageLeaf'"#,
+        node_id
+    )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or_else(|| AppError::not_found("Image"))?;
+
+    if query.size.is_none() {
+        let mut data = node.data;
+        let annotations = annotations::list(&state.db, Some(&user_id.to_string()), &node_id).await?;
+        if !annotations.is_empty() {
+            data["annotations"] = serde_json::to_value(annotations)?;
+        }
+        return Ok(Json(data).into_response());
+    }
+
+    let variant = image_variant(&state, &node.data, query.size.as_deref())?;
+    Ok(Redirect::temporary(variant["url"].as_str().unwrap_or_default()).into_response())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// Inclusive start and end
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a `Range` header for a blob of `len` bytes. Only single ranges
+/// are served; multiple or malformed ranges get the whole blob, as RFC 9110
+/// allows.
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return ByteRange::Full;
+    };
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+
+    let (start, end) = match (start.parse::<u64>(), end) {
+        // `-n`: the last n bytes
+        (Err(_), _) if start.is_empty() => match end.parse::<u64>() {
+            Ok(0) => return ByteRange::Unsatisfiable,
+            Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+            Err(_) => return ByteRange::Full,
+        },
+        (Err(_), _) => return ByteRange::Full,
+        // `a-`: from a to the end
+        (Ok(start), "") => (start, len.saturating_sub(1)),
+        (Ok(start), end) => match end.parse::<u64>() {
+            Ok(end) if end >= start => (start, end.min(len.saturating_sub(1))),
+            _ => return ByteRange::Full,
+        },
+    };
+
+    if len == 0 || start >= len {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial(start, end)
+    }
+}
+
+/// Whether an `If-None-Match` header matches `etag`, compared weakly
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    if_none_match
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+/// Serves the bytes of an image, or one of its renditions with `?size=`.
+/// Supports single byte ranges and `If-None-Match` against the content
+/// hash. With `IMAGE_CONTENT_REDIRECT` set, redirects to a short-lived
+/// signed URL instead.
+pub async fn get_image_content_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+    Query(query): Query<ImageQuery>,
+    headers: HeaderMap,
+) -> Result<Response> {
+    tree::require_access(&state.db, Some(&user_id.to_string()), &node_id).await?;
+
+    let node = sqlx::query!(
+        r#"SELECT data FROM tree_nodes WHERE id = $1 AND node_type = 'ImageLeaf'"#,
+        node_id
+    )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or_else(|| AppError::not_found("Image"))?;
+
+    let variant = image_variant(&state, &node.data, query.size.as_deref())?;
+    let storage_path = variant["storage_path"]
+        .as_str()
+        .ok_or_else(|| AppError::not_found("Image content"))?;
+
+    let config = &state.upload_config;
+    if config.content_redirect {
+        let url = state
+            .storage
+            .generate_presigned_url(storage_path, config.content_url_expiry_secs)
+            .await?;
+        return Ok(Redirect::temporary(&url).into_response());
+    }
+
+    // Content is addressed by hash, so the hash is a strong validator
+    let etag = node.data["hash"].as_str().map(|hash| match variant.get("max_size") {
+        Some(_) => format!("\"{}-{}\"", hash, query.size.as_deref().unwrap_or_default()),
+        None => format!("\"{}\"", hash),
+    });
+    let cache_control = format!("private, max-age={}", config.content_cache_secs);
+
+    let mut response = Response::builder()
+        .header(header::CACHE_CONTROL, &cache_control)
+        .header(header::ACCEPT_RANGES, "bytes");
+    if let Some(etag) = &etag {
+        response = response.header(header::ETAG, etag);
+        let not_modified = headers
+            .get(header::IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| etag_matches(value, etag));
+        if not_modified {
+            return response
+                .status(StatusCode::NOT_MODIFIED)
+                .body(Body::empty())
+                .map_err(|e| AppError::internal(e.to_string()));
+        }
+    }
+
+    let len = match variant["size"].as_u64() {
+        Some(len) => len,
+        None => state.storage.get_metadata(storage_path).await?.size,
+    };
+    let range = headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .map_or(ByteRange::Full, |value| parse_range(value, len));
+
+    let mime_type = variant["mime_type"].as_str().unwrap_or("application/octet-stream");
+    response = response.header(header::CONTENT_TYPE, mime_type);
+    let response = match range {
+        ByteRange::Full => response
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, len)
+            .body(Body::from_stream(state.storage.stream_image(storage_path, None).await?)),
+        ByteRange::Partial(start, end) => response
+            .status(StatusCode::PARTIAL_CONTENT)
+            .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len))
+            .header(header::CONTENT_LENGTH, end - start + 1)
+            .body(Body::from_stream(state.storage.stream_image(storage_path, Some((start, end))).await?)),
+        ByteRange::Unsatisfiable => response
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+            .body(Body::empty()),
+    };
+    response.map_err(|e| AppError::internal(e.to_string()))
+}
+
+// ============================================================================
+// Local Files
+// ============================================================================
+
+#[derive(Debug, Deserialize)]
+pub struct FileQuery {
+    pub expires: Option<i64>,
+    pub signature: String,
+}
+
+/// Serves blobs of the local storage backend
+///
+/// GET /files/{*path}?expires=...&signature=...
+pub async fn local_file_handler(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<FileQuery>,
+) -> Result<Response> {
+    let store = state
+        .storage
+        .local_store()
+        .ok_or_else(|| AppError::not_found("File"))?;
+    store.verify(&path, query.expires, &query.signature)?;
+
+    let data = store.download(&path).await?;
+    let content_type = mime_guess::from_path(&path).first_or_octet_stream().to_string();
+
+    Ok(([(header::CONTENT_TYPE, content_type)], data).into_response())
+}
+
+/// Accepts direct uploads for the local storage backend
+///
+/// PUT /files/{*path}?expires=...&signature=...
+pub async fn local_upload_handler(
+    State(state): State<Arc<AppState>>,
+    Path(path): Path<String>,
+    Query(query): Query<FileQuery>,
+    body: axum::body::Body,
+) -> Result<StatusCode> {
+    let store = state
+        .storage
+        .local_store()
+        .ok_or_else(|| AppError::not_found("File"))?;
+    store.verify_upload(&path, query.expires, &query.signature)?;
+
+    let max = state.upload_config.max_image_bytes;
+    let mut upload = store.begin_upload("application/octet-stream").await?;
+    let mut stream = body.into_data_stream();
+    let mut size = 0u64;
+    while let Some(chunk) = stream.next().await {
+        let written = match chunk {
+            Ok(chunk) if size + chunk.len() as u64 <= max => {
+                size += chunk.len() as u64;
+                upload.write(chunk).await
+            }
+            Ok(_) => Err(AppError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Image too large (max {} bytes)", max),
+            )),
+            Err(e) => Err(AppError::bad_request(format!("Read: {}", e))),
+        };
+        if let Err(e) = written {
+            upload.abort().await?;
+            return Err(e);
+        }
+    }
+    upload.commit(&path).await?;
+
+    Ok(StatusCode::OK)
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_local_direct_upload() {
+        let mut state = (*crate::init::test_state(AiConfig::default())).clone();
+        state.upload_config.max_image_bytes = 16;
+        let state = Arc::new(state);
+        let store = state.storage.local_store().unwrap();
+        let expires = chrono::Utc::now().timestamp() + 60;
+        let url = store.signed_upload_url("uploads/a.png", expires);
+        let query = || FileQuery {
+            expires: Some(expires),
+            signature: url.split("signature=").nth(1).unwrap().to_string(),
+        };
+        let put = |body: &'static [u8]| {
+            local_upload_handler(
+                State(state.clone()),
+                Path("uploads/a.png".to_string()),
+                Query(query()),
+                axum::body::Body::from(body),
+            )
+        };
+
+        assert_eq!(put(b"small").await.unwrap(), StatusCode::OK);
+        assert_eq!(store.download("uploads/a.png").await.unwrap(), Bytes::from_static(b"small"));
+        assert_eq!(put(b"more than sixteen bytes").await.unwrap_err().code, ErrorCode::PayloadTooLarge);
+
+        let err = local_file_handler(State(state.clone()), Path("uploads/a.png".to_string()), Query(query()))
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, ErrorCode::Forbidden);
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+        assert_eq!(parse_range("bytes=900-", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_range("bytes=500-5000", 1000), ByteRange::Partial(500, 999));
+        assert_eq!(parse_range("bytes=-5000", 1000), ByteRange::Partial(0, 999));
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), ByteRange::Full);
+        assert_eq!(parse_range("bytes=9-1", 1000), ByteRange::Full);
+        assert_eq!(parse_range("items=0-1", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"abc\"", "\"abc\""));
+        assert!(etag_matches("\"x\", W/\"abc\"", "\"abc\""));
+        assert!(etag_matches("*", "\"abc\""));
+        assert!(!etag_matches("\"abcd\"", "\"abc\""));
+    }
+}
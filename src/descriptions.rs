@@ -0,0 +1,414 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+use uuid::Uuid;
+use crate::agents::tools::{marked_area_input, vision_input, vision_prompt};
+use crate::agents::{structured_call, structured_call_model, AiClient, ImageDescriptionOutput, StreamEvent};
+use crate::error::{AppError, ErrorCode, Result};
+use crate::models::{AnnotationStatus, NodeSummary};
+use crate::{annotations, AiConfig, AiProvider, AiRole, AppState};
+
+// ============================================================================
+// Prompts
+// ============================================================================
+
+/// Prompt for describing a whole photo, `{language}` filled in. Part of the
+/// cache key, so rewording it retires the descriptions made with it.
+pub const DESCRIBE_TEMPLATE: &str = "Describe this photo in {language} language.";
+
+pub const DESCRIBE_PREAMBLE: &str = "You are an inspection assistant describing construction site photos.";
+
+// ============================================================================
+// Model versions
+// ============================================================================
+
+/// How long listed model versions are reused, so describing a branch
+/// asks Ollama once rather than once per image
+const VERSIONS_TTL: Duration = Duration::from_secs(60);
+
+type Versions = Option<HashMap<String, String>>;
+
+/// Versions last listed per Ollama URL, with when they were listed
+static LISTED: LazyLock<Mutex<HashMap<String, (Instant, Versions)>>> = LazyLock::new(Default::default);
+
+/// Digests of the installed models by `name:tag`, or `None` when they
+/// can't be told, e.g. with the mock provider or Ollama unreachable
+pub async fn model_versions(config: &AiConfig) -> Versions {
+    if !matches!(config.provider, AiProvider::Ollama) {
+        return None;
+    }
+    if let Some((listed_at, versions)) = LISTED.lock().unwrap().get(&config.url)
+        && listed_at.elapsed() < VERSIONS_TTL
+    {
+        return versions.clone();
+    }
+
+    let versions = list_versions(config).await;
+    LISTED
+        .lock()
+        .unwrap()
+        .insert(config.url.clone(), (Instant::now(), versions.clone()));
+    versions
+}
+
+async fn list_versions(config: &AiConfig) -> Versions {
+    let client = reqwest::Client::builder()
+        .timeout(config.request_timeout)
+        .build()
+        .ok()?;
+    let url = format!("{}/api/tags", config.url.trim_end_matches('/'));
+    let tags: serde_json::Value = match client.get(&url).send().await.and_then(|r| r.error_for_status()) {
+        Ok(response) => response.json().await.ok()?,
+        Err(e) => {
+            log::warn!("Listing models at {} failed: {}", url, e);
+            return None;
+        }
+    };
+
+    let versions = tags["models"]
+        .as_array()?
+        .iter()
+        .filter_map(|model| {
+            let name = model["name"].as_str()?;
+            let digest = model["digest"].as_str()?;
+            Some((tagged(name), digest.to_string()))
+        })
+        .collect();
+    Some(versions)
+}
+
+/// Ollama's name for a model, `llava` being `llava:latest`
+fn tagged(model: &str) -> String {
+    if model.contains(':') {
+        model.to_string()
+    } else {
+        format!("{}:latest", model)
+    }
+}
+
+fn version_of(versions: Option<&HashMap<String, String>>, model: &str) -> String {
+    versions
+        .and_then(|versions| versions.get(&tagged(model)))
+        .cloned()
+        .unwrap_or_default()
+}
+
+/// Whether a description made with `version` of `model` still holds. With
+/// the installed versions unknown, stored descriptions are trusted.
+fn is_current(versions: Option<&HashMap<String, String>>, model: &str, version: &str) -> bool {
+    match versions {
+        Some(versions) => versions.get(&tagged(model)).is_some_and(|current| current == version),
+        None => true,
+    }
+}
+
+// ============================================================================
+// Cache
+// ============================================================================
+
+/// A stored description of the image with `hash`, made by the first of the
+/// configured vision models that still has the version it was made with
+pub async fn cached(
+    db: &sqlx::PgPool,
+    config: &AiConfig,
+    versions: Option<&HashMap<String, String>>,
+    hash: &str,
+    template: &str,
+    language: &str,
+) -> Result<Option<ImageDescriptionOutput>> {
+    let models = config.models(AiRole::Vision);
+    let rows = sqlx::query!(
+        r#"
+        SELECT model_name, model_version, output AS "output!"
+        FROM image_descriptions
+        WHERE image_hash = $1 AND prompt_template = $2 AND language = $3
+          AND model_name = ANY($4) AND output IS NOT NULL
+        "#,
+        hash,
+        template,
+        language,
+        models
+    )
+    .fetch_all(db)
+    .await?;
+
+    for model in models {
+        let Some(row) = rows.iter().find(|row| &row.model_name == model) else {
+            continue;
+        };
+        if !is_current(versions, model, &row.model_version) {
+            continue;
+        }
+        if let Ok(output) = serde_json::from_value(row.output.clone()) {
+            return Ok(Some(output));
+        }
+    }
+    Ok(None)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn store(
+    db: &sqlx::PgPool,
+    node_id: &Uuid,
+    hash: &str,
+    model: &str,
+    version: &str,
+    prompt: &str,
+    template: &str,
+    language: &str,
+    output: &ImageDescriptionOutput,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        INSERT INTO image_descriptions
+            (node_id, image_hash, model_name, model_version, prompt, prompt_template, language,
+             description, confidence, output)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10)
+        ON CONFLICT (image_hash, model_name, prompt_template, language) WHERE image_hash IS NOT NULL
+        DO UPDATE SET node_id = EXCLUDED.node_id,
+                      model_version = EXCLUDED.model_version,
+                      prompt = EXCLUDED.prompt,
+                      description = EXCLUDED.description,
+                      confidence = EXCLUDED.confidence,
+                      output = EXCLUDED.output,
+                      created_at = now()
+        "#,
+        node_id,
+        hash,
+        model,
+        version,
+        prompt,
+        template,
+        language,
+        output.overview,
+        output.confidence,
+        serde_json::to_value(output)?
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+// ============================================================================
+// Describing
+// ============================================================================
+
+/// Describes an image leaf in `language`, reusing a stored description of
+/// the same content when the model that made it is unchanged. The overview
+/// becomes the node's `description`; when the node is `user`'s own, it is
+/// then also searched in `language`. Access to `node` must already have
+/// been checked.
+#[allow(clippy::too_many_arguments)]
+pub async fn describe_image(
+    state: &AppState,
+    client: &AiClient,
+    request_id: &str,
+    event_tx: &mpsc::Sender<StreamEvent>,
+    user: Option<&str>,
+    node: &NodeSummary,
+    language: &str,
+) -> Result<ImageDescriptionOutput> {
+    let hash = node.data["hash"].as_str();
+    let versions = model_versions(&state.ai_config).await;
+    if let Some(hash) = hash
+        && let Some(output) =
+            cached(&state.db, &state.ai_config, versions.as_ref(), hash, DESCRIBE_TEMPLATE, language).await?
+    {
+        set_description(&state.db, user, &node.id, &output.overview, language).await?;
+        return Ok(output);
+    }
+
+    let text = DESCRIBE_TEMPLATE.replace("{language}", language);
+    let image = vision_input(state, node).await?;
+    let (output, model): (ImageDescriptionOutput, String) = structured_call_model(
+        client,
+        &state.ai_config,
+        AiRole::Vision,
+        request_id,
+        event_tx,
+        DESCRIBE_PREAMBLE,
+        vision_prompt(text.clone(), vec![image]),
+    )
+    .await
+    .map_err(|e| AppError::new(ErrorCode::ModelError, e.message))?;
+
+    if let Some(hash) = hash {
+        let version = version_of(versions.as_ref(), &model);
+        store(&state.db, &node.id, hash, &model, &version, &text, DESCRIBE_TEMPLATE, language, &output).await?;
+    }
+    set_description(&state.db, user, &node.id, &output.overview, language).await?;
+
+    Ok(output)
+}
+
+/// Describes only the area of image `node_id` marked by `annotation_id`,
+/// or by its latest open annotation, with some surroundings. Crops aren't
+/// cached; the same image rarely gets asked about twice by area.
+#[allow(clippy::too_many_arguments)]
+pub async fn describe_marked_area(
+    state: &AppState,
+    client: &AiClient,
+    request_id: &str,
+    event_tx: &mpsc::Sender<StreamEvent>,
+    user: Option<&str>,
+    node_id: &Uuid,
+    annotation_id: Option<&Uuid>,
+    language: &str,
+) -> Result<ImageDescriptionOutput> {
+    let annotation = match annotation_id {
+        Some(annotation_id) => annotations::get(&state.db, user, node_id, annotation_id).await?,
+        None => annotations::list(&state.db, user, node_id)
+            .await?
+            .into_iter()
+            .rfind(|annotation| annotation.status == AnnotationStatus::Open)
+            .ok_or_else(|| AppError::not_found("Marked area"))?,
+    };
+
+    let image = marked_area_input(state, user, &annotation).await?;
+    let note = annotation.note.map(|note| format!(" ({})", note)).unwrap_or_default();
+    let text = format!(
+        "This is the area of a photo marked as \"{}\"{}. Describe it in {} language.",
+        annotation.label, note, language
+    );
+    structured_call(
+        client,
+        &state.ai_config,
+        AiRole::Vision,
+        request_id,
+        event_tx,
+        DESCRIBE_PREAMBLE,
+        vision_prompt(text, vec![image]),
+    )
+    .await
+    .map_err(|e| AppError::new(ErrorCode::ModelError, e.message))
+}
+
+/// Other leaves showing the same content, possibly in other users' trees,
+/// keep their own description and language
+async fn set_description(
+    db: &sqlx::PgPool,
+    user: Option<&str>,
+    node_id: &Uuid,
+    overview: &str,
+    language: &str,
+) -> Result<()> {
+    sqlx::query!(
+        r#"
+        UPDATE tree_nodes
+        SET data = jsonb_set(data, '{description}', to_jsonb($2::text)),
+            language = CASE WHEN user_id::text = $4 THEN $3 ELSE language END
+        WHERE id = $1
+        "#,
+        node_id,
+        overview,
+        language,
+        user
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::NodeType;
+
+    #[test]
+    fn test_descriptions_follow_model_version() {
+        let versions = HashMap::from([("llava:latest".to_string(), "sha256:new".to_string())]);
+        assert!(is_current(Some(&versions), "llava", "sha256:new"));
+        assert!(is_current(Some(&versions), "llava:latest", "sha256:new"));
+        assert!(!is_current(Some(&versions), "llava", "sha256:old"));
+        // Uninstalled models can't vouch for their descriptions
+        assert!(!is_current(Some(&versions), "llama3.2-vision", "sha256:new"));
+        assert!(is_current(None, "llava", "sha256:old"));
+        assert_eq!(version_of(Some(&versions), "llava"), "sha256:new");
+        assert_eq!(version_of(None, "llava"), "");
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_cached_description_is_written_to_the_node_only() {
+        let mock = std::sync::Arc::new(crate::agents::MockLlm::new());
+        let state = crate::init::test_db_state(crate::init::test_config(mock)).await;
+        let client = crate::agents::build_client(&state.ai_config).unwrap();
+        let (owner, other) = (Uuid::now_v7(), Uuid::now_v7());
+        let hash = format!("test-{}", Uuid::now_v7());
+        let mut leaves = Vec::new();
+        for user_id in [owner, other] {
+            let root_id = crate::init::test_root(&state.db, &user_id).await;
+            let leaf = sqlx::query!(
+                r#"
+                INSERT INTO tree_nodes (user_id, parent_id, node_type, data, language)
+                VALUES ($1, $2, 'ImageLeaf', jsonb_build_object('url', 'x', 'hash', $3::text), 'en')
+                RETURNING id, data
+                "#,
+                user_id,
+                root_id,
+                hash
+            )
+            .fetch_one(&state.db)
+            .await
+            .unwrap();
+            let node = NodeSummary {
+                id: leaf.id,
+                parent_id: Some(root_id),
+                name: None,
+                node_type: NodeType::ImageLeaf,
+                data: leaf.data,
+                created_at: String::new(),
+            };
+            leaves.push((root_id, node));
+        }
+        let output = ImageDescriptionOutput {
+            subject: "Hallway".to_string(),
+            overview: "Trockenbau im Flur".to_string(),
+            sections: Vec::new(),
+            confidence: Some(0.9),
+        };
+        store(&state.db, &leaves[0].1.id, &hash, "llava", "", "p", DESCRIBE_TEMPLATE, "de", &output)
+            .await
+            .unwrap();
+
+        let (tx, _rx) = mpsc::channel(16);
+        let owner_id = owner.to_string();
+        let described = describe_image(&state, &client, "test", &tx, Some(&owner_id), &leaves[0].1, "de")
+            .await
+            .unwrap();
+        assert_eq!(described.overview, output.overview);
+
+        let rows = sqlx::query!(
+            "SELECT user_id, data->>'description' AS description, language FROM tree_nodes WHERE data->>'hash' = $1",
+            hash
+        )
+        .fetch_all(&state.db)
+        .await
+        .unwrap();
+        for row in rows {
+            if row.user_id == Some(owner) {
+                assert_eq!(row.description.as_deref(), Some("Trockenbau im Flur"));
+                assert_eq!(row.language.as_deref(), Some("de"));
+            } else {
+                assert_eq!(row.description, None);
+                assert_eq!(row.language.as_deref(), Some("en"));
+            }
+        }
+
+        for (root_id, _) in leaves {
+            sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+        sqlx::query!("DELETE FROM image_descriptions WHERE image_hash = $1", hash)
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+}
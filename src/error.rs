@@ -86,6 +86,7 @@ impl std::error::Error for AppError {}
 // ============================================================================
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ErrorCode {
     // Client errors (4xx)
     BadRequest,
@@ -99,6 +100,7 @@ pub enum ErrorCode {
     UnsupportedMediaType,
 
     // Server errors (5xx)
+    #[serde(rename = "INTERNAL_ERROR")]
     Internal,
     ServiceUnavailable,
     DatabaseError,
@@ -2,13 +2,15 @@ use std::convert::Infallible;
 // ============================================================================
 // Middleware
 // ============================================================================
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
 use axum::http::StatusCode;
 use axum::middleware::Next;
 use axum::response::{Response, Sse};
 use crate::models::*;
 use crate::error::*;
 use axum::{
-    extract::{Path, Request, State},
+    extract::{Path, Query, Request, State},
     Json,
 };
 use std::sync::Arc;
@@ -18,7 +20,7 @@ use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 pub use crate::storage::{StorageService, ImageProcessor, ImageUrlResolver};
-use crate::AppState;
+use crate::{annotations, jobs, search, tree, AiProvider, AppState};
 use crate::AgentRequest;
 use crate::agents::StreamEvent;
 
@@ -59,6 +61,33 @@ pub async fn auth_middleware(mut request: Request, next: Next) -> std::result::R
     Ok(next.run(request).await)
 }
 
+/// The user identified by [`auth_middleware`]. Rejects requests without a
+/// valid `X-User-ID` header.
+pub struct CurrentUser(pub Uuid);
+
+impl<S: Send + Sync> FromRequestParts<S> for CurrentUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self> {
+        match parts.extensions.get::<std::result::Result<Uuid, StatusCode>>() {
+            Some(Ok(user_id)) => Ok(Self(*user_id)),
+            _ => Err(AppError::unauthorized("Missing or invalid X-User-ID")),
+        }
+    }
+}
+
+/// The `X-Language` of the request, `en` when not given
+pub struct RequestLanguage(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for RequestLanguage {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> std::result::Result<Self, Infallible> {
+        let language = parts.extensions.get::<String>().cloned();
+        Ok(Self(language.unwrap_or_else(|| "en".to_string())))
+    }
+}
+
 pub async fn get_tree_handler(
     State(state): State<Arc<AppState>>,
     Path((user_id, root_id)): Path<(Uuid, Uuid)>,
@@ -92,15 +121,343 @@ async fn load_full_tree(
         .fetch_one(db)
         .await?;
 
-    Ok(TreeNode {
+    let mut tree = TreeNode {
         id: node.id.unwrap(),
         parent_id: node.parent_id,
         node_type: node.node_type,
         data: serde_json::from_value(node.data.unwrap())?,
         children: vec![],
         created_at: node.created_at.unwrap().to_rfc3339(),
-    })
+    };
+    annotations::attach(db, &mut tree).await?;
+    Ok(tree)
+}
+
+/// Duplicates a Branch and its subtree, e.g. to template a new floor from
+/// an existing one. Content-addressed images are shared with the source;
+/// older images that own their object get a server-side copy of it.
+pub async fn duplicate_node_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+    Json(request): Json<DuplicateNodeRequest>,
+) -> Result<(StatusCode, Json<DuplicateNodeResponse>)> {
+    let user = user_id.to_string();
+    let source = tree::get_node(&state.db, Some(&user), &node_id).await?;
+    if source.node_type != NodeType::Branch {
+        return Err(AppError::bad_request("Only branches can be duplicated"));
+    }
+    let parent_id = request
+        .parent_id
+        .or(source.parent_id)
+        .ok_or_else(|| AppError::bad_request("Branch has no parent to duplicate it under"))?;
+    let parent = tree::get_node(&state.db, Some(&user), &parent_id).await?;
+    if parent.node_type == NodeType::ImageLeaf {
+        return Err(AppError::bad_request("Images can't have children"));
+    }
+
+    let mut tx = state.db.begin().await?;
+    let copies = tree::duplicate_subtree(
+        &mut tx,
+        &user_id,
+        &node_id,
+        &parent_id,
+        request.label.as_deref(),
+        request.include_images,
+    )
+    .await?;
+
+    // Objects copied so far are removed again if anything fails
+    let mut copied = Vec::new();
+    let result = async {
+        copy_owned_images(&state, &mut tx, &user_id, &copies, &mut copied).await?;
+        tx.commit().await?;
+        Ok(())
+    }
+    .await;
+    if let Err(e) = result {
+        let _ = state.storage.delete_batch(copied).await;
+        return Err(e);
+    }
+
+    let root = copies
+        .iter()
+        .find(|copy| copy.parent_id == Some(parent_id))
+        .ok_or_else(|| AppError::internal("Duplicated subtree has no root"))?;
+    Ok((
+        StatusCode::CREATED,
+        Json(DuplicateNodeResponse {
+            node_id: root.id,
+            nodes: copies.len(),
+            images: copies.iter().filter(|copy| copy.node_type == NodeType::ImageLeaf).count(),
+        }),
+    ))
+}
+
+/// Gives copied leaves from before content addressing their own object,
+/// since deleting a leaf deletes the object it owns
+async fn copy_owned_images(
+    state: &AppState,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: &Uuid,
+    copies: &[tree::CopiedNode],
+    copied: &mut Vec<String>,
+) -> Result<()> {
+    for copy in copies {
+        let Some(source_path) = copy.storage_path.as_deref() else {
+            continue;
+        };
+        if copy.node_type != NodeType::ImageLeaf || copy.content_addressed {
+            continue;
+        }
+
+        let file_name = source_path.rsplit('/').next().unwrap_or(source_path);
+        let storage_path = format!("images/{}/{}/{}", user_id, copy.id, file_name);
+        state.storage.copy_image(source_path, &storage_path).await?;
+        copied.push(storage_path.clone());
+
+        sqlx::query!(
+            r#"
+            UPDATE tree_nodes
+            SET data = data || jsonb_build_object('storage_path', $2::text, 'url', $3::text)
+            WHERE id = $1
+            "#,
+            copy.id,
+            storage_path,
+            state.storage.public_url(&storage_path)
+        )
+            .execute(&mut **tx)
+            .await?;
+    }
+    Ok(())
+}
+
+/// GET /api/tree/{node_id}/settings
+pub async fn get_root_settings_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+) -> Result<Json<RootSettings>> {
+    Ok(Json(tree::get_root_settings(&state.db, Some(&user_id.to_string()), &node_id).await?))
+}
+
+/// Changes how a tree treats new content, e.g. describing new photos
+///
+/// PUT /api/tree/{node_id}/settings
+pub async fn update_root_settings_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+    Json(settings): Json<RootSettings>,
+) -> Result<Json<RootSettings>> {
+    Ok(Json(tree::update_root_settings(&state.db, &user_id, &node_id, &settings).await?))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct NearDuplicatesQuery {
+    /// Largest perceptual hash distance, in bits, that counts as a
+    /// near-duplicate; the configured default when omitted
+    pub distance: Option<u32>,
+}
+
+/// Groups of near-identical photos within a branch
+///
+/// GET /api/tree/{node_id}/near-duplicates?distance=...
+pub async fn near_duplicates_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+    Query(query): Query<NearDuplicatesQuery>,
+) -> Result<Json<Vec<NearDuplicateCluster>>> {
+    let distance = query
+        .distance
+        .unwrap_or(state.upload_config.near_duplicate_distance);
+    let clusters = tree::near_duplicates(&state.db, Some(&user_id.to_string()), &node_id, distance).await?;
+    Ok(Json(
+        clusters
+            .into_iter()
+            .map(|images| NearDuplicateCluster { images })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+/// Nodes whose names, labels or image descriptions match `q`, best first
+///
+/// GET /api/search?q=...
+pub async fn search_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<Vec<SearchHit>>> {
+    Ok(Json(search::search(&state.db, Some(&user_id.to_string()), &query.q).await?))
+}
+
+/// Regions marked on an image, oldest first
+///
+/// GET /api/images/{node_id}/annotations
+pub async fn list_annotations_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+) -> Result<Json<Vec<Annotation>>> {
+    let annotations = annotations::list(&state.db, Some(&user_id.to_string()), &node_id).await?;
+    Ok(Json(annotations))
+}
+
+/// Marks a region of an image
+///
+/// POST /api/images/{node_id}/annotations
+pub async fn create_annotation_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(node_id): Path<Uuid>,
+    Json(request): Json<CreateAnnotationRequest>,
+) -> Result<(StatusCode, Json<Annotation>)> {
+    let annotation = annotations::create(&state.db, &user_id, &node_id, request).await?;
+    Ok((StatusCode::CREATED, Json(annotation)))
+}
+
+/// GET /api/images/{node_id}/annotations/{annotation_id}
+pub async fn get_annotation_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path((node_id, annotation_id)): Path<(Uuid, Uuid)>,
+) -> Result<Json<Annotation>> {
+    let annotation = annotations::get(&state.db, Some(&user_id.to_string()), &node_id, &annotation_id).await?;
+    Ok(Json(annotation))
+}
+
+/// Changes an annotation's shape, label or note, or opens or resolves it
+///
+/// PATCH /api/images/{node_id}/annotations/{annotation_id}
+pub async fn update_annotation_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path((node_id, annotation_id)): Path<(Uuid, Uuid)>,
+    Json(request): Json<UpdateAnnotationRequest>,
+) -> Result<Json<Annotation>> {
+    let annotation = annotations::update(&state.db, &user_id, &node_id, &annotation_id, request).await?;
+    Ok(Json(annotation))
+}
+
+/// DELETE /api/images/{node_id}/annotations/{annotation_id}
+pub async fn delete_annotation_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path((node_id, annotation_id)): Path<(Uuid, Uuid)>,
+) -> Result<StatusCode> {
+    annotations::delete(&state.db, &user_id, &node_id, &annotation_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Queues background work, e.g. describing every photo under a branch
+///
+/// POST /api/jobs
+pub async fn enqueue_job_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    RequestLanguage(language): RequestLanguage,
+    Json(mut job): Json<JobKind>,
+) -> Result<(StatusCode, Json<Job>)> {
+    if let JobKind::Describe { language: requested @ None, .. } = &mut job {
+        *requested = Some(language);
+    }
+    let job = jobs::enqueue(&state, &user_id, job).await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct JobsQuery {
+    pub status: Option<JobStatus>,
+}
+
+/// The user's latest jobs, newest first
+///
+/// GET /api/jobs?status=...
+pub async fn list_jobs_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Query(query): Query<JobsQuery>,
+) -> Result<Json<Vec<Job>>> {
+    Ok(Json(jobs::list(&state.db, &user_id, query.status).await?))
+}
+
+/// GET /api/jobs/{job_id}
+pub async fn get_job_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(job_id): Path<Uuid>,
+) -> Result<Json<Job>> {
+    Ok(Json(jobs::get(&state.db, &user_id, &job_id).await?))
+}
+
+/// How often a job stream rechecks the database, for jobs run by another server
+const JOB_STATUS_POLL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Streams a job's progress as [`StreamEvent`]s until it succeeds or
+/// finally fails. Finished jobs get their outcome right away.
+///
+/// GET /api/jobs/{job_id}/events
+pub async fn job_events_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Path(job_id): Path<Uuid>,
+) -> Result<Sse<impl Stream<Item = std::result::Result<Event, Infallible>>>> {
+    // Subscribe before reading the status, so nothing falls in between
+    let mut rx = state.jobs.subscribe();
+    let job = jobs::get(&state.db, &user_id, &job_id).await?;
+
+    let stream = async_stream::stream! {
+        let mut terminal = jobs::terminal_event(&job);
+        let mut poll = tokio::time::interval(JOB_STATUS_POLL);
+        poll.tick().await;
+        while terminal.is_none() {
+            let event = tokio::select! {
+                received = rx.recv() => match received {
+                    Ok((id, event)) if id == job_id => event,
+                    Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                },
+                _ = poll.tick() => {
+                    match jobs::get(&state.db, &user_id, &job_id).await {
+                        Ok(job) => terminal = jobs::terminal_event(&job),
+                        Err(e) => log::warn!("Polling job {} failed: {}", job_id, e),
+                    }
+                    continue;
+                }
+            };
+            let finished = matches!(
+                event,
+                StreamEvent::Completed { .. } | StreamEvent::Error { recoverable: false, .. }
+            );
+            if let Ok(json_data) = serde_json::to_string(&event) {
+                yield Ok(Event::default().event("message").data(json_data));
+            }
+            if finished {
+                break;
+            }
+        }
+        if let Some(event) = terminal
+            && let Ok(json_data) = serde_json::to_string(&event)
+        {
+            yield Ok(Event::default().event("message").data(json_data));
+        }
+
+        yield Ok(Event::default().event("done").data("Stream closed"));
+    };
+
+    Ok(Sse::new(stream).keep_alive(
+        KeepAlive::new()
+            .interval(std::time::Duration::from_secs(15))
+            .text("keep-alive"),
+    ))
 }
+
 // ============================================================================
 // RESPONSE TYPES
 // ============================================================================
@@ -130,8 +487,12 @@ pub struct CancelErrorResponse {
 /// Returns: Server-Sent Events stream with StreamEvent data
 pub async fn chat_stream_handler(
     State(state): State<Arc<AppState>>,
-    Json(request): Json<AgentRequest>,
+    CurrentUser(user_id): CurrentUser,
+    Json(mut request): Json<AgentRequest>,
 ) -> Sse<impl Stream<Item = std::result::Result<Event, Infallible>>> {
+    // Agent tools check access as this user, so it must be the
+    // authenticated one rather than whatever the body claims
+    request.user_id = Some(user_id.to_string());
     let agent = state.master_agent.clone();
     let mut rx = agent.handle_request_stream(state.clone(), request).await;
 
@@ -239,11 +600,14 @@ pub async fn health_check(
             .await
             .is_ok();
     */
-    health.services.s3 = state.storage.exists("health-check").await.unwrap_or(true);
+    health.services.s3 = state.storage.exists("health-check").await.is_ok();
 
-    health.services.ollama = reqwest::get(format!("{}/api/tags", state.ai_config.url))
-        .await
-        .is_ok();
+    health.services.ollama = match &state.ai_config.provider {
+        AiProvider::Mock(_) => true,
+        AiProvider::Ollama => reqwest::get(format!("{}/api/tags", state.ai_config.url))
+            .await
+            .is_ok(),
+    };
 
     if !health.is_healthy() {
         health.status = "degraded".to_string();
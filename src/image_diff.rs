@@ -0,0 +1,205 @@
+use image::imageops::FilterType;
+use image::{DynamicImage, GrayImage, Rgb, RgbImage};
+
+// ============================================================================
+// Change detection
+// ============================================================================
+
+/// Longest side both photos are compared at
+const WORK_SIZE: u32 = 512;
+/// Largest shift, in working pixels, tried when aligning the photos
+const MAX_SHIFT: i32 = 32;
+/// Coarse alignment runs at 1/COARSE of the working size
+const COARSE: u32 = 4;
+/// Luma difference, after exposure compensation, that counts as a change
+const CHANGE_THRESHOLD: f32 = 32.0;
+
+/// Where and how much two photos of the same spot differ
+#[derive(Debug, Clone)]
+pub struct ImageDiff {
+    /// Share of the overlapping area that changed, 0–100
+    pub changed_percent: f32,
+    /// Shift of the second photo against the first, in working pixels
+    pub offset: (i32, i32),
+    /// The second photo with changes highlighted, yellow to red by strength
+    pub overlay: RgbImage,
+}
+
+/// Aligns `after` to `before` by the translation that matches them best,
+/// then marks pixels whose brightness differs by more than the overall
+/// exposure difference explains. Both are scaled to a common working size
+/// first, so photos from different cameras can be compared.
+pub fn diff(before: &DynamicImage, after: &DynamicImage) -> ImageDiff {
+    let base = if before.width().max(before.height()) > WORK_SIZE {
+        before.thumbnail(WORK_SIZE, WORK_SIZE)
+    } else {
+        before.clone()
+    };
+    let (width, height) = (base.width(), base.height());
+    let after = after.resize_exact(width, height, FilterType::Triangle);
+
+    // Smoothing keeps sensor noise and JPEG artefacts out of the mask
+    let a = base.blur(1.5).to_luma8();
+    let b = after.blur(1.5).to_luma8();
+    let offset = align(&a, &b);
+
+    let (mean_a, mean_b) = overlap_means(&a, &b, offset);
+    let mut overlay = after.to_rgb8();
+    let (mut overlap, mut changed) = (0u64, 0u64);
+    for y in 0..height as i32 {
+        for x in 0..width as i32 {
+            let Some(pixel_b) = shifted(&b, x, y, offset) else {
+                continue;
+            };
+            overlap += 1;
+            let pixel_a = f32::from(a.get_pixel(x as u32, y as u32)[0]);
+            let difference = ((pixel_a - mean_a) - (pixel_b - mean_b)).abs();
+            if difference <= CHANGE_THRESHOLD {
+                continue;
+            }
+            changed += 1;
+
+            // Overlay pixels follow `after`, which is drawn unshifted
+            let (ox, oy) = ((x + offset.0) as u32, (y + offset.1) as u32);
+            let strength = ((difference - CHANGE_THRESHOLD) / 96.0).min(1.0);
+            let heat = [255.0, 255.0 * (1.0 - strength), 0.0];
+            let pixel = overlay.get_pixel_mut(ox, oy);
+            *pixel = Rgb(std::array::from_fn(|i| (f32::from(pixel[i]) * 0.45 + heat[i] * 0.55) as u8));
+        }
+    }
+
+    ImageDiff {
+        changed_percent: if overlap == 0 { 0.0 } else { changed as f32 * 100.0 / overlap as f32 },
+        offset,
+        overlay,
+    }
+}
+
+/// Pixel of `image` at (x, y) of the other image, given the offset between them
+fn shifted(image: &GrayImage, x: i32, y: i32, offset: (i32, i32)) -> Option<f32> {
+    let (sx, sy) = (x + offset.0, y + offset.1);
+    if sx < 0 || sy < 0 || sx >= image.width() as i32 || sy >= image.height() as i32 {
+        return None;
+    }
+    Some(f32::from(image.get_pixel(sx as u32, sy as u32)[0]))
+}
+
+fn overlap_means(a: &GrayImage, b: &GrayImage, offset: (i32, i32)) -> (f32, f32) {
+    let (mut sum_a, mut sum_b, mut n) = (0f64, 0f64, 0f64);
+    for y in 0..a.height() as i32 {
+        for x in 0..a.width() as i32 {
+            if let Some(pixel_b) = shifted(b, x, y, offset) {
+                sum_a += f64::from(a.get_pixel(x as u32, y as u32)[0]);
+                sum_b += f64::from(pixel_b);
+                n += 1.0;
+            }
+        }
+    }
+    if n == 0.0 {
+        return (0.0, 0.0);
+    }
+    ((sum_a / n) as f32, (sum_b / n) as f32)
+}
+
+/// Mean absolute difference over the overlap, with exposure compensated.
+/// Shifts leaving less than half the image overlapping don't count.
+fn mismatch(a: &GrayImage, b: &GrayImage, offset: (i32, i32)) -> f32 {
+    let (mean_a, mean_b) = overlap_means(a, b, offset);
+    let (mut sum, mut n) = (0f64, 0u64);
+    for y in 0..a.height() as i32 {
+        for x in 0..a.width() as i32 {
+            if let Some(pixel_b) = shifted(b, x, y, offset) {
+                let pixel_a = f32::from(a.get_pixel(x as u32, y as u32)[0]);
+                sum += f64::from(((pixel_a - mean_a) - (pixel_b - mean_b)).abs());
+                n += 1;
+            }
+        }
+    }
+    if n * 2 < u64::from(a.width() * a.height()) {
+        return f32::MAX;
+    }
+    (sum / n as f64) as f32
+}
+
+/// Best translation of `b` against `a`: an exhaustive search on a coarse
+/// copy, refined at full working size
+fn align(a: &GrayImage, b: &GrayImage) -> (i32, i32) {
+    let coarse = |image: &GrayImage| {
+        image::imageops::resize(
+            image,
+            (image.width() / COARSE).max(1),
+            (image.height() / COARSE).max(1),
+            FilterType::Triangle,
+        )
+    };
+    let (small_a, small_b) = (coarse(a), coarse(b));
+    let reach = MAX_SHIFT / COARSE as i32;
+    let best = best_shift(&small_a, &small_b, (0, 0), reach);
+
+    let scaled = (best.0 * COARSE as i32, best.1 * COARSE as i32);
+    best_shift(a, b, scaled, COARSE as i32 - 1)
+}
+
+fn best_shift(a: &GrayImage, b: &GrayImage, around: (i32, i32), reach: i32) -> (i32, i32) {
+    let mut best = (around, mismatch(a, b, around));
+    for dy in -reach..=reach {
+        for dx in -reach..=reach {
+            let offset = (around.0 + dx, around.1 + dy);
+            let score = mismatch(a, b, offset);
+            // Prefer the smaller shift on ties, so static scenes stay unshifted
+            if score < best.1 || (score == best.1 && offset.0.abs() + offset.1.abs() < best.0.0.abs() + best.0.1.abs()) {
+                best = (offset, score);
+            }
+        }
+    }
+    best.0
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    /// A textured scene, shifted right and down by `shift` pixels
+    fn scene(shift: (i32, i32)) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(256, 192, |x, y| {
+            let (x, y) = (x as i32 - shift.0, y as i32 - shift.1);
+            let value = ((x / 12 + y / 12) % 2 * 120 + 60 + (x * 7 + y * 3) % 40) as u8;
+            Luma([value])
+        }))
+    }
+
+    #[test]
+    fn test_identical_photos_do_not_change() {
+        let result = diff(&scene((0, 0)), &scene((0, 0)));
+        assert_eq!(result.offset, (0, 0));
+        assert_eq!(result.changed_percent, 0.0);
+        assert_eq!(result.overlay.dimensions(), (256, 192));
+    }
+
+    #[test]
+    fn test_shifted_photo_is_aligned_and_change_found() {
+        let before = scene((0, 0));
+        let mut after = scene((6, 4)).to_luma8();
+        // Something new in the second photo: a bright 64×48 block, 6.25% of the frame
+        for y in 100..148 {
+            for x in 150..214 {
+                after.put_pixel(x, y, Luma([255]));
+            }
+        }
+
+        let result = diff(&before, &DynamicImage::ImageLuma8(after));
+        assert_eq!(result.offset, (6, 4));
+        assert!(
+            (4.0..10.0).contains(&result.changed_percent),
+            "changed {}%",
+            result.changed_percent
+        );
+        let highlighted = result.overlay.get_pixel(180, 120);
+        assert!(highlighted[0] > highlighted[2], "{:?}", highlighted);
+    }
+}
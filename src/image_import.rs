@@ -0,0 +1,249 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use bytes::Bytes;
+use futures::StreamExt;
+use reqwest::{header, redirect, Url};
+use crate::error::{AppError, ErrorCode, Result};
+use crate::storage::{env_list, env_or};
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+#[derive(Debug, Clone)]
+pub struct ImportConfig {
+    /// URL schemes images may be imported from, lowercase
+    pub allowed_schemes: Vec<String>,
+    /// Upper bound on the whole import, redirects and body included
+    pub timeout: Duration,
+    pub max_redirects: usize,
+}
+
+impl Default for ImportConfig {
+    fn default() -> Self {
+        Self {
+            allowed_schemes: vec!["https".to_string()],
+            timeout: Duration::from_secs(30),
+            max_redirects: 3,
+        }
+    }
+}
+
+impl ImportConfig {
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let default = Self::default();
+        Ok(Self {
+            allowed_schemes: env_list("IMPORT_ALLOWED_SCHEMES")
+                .map(|schemes| schemes.iter().map(|s| s.to_lowercase()).collect())
+                .unwrap_or(default.allowed_schemes),
+            timeout: Duration::from_secs(env_or("IMPORT_TIMEOUT_SECS", default.timeout.as_secs())?),
+            max_redirects: env_or("IMPORT_MAX_REDIRECTS", default.max_redirects)?,
+        })
+    }
+}
+
+// ============================================================================
+// Fetching
+// ============================================================================
+
+/// Downloads an image from a user-supplied URL without letting the URL
+/// reach into our own network: every hop of a redirect chain must use an
+/// allowed scheme and resolve to public addresses only, and the connection
+/// is pinned to the addresses that were checked so a second DNS answer
+/// can't swap in a private one. Bodies over `max_bytes` are cut off.
+pub async fn fetch_image(config: &ImportConfig, url: &str, max_bytes: u64) -> Result<Bytes> {
+    let url = Url::parse(url).map_err(|e| AppError::bad_request(format!("Invalid URL: {}", e)))?;
+    tokio::time::timeout(config.timeout, fetch_following_redirects(config, url, max_bytes))
+        .await
+        .map_err(|_| {
+            AppError::new(
+                ErrorCode::ExternalServiceError,
+                format!("Import timed out after {} seconds", config.timeout.as_secs()),
+            )
+        })?
+}
+
+async fn fetch_following_redirects(config: &ImportConfig, mut url: Url, max_bytes: u64) -> Result<Bytes> {
+    for _ in 0..=config.max_redirects {
+        let addrs = resolve_public(config, &url).await?;
+        let mut client = reqwest::Client::builder()
+            .redirect(redirect::Policy::none())
+            .no_proxy();
+        if let Some(domain) = url.domain() {
+            client = client.resolve_to_addrs(domain, &addrs);
+        }
+        let client = client.build().map_err(|e| AppError::internal(format!("HTTP client: {}", e)))?;
+
+        let response = client.get(url.clone()).send().await.map_err(remote_error)?;
+        let status = response.status();
+        if status.is_redirection() {
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| remote_error(format!("{} without a Location", status)))?;
+            url = url
+                .join(location)
+                .map_err(|e| remote_error(format!("Invalid redirect: {}", e)))?;
+            continue;
+        }
+        if !status.is_success() {
+            return Err(remote_error(format!("Remote server answered {}", status)));
+        }
+
+        let too_large = || {
+            AppError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Image too large (max {} bytes)", max_bytes),
+            )
+        };
+        if response.content_length().is_some_and(|len| len > max_bytes) {
+            return Err(too_large());
+        }
+        let mut body = Vec::new();
+        let mut chunks = response.bytes_stream();
+        while let Some(chunk) = chunks.next().await {
+            let chunk = chunk.map_err(remote_error)?;
+            if (body.len() + chunk.len()) as u64 > max_bytes {
+                return Err(too_large());
+            }
+            body.extend_from_slice(&chunk);
+        }
+        return Ok(Bytes::from(body));
+    }
+
+    Err(remote_error(format!("More than {} redirects", config.max_redirects)))
+}
+
+fn remote_error(e: impl std::fmt::Display) -> AppError {
+    AppError::new(ErrorCode::ExternalServiceError, format!("Import failed: {}", e))
+}
+
+/// Checks the scheme and resolves the host, failing unless every address
+/// it resolves to is public
+async fn resolve_public(config: &ImportConfig, url: &Url) -> Result<Vec<SocketAddr>> {
+    if !config.allowed_schemes.iter().any(|scheme| scheme == url.scheme()) {
+        return Err(AppError::bad_request(format!("URL scheme '{}' is not allowed", url.scheme())));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| AppError::bad_request("URL has no host"))?;
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| AppError::bad_request("URL has no port"))?;
+
+    // IPv6 literals come bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|e| AppError::bad_request(format!("Cannot resolve {}: {}", host, e)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(AppError::bad_request(format!("Cannot resolve {}", host)));
+    }
+    if let Some(blocked) = addrs.iter().find(|addr| !is_public(addr.ip())) {
+        return Err(AppError::forbidden(format!(
+            "{} resolves to non-public address {}",
+            host,
+            blocked.ip()
+        )));
+    }
+    Ok(addrs)
+}
+
+/// Whether an address is globally routable, i.e. not loopback, private,
+/// link-local, shared (CGNAT), documentation, multicast or reserved
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(v4) => is_public_v4(v4),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (CGNAT), 100.64.0.0/10
+        || (a == 100 && (64..128).contains(&b))
+        // IETF protocol assignments, 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking, 198.18.0.0/15
+        || (a == 198 && (18..20).contains(&b))
+        // Reserved, 240.0.0.0/4
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        || ip.is_unique_local()
+        || ip.is_unicast_link_local()
+        // Documentation, 2001:db8::/32
+        || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+        // NAT64, 64:ff9b::/96, would reach IPv4 addresses unchecked
+        || (segments[0] == 0x0064 && segments[1] == 0xff9b)
+        // IPv4-compatible, ::/96
+        || segments[..6].iter().all(|&s| s == 0))
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_public() {
+        for blocked in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "::ffff:10.0.0.1",
+            "64:ff9b::a00:1",
+        ] {
+            assert!(!is_public(blocked.parse().unwrap()), "{}", blocked);
+        }
+        for allowed in ["93.184.216.34", "1.1.1.1", "2606:4700:4700::1111", "::ffff:8.8.8.8"] {
+            assert!(is_public(allowed.parse().unwrap()), "{}", allowed);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rejects_disallowed_urls() {
+        let config = ImportConfig::default();
+        for (url, code) in [
+            ("http://example.com/a.jpg", ErrorCode::BadRequest),
+            ("file:///etc/passwd", ErrorCode::BadRequest),
+            ("https://127.0.0.1/a.jpg", ErrorCode::Forbidden),
+            ("https://[::1]/a.jpg", ErrorCode::Forbidden),
+            ("https://10.0.0.1:8443/a.jpg", ErrorCode::Forbidden),
+        ] {
+            let error = fetch_image(&config, url, 1024).await.unwrap_err();
+            assert_eq!(error.code, code, "{}", url);
+        }
+    }
+}
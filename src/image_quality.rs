@@ -0,0 +1,179 @@
+use image::{DynamicImage, GrayImage};
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// Quality analysis
+// ============================================================================
+
+/// Images are scaled down to this longest side before analysis, so scores
+/// are comparable between cameras
+const ANALYSIS_SIZE: u32 = 1024;
+
+/// Below this variance of the Laplacian an image counts as blurred
+const MIN_SHARPNESS: f32 = 100.0;
+/// Mean luma bounds of a usable exposure
+const MIN_BRIGHTNESS: f32 = 40.0;
+const MAX_BRIGHTNESS: f32 = 215.0;
+/// Share of crushed shadows or blown highlights that spoils an exposure
+const MAX_CLIPPED: f32 = 0.5;
+/// Above this estimated noise sigma, in luma levels, an image counts as noisy
+const MAX_NOISE: f32 = 10.0;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityWarning {
+    Blurry,
+    Underexposed,
+    Overexposed,
+    Noisy,
+}
+
+/// How usable a photo is for inspection
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ImageQuality {
+    /// Variance of the Laplacian; low means blurred
+    pub sharpness: f32,
+    /// Mean luma, 0–255
+    pub brightness: f32,
+    /// Estimated standard deviation of the noise, in luma levels
+    pub noise: f32,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<QualityWarning>,
+}
+
+impl ImageQuality {
+    pub fn analyze(img: &DynamicImage) -> Self {
+        let gray = if img.width().max(img.height()) > ANALYSIS_SIZE {
+            img.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_luma8()
+        } else {
+            img.to_luma8()
+        };
+
+        let sharpness = laplacian_variance(&gray);
+        let (brightness, dark, bright) = exposure(&gray);
+        let noise = noise_sigma(&gray);
+
+        let mut warnings = Vec::new();
+        if sharpness < MIN_SHARPNESS {
+            warnings.push(QualityWarning::Blurry);
+        }
+        if brightness < MIN_BRIGHTNESS || dark > MAX_CLIPPED {
+            warnings.push(QualityWarning::Underexposed);
+        }
+        if brightness > MAX_BRIGHTNESS || bright > MAX_CLIPPED {
+            warnings.push(QualityWarning::Overexposed);
+        }
+        if noise > MAX_NOISE {
+            warnings.push(QualityWarning::Noisy);
+        }
+
+        Self {
+            sharpness,
+            brightness,
+            noise,
+            warnings,
+        }
+    }
+
+    pub fn is_low(&self) -> bool {
+        !self.warnings.is_empty()
+    }
+}
+
+/// Sum of a 3×3 kernel applied around every interior pixel
+fn convolve(gray: &GrayImage, kernel: [[i32; 3]; 3], mut each: impl FnMut(i32)) {
+    let (width, height) = gray.dimensions();
+    for y in 1..height.saturating_sub(1) {
+        for x in 1..width.saturating_sub(1) {
+            let mut sum = 0;
+            for (dy, row) in kernel.iter().enumerate() {
+                for (dx, weight) in row.iter().enumerate() {
+                    let pixel = gray.get_pixel(x + dx as u32 - 1, y + dy as u32 - 1)[0];
+                    sum += weight * i32::from(pixel);
+                }
+            }
+            each(sum);
+        }
+    }
+}
+
+fn laplacian_variance(gray: &GrayImage) -> f32 {
+    let (mut n, mut sum, mut sum_sq) = (0f64, 0f64, 0f64);
+    convolve(gray, [[0, 1, 0], [1, -4, 1], [0, 1, 0]], |value| {
+        let value = f64::from(value);
+        n += 1.0;
+        sum += value;
+        sum_sq += value * value;
+    });
+    if n == 0.0 {
+        return 0.0;
+    }
+    let mean = sum / n;
+    (sum_sq / n - mean * mean) as f32
+}
+
+/// Mean luma and the shares of near-black and near-white pixels
+fn exposure(gray: &GrayImage) -> (f32, f32, f32) {
+    let total = gray.pixels().len().max(1) as f32;
+    let (mut sum, mut dark, mut bright) = (0u64, 0u32, 0u32);
+    for pixel in gray.pixels() {
+        let value = pixel[0];
+        sum += u64::from(value);
+        dark += u32::from(value < 16);
+        bright += u32::from(value > 239);
+    }
+    (sum as f32 / total, dark as f32 / total, bright as f32 / total)
+}
+
+/// Immerkær's fast noise variance estimation: the kernel cancels out
+/// image structure up to second order, leaving mostly noise
+fn noise_sigma(gray: &GrayImage) -> f32 {
+    let (mut n, mut sum) = (0f64, 0f64);
+    convolve(gray, [[1, -2, 1], [-2, 4, -2], [1, -2, 1]], |value| {
+        n += 1.0;
+        sum += f64::from(value.abs());
+    });
+    if n == 0.0 {
+        return 0.0;
+    }
+    (sum * (std::f64::consts::PI / 2.0).sqrt() / (6.0 * n)) as f32
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Luma;
+
+    /// Hard-edged 16-pixel checkerboard, optionally with pseudo-random noise
+    fn checkerboard(low: u8, high: u8, noise: i32) -> DynamicImage {
+        let mut seed = 12345u32;
+        DynamicImage::ImageLuma8(GrayImage::from_fn(256, 256, |x, y| {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let jitter = if noise > 0 { (seed >> 16) as i32 % (2 * noise + 1) - noise } else { 0 };
+            let base = if (x / 16 + y / 16) % 2 == 0 { low } else { high };
+            Luma([(i32::from(base) + jitter).clamp(0, 255) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_quality_warnings() {
+        let good = ImageQuality::analyze(&checkerboard(60, 190, 0));
+        assert!(!good.is_low(), "{:?}", good);
+
+        let blurred = ImageQuality::analyze(&checkerboard(60, 190, 0).blur(4.0));
+        assert_eq!(blurred.warnings, vec![QualityWarning::Blurry]);
+
+        let dark = ImageQuality::analyze(&checkerboard(0, 40, 0));
+        assert!(dark.warnings.contains(&QualityWarning::Underexposed));
+
+        let bright = ImageQuality::analyze(&checkerboard(220, 255, 0));
+        assert!(bright.warnings.contains(&QualityWarning::Overexposed));
+
+        let noisy = ImageQuality::analyze(&checkerboard(60, 190, 40));
+        assert!(noisy.warnings.contains(&QualityWarning::Noisy));
+    }
+}
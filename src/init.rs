@@ -2,7 +2,11 @@ use std::error::Error;
 use std::sync::Arc;
 use sqlx::postgres::PgPoolOptions;
 use crate::{AiConfig, AppState, MasterAgent};
+use crate::blob_store::{LocalStore, S3Store};
 use crate::error::AppError;
+use crate::storage::UploadConfig;
+use crate::sweeper::{self, SweepConfig};
+use crate::jobs::{self, JobConfig, JobHub};
 use crate::handlers::{ImageProcessor, ImageUrlResolver, StorageService};
 
 // ============================================================================
@@ -12,11 +16,17 @@ use crate::handlers::{ImageProcessor, ImageUrlResolver, StorageService};
 #[derive(Debug, Clone)]
 pub struct Config {
     pub database_url: String,
-    pub s3: S3Config,
+    pub storage: StorageConfig,
     pub host: String,
     pub port: u16,
 }
 
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    S3(S3Config),
+    Local(LocalConfig),
+}
+
 #[derive(Debug, Clone)]
 pub struct S3Config {
     pub bucket: String,
@@ -27,22 +37,65 @@ pub struct S3Config {
     pub public_url_base: String,
 }
 
+#[derive(Clone)]
+pub struct LocalConfig {
+    pub root: String,
+    /// Base URL the `/files/` route is reachable under
+    pub public_url_base: String,
+    /// HMAC key for signed file URLs
+    pub secret: Vec<u8>,
+}
+
+impl std::fmt::Debug for LocalConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LocalConfig")
+            .field("root", &self.root)
+            .field("public_url_base", &self.public_url_base)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Config {
     pub fn from_env() -> Result<Self, Box<dyn std::error::Error>> {
-        Ok(Self {
-            database_url: std::env::var("DATABASE_URL")?,
-            s3: S3Config {
+        let port: u16 = std::env::var("PORT")
+            .unwrap_or_else(|_| "3000".to_string())
+            .parse()?;
+
+        // S3 when configured, local disk otherwise
+        let backend = std::env::var("STORAGE_BACKEND").unwrap_or_else(|_| {
+            if std::env::var("S3_BUCKET").is_ok() { "s3" } else { "local" }.to_string()
+        });
+        let storage = match backend.as_str() {
+            "s3" => StorageConfig::S3(S3Config {
                 bucket: std::env::var("S3_BUCKET")?,
                 region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
                 endpoint: std::env::var("S3_ENDPOINT").ok(),
                 access_key: std::env::var("AWS_ACCESS_KEY_ID")?,
                 secret_key: std::env::var("AWS_SECRET_ACCESS_KEY")?,
                 public_url_base: std::env::var("S3_PUBLIC_URL")?,
-            },
+            }),
+            "local" => StorageConfig::Local(LocalConfig {
+                root: std::env::var("LOCAL_STORAGE_DIR").unwrap_or_else(|_| "./data/blobs".to_string()),
+                public_url_base: std::env::var("LOCAL_PUBLIC_URL")
+                    .unwrap_or_else(|_| format!("http://localhost:{}", port)),
+                secret: match std::env::var("LOCAL_STORAGE_SECRET") {
+                    Ok(secret) => secret.into_bytes(),
+                    Err(_) => {
+                        log::warn!("⚠️  LOCAL_STORAGE_SECRET not set, file URLs will not survive a restart");
+                        let mut secret = vec![0u8; 32];
+                        getrandom::fill(&mut secret).map_err(|e| format!("Random secret: {}", e))?;
+                        secret
+                    }
+                },
+            }),
+            other => return Err(format!("Invalid STORAGE_BACKEND: unknown backend '{}'", other).into()),
+        };
+
+        Ok(Self {
+            database_url: std::env::var("DATABASE_URL")?,
+            storage,
             host: std::env::var("HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
-            port: std::env::var("PORT")
-                .unwrap_or_else(|_| "3000".to_string())
-                .parse()?,
+            port,
         })
     }
 }
@@ -51,6 +104,9 @@ pub async fn app_init() -> Result<(Config, Arc<AppState>), Box<dyn Error>> {
     log::info!("✅ Configuration loaded");
     let ai_config = AiConfig::from_env()?;
     log::info!("✅ Ai Configuration loaded");
+    let upload_config = UploadConfig::from_env()?;
+    let sweep_config = SweepConfig::from_env()?;
+    let job_config = JobConfig::from_env()?;
 
     // Database
     log::info!("📊 Connecting to PostgreSQL...");
@@ -66,38 +122,56 @@ pub async fn app_init() -> Result<(Config, Arc<AppState>), Box<dyn Error>> {
         let redis = setup_redis(&config).await?;
         log::info!("✅ Redis connected");
     */
-    // S3 Storage
-    log::info!("☁️  Initializing S3 with rust-s3...");
-    let storage = setup_storage(&config.s3)?;
-    log::info!("✅ S3 storage initialized");
+    // Blob storage
+    log::info!("☁️  Initializing storage...");
+    let storage = setup_storage(&config.storage)?;
+    log::info!("✅ Storage initialized");
 
-    // Test S3
+    // Test storage
     match storage.list_user_images(&uuid::Uuid::now_v7()).await {
-        Ok(_) => log::info!("✅ S3 connection verified"),
-        Err(e) => log::warn!("⚠️  S3 test: {}", e),
+        Ok(_) => log::info!("✅ Storage connection verified"),
+        Err(e) => log::warn!("⚠️  Storage test: {}", e),
     }
 
+    let state = build_state(db, storage, ai_config, upload_config);
+
+    // Storage/database reconciliation
+    sweeper::spawn(state.clone(), sweep_config);
+
+    // Background analysis
+    jobs::spawn(state.clone(), job_config);
+
+    Ok((config, state))
+}
+
+/// Wires services and agents into the shared application state
+pub fn build_state(
+    db: sqlx::PgPool,
+    storage: Arc<StorageService>,
+    ai_config: AiConfig,
+    upload_config: UploadConfig,
+) -> Arc<AppState> {
     // Resolvers and processors
     let image_resolver = Arc::new(ImageUrlResolver {
         storage: storage.clone(),
         db: db.clone(),
     });
 
-    let image_processor = Arc::new(ImageProcessor::new(storage.clone()));
+    let image_processor = Arc::new(ImageProcessor::new(storage.clone(), upload_config.limits.clone()));
 
-
-    let master_agent = Arc::new(MasterAgent::new(&ai_config.url));
+    let master_agent = Arc::new(MasterAgent::new(&ai_config));
 
     // Application state
-    let state = Arc::new(AppState {
+    Arc::new(AppState {
         db,
         storage,
         image_resolver,
         image_processor,
         master_agent,
-        ai_config
-    });
-    Ok((config, state))
+        jobs: Arc::new(JobHub::default()),
+        ai_config,
+        upload_config,
+    })
 }
 
 // ============================================================================
@@ -117,15 +191,79 @@ async fn setup_redis(config: &Config) -> Result<redis::aio::ConnectionManager, r
     redis::aio::ConnectionManager::new(client).await
 }
 */
-fn setup_storage(config: &S3Config) -> Result<Arc<StorageService>, AppError> {
-    let storage = StorageService::new(
-        config.bucket.clone(),
-        config.region.clone(),
-        config.access_key.clone(),
-        config.secret_key.clone(),
-        config.public_url_base.clone(),
-        config.endpoint.clone(),
-    )?;
+fn setup_storage(config: &StorageConfig) -> Result<Arc<StorageService>, AppError> {
+    let storage = match config {
+        StorageConfig::S3(s3) => StorageService::new(Arc::new(S3Store::new(
+            s3.bucket.clone(),
+            s3.region.clone(),
+            s3.access_key.clone(),
+            s3.secret_key.clone(),
+            s3.public_url_base.clone(),
+            s3.endpoint.clone(),
+        )?)),
+        StorageConfig::Local(local) => StorageService::local(LocalStore::new(
+            &local.root,
+            local.public_url_base.clone(),
+            local.secret.clone(),
+        )?),
+    };
 
     Ok(Arc::new(storage))
 }
+
+#[cfg(test)]
+pub(crate) fn test_config(mock: Arc<crate::agents::MockLlm>) -> AiConfig {
+    AiConfig {
+        provider: crate::AiProvider::Mock(mock),
+        retry: crate::storage::RetryPolicy {
+            max_retries: 2,
+            initial_backoff: std::time::Duration::from_millis(1),
+            max_backoff: std::time::Duration::from_millis(4),
+        },
+        ..AiConfig::default()
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn test_state(ai_config: AiConfig) -> Arc<AppState> {
+    let db = PgPoolOptions::new()
+        .connect_lazy("postgres://localhost/cx58_test")
+        .unwrap();
+    let root = std::env::temp_dir().join(format!("cx58-test-{}", uuid::Uuid::now_v7()));
+    let storage = StorageService::local(
+        LocalStore::new(root, "http://localhost:3000".to_string(), b"test".to_vec()).unwrap(),
+    );
+
+    build_state(db, Arc::new(storage), ai_config, UploadConfig::default())
+}
+
+/// Like [`test_state`] but on the database in `DATABASE_URL`, for tests of
+/// queries. Those tests are `#[ignore]`d and run with
+/// `cargo test -- --ignored` against a migrated database.
+#[cfg(test)]
+pub(crate) async fn test_db_state(ai_config: AiConfig) -> Arc<AppState> {
+    let url = std::env::var("DATABASE_URL").expect("database tests need DATABASE_URL");
+    let db = PgPoolOptions::new()
+        .max_connections(4)
+        .acquire_timeout(std::time::Duration::from_secs(2))
+        .connect(&url)
+        .await
+        .expect("database tests need a reachable DATABASE_URL");
+
+    let mut state = (*test_state(ai_config)).clone();
+    state.db = db;
+    Arc::new(state)
+}
+
+/// A new root of `user_id` in a test database; deleting it removes
+/// everything the test put under it
+#[cfg(test)]
+pub(crate) async fn test_root(db: &sqlx::PgPool, user_id: &uuid::Uuid) -> uuid::Uuid {
+    sqlx::query_scalar!(
+        r#"INSERT INTO tree_nodes (user_id, node_type, data) VALUES ($1, 'Root', '{"title": "Test site"}') RETURNING id"#,
+        user_id
+    )
+    .fetch_one(db)
+    .await
+    .unwrap()
+}
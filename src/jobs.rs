@@ -0,0 +1,774 @@
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde_json::json;
+use tokio::sync::{broadcast, mpsc, Notify};
+use uuid::Uuid;
+use crate::agents::{build_client, AiClient, StreamEvent};
+use crate::error::{AppError, Result};
+use crate::models::{Job, JobKind, JobStatus, NodeSummary, NodeType};
+use crate::storage::{env_or, RetryPolicy};
+use crate::{descriptions, storage, tree, AppState};
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+#[derive(Debug, Clone)]
+pub struct JobConfig {
+    /// Jobs run concurrently by this server; zero leaves jobs to other servers
+    pub workers: usize,
+    /// How often idle workers look for due jobs, e.g. retries coming up or
+    /// jobs queued by another server
+    pub poll_interval: Duration,
+    /// Running jobs not finished after this are taken to be orphaned by a
+    /// crashed server and claimed again
+    pub stale_after: Duration,
+    /// Retries of jobs that failed on a server-side error, e.g. the model
+    /// being unreachable
+    pub retry: RetryPolicy,
+}
+
+impl Default for JobConfig {
+    fn default() -> Self {
+        Self {
+            workers: 2,
+            poll_interval: Duration::from_secs(5),
+            stale_after: Duration::from_secs(15 * 60),
+            retry: RetryPolicy {
+                max_retries: 3,
+                initial_backoff: Duration::from_secs(30),
+                max_backoff: Duration::from_secs(30 * 60),
+            },
+        }
+    }
+}
+
+impl JobConfig {
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let default = Self::default();
+        Ok(Self {
+            workers: env_or("JOB_WORKERS", default.workers)?,
+            poll_interval: Duration::from_secs(env_or("JOB_POLL_INTERVAL_SECS", default.poll_interval.as_secs())?),
+            stale_after: Duration::from_secs(env_or("JOB_STALE_SECS", default.stale_after.as_secs())?),
+            retry: RetryPolicy {
+                max_retries: env_or("JOB_MAX_RETRIES", default.retry.max_retries)?,
+                initial_backoff: Duration::from_secs(env_or(
+                    "JOB_RETRY_BACKOFF_SECS",
+                    default.retry.initial_backoff.as_secs(),
+                )?),
+                max_backoff: Duration::from_secs(env_or("JOB_MAX_BACKOFF_SECS", default.retry.max_backoff.as_secs())?),
+            },
+        })
+    }
+}
+
+// ============================================================================
+// Hub
+// ============================================================================
+
+/// Events buffered per subscriber before the slowest ones start missing some
+const EVENT_CAPACITY: usize = 1024;
+
+/// Connects the job API with this server's workers: progress events of
+/// running jobs, tagged with the job id, and a wake-up for new jobs
+pub struct JobHub {
+    events: broadcast::Sender<(Uuid, StreamEvent)>,
+    wake: Notify,
+}
+
+impl Default for JobHub {
+    fn default() -> Self {
+        Self {
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            wake: Notify::new(),
+        }
+    }
+}
+
+impl JobHub {
+    pub fn subscribe(&self) -> broadcast::Receiver<(Uuid, StreamEvent)> {
+        self.events.subscribe()
+    }
+
+    /// Lets an idle worker look for jobs now rather than at its next poll
+    pub fn wake(&self) {
+        self.wake.notify_one();
+    }
+
+    pub fn emit(&self, job_id: Uuid, event: StreamEvent) {
+        // Nobody listening is fine
+        let _ = self.events.send((job_id, event));
+    }
+}
+
+/// The event a finished job's stream ends with, `None` while it isn't
+pub fn terminal_event(job: &Job) -> Option<StreamEvent> {
+    let request_id = job.id.to_string();
+    match job.status {
+        JobStatus::Succeeded => Some(StreamEvent::Completed {
+            request_id,
+            final_result: job.result.as_ref().map(|r| r.to_string()).unwrap_or_default(),
+            timestamp: Utc::now().timestamp(),
+        }),
+        JobStatus::Failed => Some(StreamEvent::Error {
+            request_id,
+            error: job.error.clone().unwrap_or_default(),
+            recoverable: false,
+        }),
+        JobStatus::Queued | JobStatus::Running => None,
+    }
+}
+
+// ============================================================================
+// Queries
+// ============================================================================
+
+struct JobRow {
+    id: Uuid,
+    user_id: Uuid,
+    payload: serde_json::Value,
+    status: JobStatus,
+    progress: f32,
+    attempts: i32,
+    run_at: DateTime<Utc>,
+    result: Option<serde_json::Value>,
+    error: Option<String>,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<JobRow> for Job {
+    type Error = AppError;
+
+    fn try_from(row: JobRow) -> Result<Self> {
+        Ok(Self {
+            id: row.id,
+            user_id: row.user_id,
+            job: serde_json::from_value(row.payload)?,
+            status: row.status,
+            progress: row.progress,
+            attempts: row.attempts,
+            run_at: row.run_at.to_rfc3339(),
+            result: row.result,
+            error: row.error,
+            created_at: row.created_at.to_rfc3339(),
+            updated_at: row.updated_at.to_rfc3339(),
+        })
+    }
+}
+
+/// Queues a job for `user_id`, who must be able to see every node it names
+pub async fn enqueue(state: &AppState, user_id: &Uuid, job: JobKind) -> Result<Job> {
+    let user = user_id.to_string();
+    match &job {
+        JobKind::Describe { node_id, .. } | JobKind::Thumbnail { node_id } | JobKind::Quality { node_id } => {
+            tree::require_access(&state.db, Some(&user), node_id).await?;
+        }
+        JobKind::Compare { before, after } => {
+            tree::require_access(&state.db, Some(&user), before).await?;
+            tree::require_access(&state.db, Some(&user), after).await?;
+        }
+    }
+
+    let job: Job = sqlx::query_as!(
+        JobRow,
+        r#"
+        INSERT INTO jobs (user_id, payload) VALUES ($1, $2)
+        RETURNING id, user_id, payload, status as "status: JobStatus", progress, attempts,
+                  run_at, result, error, created_at, updated_at
+        "#,
+        user_id,
+        serde_json::to_value(&job)?
+    )
+    .fetch_one(&state.db)
+    .await?
+    .try_into()?;
+
+    state.jobs.wake();
+    Ok(job)
+}
+
+/// Queues a description of a new image when its tree describes new
+/// images. Runs in the upload's transaction, so the job only exists if the
+/// image does; wake the workers once it commits.
+pub async fn auto_describe(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: &Uuid,
+    parent_id: Option<Uuid>,
+    node_id: &Uuid,
+    language: &str,
+) -> Result<Option<Uuid>> {
+    let Some(parent_id) = parent_id else {
+        return Ok(None);
+    };
+    if !tree::root_settings(tx, &parent_id).await?.auto_describe {
+        return Ok(None);
+    }
+
+    let job = JobKind::Describe {
+        node_id: *node_id,
+        language: Some(language.to_string()),
+    };
+    let id = sqlx::query_scalar!(
+        "INSERT INTO jobs (user_id, payload) VALUES ($1, $2) RETURNING id",
+        user_id,
+        serde_json::to_value(&job)?
+    )
+    .fetch_one(&mut **tx)
+    .await?;
+    Ok(Some(id))
+}
+
+/// A job of `user_id`'s; other users' jobs are not found
+pub async fn get(db: &sqlx::PgPool, user_id: &Uuid, id: &Uuid) -> Result<Job> {
+    sqlx::query_as!(
+        JobRow,
+        r#"
+        SELECT id, user_id, payload, status as "status: JobStatus", progress, attempts,
+               run_at, result, error, created_at, updated_at
+        FROM jobs WHERE id = $1 AND user_id = $2
+        "#,
+        id,
+        user_id
+    )
+    .fetch_optional(db)
+    .await?
+    .ok_or_else(|| AppError::not_found("Job"))?
+    .try_into()
+}
+
+/// `user_id`'s latest jobs, newest first
+pub async fn list(db: &sqlx::PgPool, user_id: &Uuid, status: Option<JobStatus>) -> Result<Vec<Job>> {
+    let rows = sqlx::query_as!(
+        JobRow,
+        r#"
+        SELECT id, user_id, payload, status as "status: JobStatus", progress, attempts,
+               run_at, result, error, created_at, updated_at
+        FROM jobs
+        WHERE user_id = $1 AND ($2::job_status_enum IS NULL OR status = $2)
+        ORDER BY created_at DESC
+        LIMIT $3
+        "#,
+        user_id,
+        status as Option<JobStatus>,
+        tree::QUERY_LIMIT
+    )
+    .fetch_all(db)
+    .await?;
+
+    rows.into_iter().map(Job::try_from).collect()
+}
+
+/// Takes the next due job, or a running one whose worker has gone silent,
+/// without waiting on jobs other workers are claiming
+async fn claim(db: &sqlx::PgPool, stale_after: Duration) -> Result<Option<Job>> {
+    let stale = Utc::now() - stale_after;
+    sqlx::query_as!(
+        JobRow,
+        r#"
+        UPDATE jobs
+        SET status = 'running', attempts = attempts + 1, locked_at = now(), updated_at = now()
+        WHERE id = (
+            SELECT id FROM jobs
+            WHERE (status = 'queued' AND run_at <= now())
+               OR (status = 'running' AND locked_at < $1)
+            ORDER BY run_at
+            LIMIT 1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, user_id, payload, status as "status: JobStatus", progress, attempts,
+                  run_at, result, error, created_at, updated_at
+        "#,
+        stale
+    )
+    .fetch_optional(db)
+    .await?
+    .map(Job::try_from)
+    .transpose()
+}
+
+// ============================================================================
+// Workers
+// ============================================================================
+
+/// Starts `config.workers` workers claiming jobs from the queue
+pub fn spawn(state: Arc<AppState>, config: JobConfig) {
+    if config.workers == 0 {
+        return;
+    }
+    let client = match build_client(&state.ai_config) {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("❌ Job workers not started: {}", e);
+            return;
+        }
+    };
+    let config = Arc::new(config);
+    for _ in 0..config.workers {
+        tokio::spawn(work(state.clone(), client.clone(), config.clone()));
+    }
+}
+
+async fn work(state: Arc<AppState>, client: AiClient, config: Arc<JobConfig>) {
+    loop {
+        match claim(&state.db, config.stale_after).await {
+            Ok(Some(job)) => process(&state, &client, &config, job).await,
+            Ok(None) => {
+                tokio::select! {
+                    _ = state.jobs.wake.notified() => {}
+                    _ = tokio::time::sleep(config.poll_interval) => {}
+                }
+            }
+            Err(e) => {
+                log::error!("❌ Claiming a job failed: {}", e);
+                tokio::time::sleep(config.poll_interval).await;
+            }
+        }
+    }
+}
+
+/// Runs a claimed job and records the outcome. Server-side failures are
+/// retried with backoff; jobs redo finished images cheaply on retry, since
+/// descriptions are cached and renditions reused.
+async fn process(state: &AppState, client: &AiClient, config: &JobConfig, job: Job) {
+    let request_id = job.id.to_string();
+    state.jobs.emit(
+        job.id,
+        StreamEvent::Started {
+            request_id: request_id.clone(),
+            timestamp: Utc::now().timestamp(),
+        },
+    );
+
+    // Events of the work itself go out as they come, ahead of the outcome
+    let (event_tx, mut event_rx) = mpsc::channel(64);
+    let forward = {
+        let jobs_events = state.jobs.events.clone();
+        let job_id = job.id;
+        tokio::spawn(async move {
+            while let Some(event) = event_rx.recv().await {
+                let _ = jobs_events.send((job_id, event));
+            }
+        })
+    };
+    let result = run(state, client, &job, &event_tx).await;
+    drop(event_tx);
+    let _ = forward.await;
+
+    let recorded = match result {
+        Ok(result) => {
+            let recorded = sqlx::query!(
+                r#"
+                UPDATE jobs
+                SET status = 'succeeded', progress = 1, result = $2, error = NULL,
+                    locked_at = NULL, updated_at = now()
+                WHERE id = $1
+                "#,
+                job.id,
+                result
+            )
+            .execute(&state.db)
+            .await;
+            state.jobs.emit(
+                job.id,
+                StreamEvent::Completed {
+                    request_id,
+                    final_result: result.to_string(),
+                    timestamp: Utc::now().timestamp(),
+                },
+            );
+            recorded
+        }
+        Err(e) => {
+            let retry = e.code.is_server_error() && job.attempts as u32 <= config.retry.max_retries;
+            let delay = config.retry.backoff(job.attempts.saturating_sub(1) as u32);
+            log::warn!("Job {} attempt {} failed: {}", job.id, job.attempts, e);
+            let recorded = sqlx::query!(
+                r#"
+                UPDATE jobs
+                SET status = CASE WHEN $2 THEN 'queued' ELSE 'failed' END::job_status_enum,
+                    run_at = CASE WHEN $2 THEN now() + make_interval(secs => $3) ELSE run_at END,
+                    error = $4, locked_at = NULL, updated_at = now()
+                WHERE id = $1
+                "#,
+                job.id,
+                retry,
+                delay.as_secs_f64(),
+                e.message
+            )
+            .execute(&state.db)
+            .await;
+            let error = if retry {
+                format!("{}; retrying in {} s", e.message, delay.as_secs())
+            } else {
+                e.message
+            };
+            state.jobs.emit(
+                job.id,
+                StreamEvent::Error {
+                    request_id,
+                    error,
+                    recoverable: retry,
+                },
+            );
+            recorded
+        }
+    };
+    if let Err(e) = recorded {
+        log::error!("❌ Recording the outcome of job {} failed: {}", job.id, e);
+    }
+}
+
+async fn run(
+    state: &AppState,
+    client: &AiClient,
+    job: &Job,
+    event_tx: &mpsc::Sender<StreamEvent>,
+) -> Result<serde_json::Value> {
+    let request_id = job.id.to_string();
+    let user = job.user_id.to_string();
+    let user = Some(user.as_str());
+
+    match &job.job {
+        JobKind::Compare { before, after } => {
+            let comparison = storage::compare_images(state, user, before, after).await?;
+            let data = serde_json::to_value(&comparison)?;
+            let _ = event_tx
+                .send(StreamEvent::ComparisonChunk {
+                    request_id,
+                    data: data.clone(),
+                })
+                .await;
+            Ok(data)
+        }
+        JobKind::Describe { node_id, language } => {
+            let language = language.as_deref().unwrap_or("en");
+            let images = targets(state, user, node_id).await?;
+            let mut results = Vec::new();
+            for (done, image) in images.iter().enumerate() {
+                let output =
+                    descriptions::describe_image(state, client, &request_id, event_tx, user, image, language).await?;
+                let _ = event_tx
+                    .send(StreamEvent::DescriptionChunk {
+                        request_id: request_id.clone(),
+                        data: json!({ "node_id": image.id, "description": output }),
+                    })
+                    .await;
+                results.push(json!({ "node_id": image.id, "description": output.overview }));
+                report_progress(state, job, done + 1, images.len(), event_tx).await?;
+            }
+            Ok(json!({ "images": results }))
+        }
+        JobKind::Thumbnail { node_id } => {
+            let images = targets(state, user, node_id).await?;
+            for (done, image) in images.iter().enumerate() {
+                if let (Some(hash), Some(path)) = (image.data["hash"].as_str(), image.data["storage_path"].as_str()) {
+                    storage::generate_renditions(state, hash, path).await?;
+                }
+                report_progress(state, job, done + 1, images.len(), event_tx).await?;
+            }
+            Ok(json!({ "images": images.len() }))
+        }
+        JobKind::Quality { node_id } => {
+            let images = targets(state, user, node_id).await?;
+            let mut results = Vec::new();
+            for (done, image) in images.iter().enumerate() {
+                let quality = storage::update_image_quality(state, image).await?;
+                results.push(json!({ "node_id": image.id, "quality": quality }));
+                report_progress(state, job, done + 1, images.len(), event_tx).await?;
+            }
+            Ok(json!({ "images": results }))
+        }
+    }
+}
+
+/// The image `node_id` names, or the images under it
+async fn targets(state: &AppState, user: Option<&str>, node_id: &Uuid) -> Result<Vec<NodeSummary>> {
+    let node = tree::get_node(&state.db, user, node_id).await?;
+    if node.node_type == NodeType::ImageLeaf {
+        return Ok(vec![node]);
+    }
+    tree::get_images(&state.db, user, node_id, None, None).await
+}
+
+async fn report_progress(
+    state: &AppState,
+    job: &Job,
+    done: usize,
+    total: usize,
+    event_tx: &mpsc::Sender<StreamEvent>,
+) -> Result<()> {
+    sqlx::query!(
+        "UPDATE jobs SET progress = $2, locked_at = now(), updated_at = now() WHERE id = $1",
+        job.id,
+        done as f32 / total.max(1) as f32
+    )
+    .execute(&state.db)
+    .await?;
+    let _ = event_tx
+        .send(StreamEvent::CoordinatorThinking {
+            request_id: job.id.to_string(),
+            message: format!("{} of {} images done", done, total),
+        })
+        .await;
+    Ok(())
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_job_payloads() {
+        let job: JobKind = serde_json::from_value(json!({
+            "type": "describe",
+            "node_id": "0192a4a0-0000-7000-8000-000000000001"
+        }))
+        .unwrap();
+        assert!(matches!(job, JobKind::Describe { language: None, .. }));
+
+        let compare = JobKind::Compare {
+            before: Uuid::nil(),
+            after: Uuid::nil(),
+        };
+        assert_eq!(serde_json::to_value(&compare).unwrap()["type"], "compare");
+    }
+
+    #[test]
+    fn test_terminal_events() {
+        let mut job = Job {
+            id: Uuid::now_v7(),
+            user_id: Uuid::now_v7(),
+            job: JobKind::Quality { node_id: Uuid::nil() },
+            status: JobStatus::Running,
+            progress: 0.5,
+            attempts: 1,
+            run_at: String::new(),
+            result: None,
+            error: None,
+            created_at: String::new(),
+            updated_at: String::new(),
+        };
+        assert!(terminal_event(&job).is_none());
+
+        job.status = JobStatus::Failed;
+        job.error = Some("Image not found".to_string());
+        assert!(matches!(
+            terminal_event(&job),
+            Some(StreamEvent::Error { recoverable: false, error, .. }) if error == "Image not found"
+        ));
+    }
+
+    /// A job as a worker has just claimed it, inserted already running so
+    /// workers of other tests don't take it
+    async fn running_job(db: &sqlx::PgPool, user_id: &Uuid, job: JobKind) -> Job {
+        sqlx::query_as!(
+            JobRow,
+            r#"
+            INSERT INTO jobs (user_id, payload, status, attempts, locked_at)
+            VALUES ($1, $2, 'running', 1, now())
+            RETURNING id, user_id, payload, status as "status: JobStatus", progress, attempts,
+                      run_at, result, error, created_at, updated_at
+            "#,
+            user_id,
+            serde_json::to_value(&job).unwrap()
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+        .try_into()
+        .unwrap()
+    }
+
+    async fn cleanup(db: &sqlx::PgPool, user_id: &Uuid, root_id: &Uuid) {
+        sqlx::query!("DELETE FROM jobs WHERE user_id = $1", user_id)
+            .execute(db)
+            .await
+            .unwrap();
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    /// A stored image under a new root of `user_id`; returns the root and leaf
+    async fn image_leaf(state: &AppState, user_id: &Uuid) -> (Uuid, Uuid) {
+        let root_id = crate::init::test_root(&state.db, user_id).await;
+        let storage_path = format!("images/{}.png", Uuid::now_v7());
+        state
+            .storage
+            .upload_blob(&storage_path, bytes::Bytes::from_static(b"png"), "image/png")
+            .await
+            .unwrap();
+        let leaf_id = sqlx::query_scalar!(
+            "INSERT INTO tree_nodes (user_id, parent_id, node_type, data) VALUES ($1, $2, 'ImageLeaf', $3) RETURNING id",
+            user_id,
+            root_id,
+            json!({ "storage_path": storage_path, "mime_type": "image/png" })
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap();
+        (root_id, leaf_id)
+    }
+
+    fn retry_once() -> JobConfig {
+        JobConfig {
+            retry: RetryPolicy {
+                max_retries: 1,
+                initial_backoff: Duration::from_secs(60),
+                max_backoff: Duration::from_secs(60),
+            },
+            ..JobConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_claim_skips_locked_and_reclaims_stale_jobs() {
+        let state = crate::init::test_db_state(crate::AiConfig::default()).await;
+        let db = &state.db;
+        let user_id = Uuid::now_v7();
+        // Due long before any job of another test
+        let id = sqlx::query_scalar!(
+            "INSERT INTO jobs (user_id, payload, run_at) VALUES ($1, $2, '2000-01-01') RETURNING id",
+            user_id,
+            serde_json::to_value(JobKind::Quality { node_id: Uuid::nil() }).unwrap()
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+        let stale_after = Duration::from_secs(60);
+
+        let mut other_worker = db.begin().await.unwrap();
+        sqlx::query!("SELECT id FROM jobs WHERE id = $1 FOR UPDATE", id)
+            .fetch_one(&mut *other_worker)
+            .await
+            .unwrap();
+        let claimed = claim(db, stale_after).await.unwrap();
+        assert_ne!(claimed.map(|job| job.id), Some(id));
+        other_worker.rollback().await.unwrap();
+
+        let job = claim(db, stale_after).await.unwrap().unwrap();
+        assert_eq!((job.id, job.status, job.attempts), (id, JobStatus::Running, 1));
+        let claimed = claim(db, stale_after).await.unwrap();
+        assert_ne!(claimed.map(|job| job.id), Some(id));
+
+        sqlx::query!("UPDATE jobs SET locked_at = now() - interval '2 minutes' WHERE id = $1", id)
+            .execute(db)
+            .await
+            .unwrap();
+        let job = claim(db, stale_after).await.unwrap().unwrap();
+        assert_eq!((job.id, job.status, job.attempts), (id, JobStatus::Running, 2));
+
+        sqlx::query!("DELETE FROM jobs WHERE user_id = $1", user_id)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_described_job_succeeds() {
+        let overview = "Drywall panels in the hallway";
+        let mock = crate::agents::MockLlm::new().on_image(crate::agents::MockReply::text(
+            json!({ "subject": "Hallway", "overview": overview, "sections": [], "confidence": 0.8 }).to_string(),
+        ));
+        let state = crate::init::test_db_state(crate::init::test_config(Arc::new(mock))).await;
+        let client = build_client(&state.ai_config).unwrap();
+        let user_id = Uuid::now_v7();
+        let (root_id, leaf_id) = image_leaf(&state, &user_id).await;
+        let job = running_job(&state.db, &user_id, JobKind::Describe { node_id: leaf_id, language: None }).await;
+        let mut events = state.jobs.subscribe();
+
+        process(&state, &client, &retry_once(), job.clone()).await;
+
+        let job = get(&state.db, &user_id, &job.id).await.unwrap();
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.progress, 1.0);
+        assert_eq!(job.result.unwrap()["images"][0]["description"], overview);
+        let node = tree::get_node(&state.db, Some(&user_id.to_string()), &leaf_id).await.unwrap();
+        assert_eq!(node.data["description"], overview);
+        let mut last = None;
+        while let Ok((_, event)) = events.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(StreamEvent::Completed { .. })));
+
+        cleanup(&state.db, &user_id, &root_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_server_errors_are_retried_until_attempts_run_out() {
+        let mock = crate::agents::MockLlm::new().on_image(crate::agents::MockReply::error(503, "overloaded"));
+        let state = crate::init::test_db_state(crate::init::test_config(Arc::new(mock))).await;
+        let client = build_client(&state.ai_config).unwrap();
+        let config = retry_once();
+        let user_id = Uuid::now_v7();
+        let (root_id, leaf_id) = image_leaf(&state, &user_id).await;
+        let job = running_job(&state.db, &user_id, JobKind::Describe { node_id: leaf_id, language: None }).await;
+
+        process(&state, &client, &config, job.clone()).await;
+        let retried = get(&state.db, &user_id, &job.id).await.unwrap();
+        assert_eq!(retried.status, JobStatus::Queued);
+        assert!(retried.error.is_some());
+        let run_at = DateTime::parse_from_rfc3339(&retried.run_at).unwrap();
+        assert!(run_at > Utc::now() + chrono::Duration::seconds(30));
+
+        // The next claim counts the second attempt, which is the last
+        let job = sqlx::query_as!(
+            JobRow,
+            r#"
+            UPDATE jobs SET status = 'running', attempts = attempts + 1, locked_at = now() WHERE id = $1
+            RETURNING id, user_id, payload, status as "status: JobStatus", progress, attempts,
+                      run_at, result, error, created_at, updated_at
+            "#,
+            job.id
+        )
+        .fetch_one(&state.db)
+        .await
+        .unwrap()
+        .try_into()
+        .unwrap();
+        process(&state, &client, &config, job).await;
+        let failed = get(&state.db, &user_id, &retried.id).await.unwrap();
+        assert_eq!((failed.status, failed.attempts), (JobStatus::Failed, 2));
+        assert_eq!(failed.run_at, retried.run_at);
+
+        cleanup(&state.db, &user_id, &root_id).await;
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_client_errors_fail_without_retrying() {
+        let mock = Arc::new(crate::agents::MockLlm::new());
+        let state = crate::init::test_db_state(crate::init::test_config(mock.clone())).await;
+        let client = build_client(&state.ai_config).unwrap();
+        let user_id = Uuid::now_v7();
+        let root_id = crate::init::test_root(&state.db, &user_id).await;
+        // Gone, or never visible to the user
+        let missing = Uuid::now_v7();
+        let job = running_job(&state.db, &user_id, JobKind::Describe { node_id: missing, language: None }).await;
+        let mut events = state.jobs.subscribe();
+
+        process(&state, &client, &retry_once(), job.clone()).await;
+
+        let job = get(&state.db, &user_id, &job.id).await.unwrap();
+        assert_eq!((job.status, job.attempts), (JobStatus::Failed, 1));
+        assert!(job.error.is_some());
+        assert!(mock.requests().is_empty());
+        let mut last = None;
+        while let Ok((_, event)) = events.try_recv() {
+            last = Some(event);
+        }
+        assert!(matches!(last, Some(StreamEvent::Error { recoverable: false, .. })));
+
+        cleanup(&state.db, &user_id, &root_id).await;
+    }
+}
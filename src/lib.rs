@@ -2,12 +2,26 @@ pub mod agents;
 pub mod error;
 
 pub mod models;
+pub mod annotations;
+pub mod blob_store;
+pub mod descriptions;
+pub mod image_diff;
+pub mod image_import;
+pub mod image_quality;
+pub mod jobs;
+pub mod perceptual_hash;
+pub mod photo_metadata;
+pub mod search;
 pub mod storage;
+pub mod uploads;
+pub mod content;
+pub mod sweeper;
+pub mod tree;
 pub mod handlers;
 pub mod init;
 
 pub use crate::agents::master_agent::MasterAgent;
-pub use crate::storage::{AiConfig, AppState};
+pub use crate::storage::{AgentMode, AiConfig, AiProvider, AiRole, AppState};
 pub use crate::agents::{AgentRequest, AgentContext, CancellationToken, RequestManager};
 pub use crate::agents::{StreamEvent,TaskParameters};
 
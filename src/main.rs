@@ -1,10 +1,21 @@
+use axum::extract::DefaultBodyLimit;
 use axum::{middleware, Router};
 use std::sync::Arc;
 use tower_http::cors::{Any, CorsLayer};
 
-use cx58_agent::handlers::{auth_middleware, chat_stream_handler, get_tree_handler, health_check};
+use cx58_agent::handlers::{
+    auth_middleware, chat_stream_handler, create_annotation_handler, delete_annotation_handler, duplicate_node_handler,
+    enqueue_job_handler, get_annotation_handler, get_job_handler, get_root_settings_handler, get_tree_handler,
+    health_check, job_events_handler, list_annotations_handler, list_jobs_handler, near_duplicates_handler,
+    search_handler, update_annotation_handler, update_root_settings_handler,
+};
 use cx58_agent::init::app_init;
-use cx58_agent::storage::{batch_upload_handler, delete_image_handler, get_image_handler, upload_image_handler};
+use cx58_agent::init::StorageConfig;
+use cx58_agent::content::{get_image_content_handler, get_image_handler, local_file_handler, local_upload_handler};
+use cx58_agent::storage::{compare_images_handler, delete_image_handler};
+use cx58_agent::uploads::{
+    batch_upload_handler, finalize_upload_handler, import_image_handler, presign_upload_handler, upload_image_handler,
+};
 use cx58_agent::AppState;
 
 fn create_app_router(state: Arc<AppState>) -> Router {
@@ -14,24 +25,107 @@ fn create_app_router(state: Arc<AppState>) -> Router {
             axum::routing::post(chat_stream_handler),
         )
         .route(
-            "/api/agent/tree/:user_id/:root_id",
+            "/api/agent/tree/{user_id}/{root_id}",
             axum::routing::get(get_tree_handler),
         )
+        .route(
+            "/api/tree/{node_id}/duplicate",
+            axum::routing::post(duplicate_node_handler),
+        )
+        .route(
+            "/api/tree/{node_id}/near-duplicates",
+            axum::routing::get(near_duplicates_handler),
+        )
+        .route(
+            "/api/tree/{node_id}/settings",
+            axum::routing::get(get_root_settings_handler),
+        )
+        .route(
+            "/api/tree/{node_id}/settings",
+            axum::routing::put(update_root_settings_handler),
+        )
+        .route(
+            "/api/search",
+            axum::routing::get(search_handler),
+        )
+        .route(
+            "/api/jobs",
+            axum::routing::post(enqueue_job_handler),
+        )
+        .route(
+            "/api/jobs",
+            axum::routing::get(list_jobs_handler),
+        )
+        .route(
+            "/api/jobs/{job_id}",
+            axum::routing::get(get_job_handler),
+        )
+        .route(
+            "/api/jobs/{job_id}/events",
+            axum::routing::get(job_events_handler),
+        )
         .route(
             "/api/images/upload",
-            axum::routing::post(upload_image_handler),
+            // Uploads stream and enforce their own per-image limit
+            axum::routing::post(upload_image_handler).layer(DefaultBodyLimit::disable()),
         )
         .route(
-            "/api/images/:node_id",
+            "/api/images/{node_id}",
             axum::routing::get(get_image_handler),
         )
         .route(
-            "/api/images/:node_id",
+            "/api/images/{node_id}",
             axum::routing::delete(delete_image_handler),
         )
+        .route(
+            "/api/images/{node_id}/annotations",
+            axum::routing::get(list_annotations_handler),
+        )
+        .route(
+            "/api/images/{node_id}/annotations",
+            axum::routing::post(create_annotation_handler),
+        )
+        .route(
+            "/api/images/{node_id}/annotations/{annotation_id}",
+            axum::routing::get(get_annotation_handler),
+        )
+        .route(
+            "/api/images/{node_id}/annotations/{annotation_id}",
+            axum::routing::patch(update_annotation_handler),
+        )
+        .route(
+            "/api/images/{node_id}/annotations/{annotation_id}",
+            axum::routing::delete(delete_annotation_handler),
+        )
+        .route(
+            "/api/images/{node_id}/content",
+            axum::routing::get(get_image_content_handler),
+        )
         .route(
             "/api/images/batch",
-            axum::routing::post(batch_upload_handler),
+            axum::routing::post(batch_upload_handler).layer(DefaultBodyLimit::disable()),
+        )
+        .route(
+            "/api/images/compare",
+            axum::routing::post(compare_images_handler),
+        )
+        .route(
+            "/api/images/import",
+            axum::routing::post(import_image_handler),
+        )
+        .route(
+            "/api/images/uploads",
+            axum::routing::post(presign_upload_handler),
+        )
+        .route(
+            "/api/images/uploads/{upload_id}/finalize",
+            axum::routing::post(finalize_upload_handler),
+        )
+        .route(
+            "/files/{*path}",
+            axum::routing::get(local_file_handler)
+                .put(local_upload_handler)
+                .layer(DefaultBodyLimit::disable()),
         )
         .route("/health", axum::routing::get(health_check))
         .layer(middleware::from_fn(auth_middleware))
@@ -65,12 +159,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     log::info!("🖼️  Upload: http://{}/api/images/upload", addr);
     log::info!("❤️  Health: http://{}/health", addr);
     log::info!("");
-    log::info!("💾 S3: {}", config.s3.bucket);
-    log::info!("🌍 Region: {}", config.s3.region);
-    if let Some(ep) = &config.s3.endpoint {
-        log::info!("🔌 Endpoint: {}", ep);
+    match &config.storage {
+        StorageConfig::S3(s3) => {
+            log::info!("💾 S3: {}", s3.bucket);
+            log::info!("🌍 Region: {}", s3.region);
+            if let Some(ep) = &s3.endpoint {
+                log::info!("🔌 Endpoint: {}", ep);
+            }
+            log::info!("🔗 CDN: {}", s3.public_url_base);
+        }
+        StorageConfig::Local(local) => {
+            log::info!("💾 Local storage: {}", local.root);
+            log::info!("🔗 Files: {}/files/", local.public_url_base);
+        }
     }
-    log::info!("🔗 CDN: {}", config.s3.public_url_base);
     log::info!("⚡ rust-s3 + Ollama (NO embeddings, NO Qdrant)");
     log::info!("");
 
@@ -1,4 +1,8 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use crate::error::AppError;
+use crate::image_quality::{ImageQuality, QualityWarning};
+use crate::photo_metadata::PhotoMetadata;
 use uuid::Uuid;
 
 use sqlx::Type;
@@ -28,6 +32,8 @@ pub enum NodeType {
 pub enum NodeData {
     Root {
         title: String,
+        #[serde(default)]
+        settings: RootSettings,
     },
     Branch {
         label: String,
@@ -45,9 +51,114 @@ pub enum NodeData {
         hash: Option<String>,
         #[serde(skip_serializing_if = "Option::is_none")]
         description: Option<String>,
+        /// Downscaled copies by rendition name, filled in after upload
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        renditions: BTreeMap<String, Rendition>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        exif: Option<Box<PhotoMetadata>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        quality: Option<ImageQuality>,
+        /// Regions marked on the image, oldest first; kept in their own
+        /// table and filled in when the node is loaded
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        annotations: Vec<Annotation>,
     },
 }
 
+/// Options of an object tree, kept in its root's data
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct RootSettings {
+    /// Queue a vision description of every new image, in the uploader's
+    /// language
+    #[serde(default)]
+    pub auto_describe: bool,
+}
+
+/// A downscaled copy of an image, e.g. `thumb` or `preview`
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Rendition {
+    pub url: String,
+    pub storage_path: String,
+    pub mime_type: String,
+    /// Longest side in pixels the image was scaled to fit
+    pub max_size: u32,
+}
+
+/// Region of an image, in coordinates normalised to 0..1 of its width
+/// and height with the origin top left
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnnotationShape {
+    Rectangle { x: f32, y: f32, width: f32, height: f32 },
+    /// Corners as `[x, y]`, at least three
+    Polygon { points: Vec<[f32; 2]> },
+}
+
+impl AnnotationShape {
+    pub fn validate(&self) -> Result<(), AppError> {
+        let inside = |v: f32| (0.0..=1.0).contains(&v);
+        match self {
+            Self::Rectangle { x, y, width, height } => {
+                if !(inside(*x) && inside(*y) && *width > 0.0 && *height > 0.0)
+                    || x + width > 1.0
+                    || y + height > 1.0
+                {
+                    return Err(AppError::validation("Rectangle must lie within the image"));
+                }
+            }
+            Self::Polygon { points } => {
+                if points.len() < 3 {
+                    return Err(AppError::validation("Polygon needs at least three points"));
+                }
+                if !points.iter().flatten().all(|v| inside(*v)) {
+                    return Err(AppError::validation("Polygon must lie within the image"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Bounding box as normalised `(x, y, width, height)`
+    pub fn bounds(&self) -> (f32, f32, f32, f32) {
+        match self {
+            Self::Rectangle { x, y, width, height } => (*x, *y, *width, *height),
+            Self::Polygon { points } => {
+                let (mut left, mut top, mut right, mut bottom) = (1.0f32, 1.0f32, 0.0f32, 0.0f32);
+                for [x, y] in points {
+                    left = left.min(*x);
+                    top = top.min(*y);
+                    right = right.max(*x);
+                    bottom = bottom.max(*y);
+                }
+                (left, top, (right - left).max(0.0), (bottom - top).max(0.0))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "annotation_status_enum", rename_all = "snake_case")]
+pub enum AnnotationStatus {
+    Open,
+    Resolved,
+}
+
+/// A region marked on an image, e.g. a defect to follow up on
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Annotation {
+    pub id: Uuid,
+    pub node_id: Uuid,
+    pub shape: AnnotationShape,
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    pub author_id: Uuid,
+    pub status: AnnotationStatus,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
 impl TreeNode {
     pub fn is_leaf(&self) -> bool {
         matches!(self.node_type, NodeType::ImageLeaf)
@@ -89,6 +200,17 @@ impl TreeNode {
     }
 }
 
+/// Flat view of a single tree node, without children
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct NodeSummary {
+    pub id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub name: Option<String>,
+    pub node_type: NodeType,
+    /// Raw node data; seeded branches don't always match [`NodeData`]
+    pub data: serde_json::Value,
+    pub created_at: String,
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct StorageResult {
@@ -105,6 +227,221 @@ pub struct UploadResponse {
     pub url: String,
     pub storage_path: String,
     pub size: u64,
+    /// True when an identical image already existed and was returned instead
+    #[serde(default)]
+    pub duplicate: bool,
+    /// Quality problems found in the image, e.g. blur or bad exposure
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<QualityWarning>,
+    /// Job describing the image, when its root describes new images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub describe_job: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BatchItemStatus {
+    Created,
+    /// An identical image already existed and was returned instead
+    Duplicate,
+    Failed,
+    /// Succeeded, but undone because another file failed in atomic mode
+    RolledBack,
+    /// Not attempted because an earlier file failed in atomic mode
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchUploadItem {
+    pub filename: String,
+    pub status: BatchItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub warnings: Vec<QualityWarning>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub describe_job: Option<Uuid>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<AppError>,
+}
+
+impl BatchUploadItem {
+    pub fn new(filename: String, status: BatchItemStatus) -> Self {
+        Self {
+            filename,
+            status,
+            node_id: None,
+            url: None,
+            warnings: Vec::new(),
+            describe_job: None,
+            error: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchUploadResponse {
+    /// False when an atomic batch was rolled back
+    pub committed: bool,
+    /// One entry per file, in upload order
+    pub items: Vec<BatchUploadItem>,
+}
+
+/// Request for a direct-to-storage upload into `parent_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresignUploadRequest {
+    pub parent_id: Uuid,
+    pub filename: String,
+    /// Declared size in bytes, checked on finalize
+    pub size: Option<u64>,
+    /// Declared SHA-256 as hex, checked on finalize
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresignedUpload {
+    pub upload_id: Uuid,
+    /// URL to `PUT` the image to
+    pub url: String,
+    /// `Content-Type` the `PUT` must be sent with
+    pub content_type: String,
+    pub expires_at: String,
+}
+
+/// Images whose perceptual hashes are close, e.g. repeated shots of one wall
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NearDuplicateCluster {
+    /// Oldest first
+    pub images: Vec<NodeSummary>,
+}
+
+/// A node matching a full-text search
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub node: NodeSummary,
+    pub rank: f32,
+    /// Matching passages as escaped HTML, with the matched words in `<mark>`
+    /// tags
+    pub highlight: String,
+}
+
+/// Request to compare two photos of the same spot pixel by pixel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompareImagesRequest {
+    /// Id of the earlier image
+    pub before: Uuid,
+    /// Id of the later image
+    pub after: Uuid,
+}
+
+/// How much changed between two photos, with the changes highlighted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageComparison {
+    pub before: Uuid,
+    pub after: Uuid,
+    /// Share of the overlapping area that changed, 0–100
+    pub changed_percent: f32,
+    /// The later photo with changed areas tinted yellow to red
+    pub overlay_url: String,
+    /// Shift of the later photo against the earlier one, in pixels of the
+    /// downscaled copies compared
+    pub offset: (i32, i32),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CreateAnnotationRequest {
+    pub shape: AnnotationShape,
+    pub label: String,
+    pub note: Option<String>,
+}
+
+/// Changes to an annotation; fields left out stay as they are
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpdateAnnotationRequest {
+    pub shape: Option<AnnotationShape>,
+    pub label: Option<String>,
+    pub note: Option<String>,
+    pub status: Option<AnnotationStatus>,
+}
+
+/// Request to import an image from a URL into `parent_id`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportImageRequest {
+    pub parent_id: Uuid,
+    pub url: String,
+}
+
+/// Request to copy a Branch and its subtree
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateNodeRequest {
+    /// Where to put the copy; next to the source when omitted
+    pub parent_id: Option<Uuid>,
+    /// Label for the copy, e.g. `Floor 3`; the source's label when omitted
+    pub label: Option<String>,
+    /// Copy image leaves too, not just the branch structure
+    #[serde(default)]
+    pub include_images: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DuplicateNodeResponse {
+    pub node_id: Uuid,
+    /// Nodes created, the copy itself included
+    pub nodes: usize,
+    pub images: usize,
+}
+
+/// Work for the background job queue. Jobs on a branch cover the images
+/// under it, up to the tree query limit.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum JobKind {
+    /// Vision description; the request's `X-Language` when no language is given
+    Describe {
+        node_id: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        language: Option<String>,
+    },
+    /// Pixel comparison of two images, see [`ImageComparison`]
+    Compare { before: Uuid, after: Uuid },
+    /// Missing renditions and perceptual hashes
+    Thumbnail { node_id: Uuid },
+    /// Blur, exposure and noise analysis
+    Quality { node_id: Uuid },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(type_name = "job_status_enum", rename_all = "snake_case")]
+pub enum JobStatus {
+    /// Waiting for a worker, including between retries
+    Queued,
+    Running,
+    Succeeded,
+    /// Gave up, after the last retry or on an error retrying can't fix
+    Failed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub job: JobKind,
+    pub status: JobStatus,
+    /// Share of the work done, 0–1
+    pub progress: f32,
+    pub attempts: i32,
+    /// When a queued job is due, later than creation while backing off
+    pub run_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// Error of the last attempt
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -117,9 +454,15 @@ pub struct ImageMetadata {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ImageDescription {
     pub id: Uuid,
-    pub node_id: Uuid,
+    /// Node the description was first made for, if it still exists
+    pub node_id: Option<Uuid>,
+    /// Content hash of the image described
+    pub image_hash: Option<String>,
     pub model_name: String,
+    /// Digest of the model at the time, empty when unknown
+    pub model_version: String,
     pub prompt: String,
+    pub language: Option<String>,
     pub description: String,
     pub confidence: Option<f32>,
     pub created_at: String,
@@ -178,6 +521,10 @@ mod tests {
                 mime_type: None,
                 hash: None,
                 description: None,
+                renditions: BTreeMap::new(),
+                exif: None,
+                quality: None,
+                annotations: vec![],
             },
             children: vec![],
             created_at: "2024-01-01T00:00:00Z".to_string(),
@@ -186,4 +533,34 @@ mod tests {
         assert_eq!(leaf.depth(), 0);
         assert!(leaf.is_leaf());
     }
+
+    #[test]
+    fn test_annotation_shape() {
+        let shape: AnnotationShape = serde_json::from_value(serde_json::json!({
+            "type": "polygon",
+            "points": [[0.2, 0.5], [0.6, 0.1], [0.4, 0.9]]
+        }))
+        .unwrap();
+        assert!(shape.validate().is_ok());
+        let (x, y, width, height) = shape.bounds();
+        assert_eq!((x, y), (0.2, 0.1));
+        assert!((width - 0.4).abs() < 1e-6 && (height - 0.8).abs() < 1e-6);
+
+        let outside = AnnotationShape::Rectangle { x: 0.8, y: 0.1, width: 0.3, height: 0.2 };
+        assert!(outside.validate().is_err());
+        let line = AnnotationShape::Polygon { points: vec![[0.1, 0.1], [0.2, 0.2]] };
+        assert!(line.validate().is_err());
+    }
+
+    #[test]
+    fn test_root_settings_default_off() {
+        // Roots created before settings existed describe nothing on upload
+        let data: NodeData = serde_json::from_value(serde_json::json!({ "title": "Site" })).unwrap();
+        assert!(matches!(data, NodeData::Root { settings: RootSettings { auto_describe: false }, .. }));
+
+        let data: NodeData =
+            serde_json::from_value(serde_json::json!({ "title": "Site", "settings": { "auto_describe": true } }))
+                .unwrap();
+        assert!(matches!(data, NodeData::Root { settings: RootSettings { auto_describe: true }, .. }));
+    }
 }
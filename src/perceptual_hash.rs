@@ -0,0 +1,145 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use uuid::Uuid;
+
+// ============================================================================
+// Difference hash
+// ============================================================================
+
+/// 64-bit difference hash (dHash): one bit per horizontally adjacent pixel
+/// pair of a 9×8 grayscale thumbnail, set when brightness increases to the
+/// right. Robust to rescaling, recompression and small exposure changes,
+/// so near-identical shots end up a few bits apart.
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] < small.get_pixel(x + 1, y)[0];
+            hash = (hash << 1) | u64::from(brighter);
+        }
+    }
+    hash
+}
+
+/// Number of differing bits, 0 (identical) to 64
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Hashes are stored in a signed `BIGINT` column
+pub fn to_db(hash: u64) -> i64 {
+    hash as i64
+}
+
+pub fn from_db(hash: i64) -> u64 {
+    hash as u64
+}
+
+// ============================================================================
+// Clustering
+// ============================================================================
+
+/// Groups images whose hashes are at most `max_distance` apart, directly or
+/// through a chain of other images. Only groups of two or more are
+/// returned, each in input order, ordered by their first member.
+pub fn clusters(images: &[(Uuid, u64)], max_distance: u32) -> Vec<Vec<Uuid>> {
+    // Union-find over indices
+    let mut parent: Vec<usize> = (0..images.len()).collect();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+
+    for i in 0..images.len() {
+        for j in i + 1..images.len() {
+            if distance(images[i].1, images[j].1) <= max_distance {
+                let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut groups: Vec<(usize, Vec<Uuid>)> = Vec::new();
+    for (i, (id, _)) in images.iter().enumerate() {
+        let r = root(&mut parent, i);
+        match groups.iter_mut().find(|(group, _)| *group == r) {
+            Some((_, members)) => members.push(*id),
+            None => groups.push((r, vec![*id])),
+        }
+    }
+    groups
+        .into_iter()
+        .map(|(_, members)| members)
+        .filter(|members| members.len() > 1)
+        .collect()
+}
+
+/// Keeps each image unless it is within `max_distance` of one already
+/// kept, so the first of every run of near-identical shots survives
+pub fn distinct<T>(images: Vec<(T, Option<u64>)>, max_distance: u32) -> Vec<T> {
+    let mut kept: Vec<u64> = Vec::new();
+    images
+        .into_iter()
+        .filter_map(|(image, hash)| match hash {
+            Some(hash) if kept.iter().any(|&k| distance(k, hash) <= max_distance) => None,
+            Some(hash) => {
+                kept.push(hash);
+                Some(image)
+            }
+            // Not hashed yet; can't tell, so keep it
+            None => Some(image),
+        })
+        .collect()
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn gradient(width: u32, height: u32, shift: u8) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let value = ((x * 255 / width) as u8).wrapping_add(shift) ^ ((y * 64 / height) as u8);
+            Rgb([value, value, value])
+        }))
+    }
+
+    #[test]
+    fn test_dhash_is_stable_across_scale_and_exposure() {
+        let original = dhash(&gradient(640, 480, 0));
+        let rescaled = dhash(&gradient(320, 240, 0));
+        let brighter = dhash(&gradient(640, 480, 3));
+        let flipped = dhash(&gradient(640, 480, 0).fliph());
+
+        assert!(distance(original, rescaled) <= 4);
+        assert!(distance(original, brighter) <= 8);
+        assert!(distance(original, flipped) > 20);
+        assert_eq!(from_db(to_db(u64::MAX)), u64::MAX);
+    }
+
+    #[test]
+    fn test_clusters_and_distinct() {
+        let ids: Vec<Uuid> = (0..4).map(|_| Uuid::now_v7()).collect();
+        let images = vec![
+            (ids[0], 0b0000),
+            (ids[1], u64::MAX),
+            (ids[2], 0b0011),
+            (ids[3], 0b0111),
+        ];
+
+        // 0 and 3 are three bits apart, but chained through 2
+        assert_eq!(clusters(&images, 2), vec![vec![ids[0], ids[2], ids[3]]]);
+        assert!(clusters(&images, 0).is_empty());
+
+        let hashed = images.iter().map(|&(id, hash)| (id, Some(hash))).collect();
+        assert_eq!(distinct(hashed, 2), vec![ids[0], ids[1], ids[3]]);
+    }
+}
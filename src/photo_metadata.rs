@@ -0,0 +1,302 @@
+use chrono::{DateTime, FixedOffset, NaiveDate, TimeZone, Utc};
+use exif::{Exif, In, Tag, Value};
+use serde::{Deserialize, Serialize};
+
+// ============================================================================
+// EXIF metadata
+// ============================================================================
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct GpsPosition {
+    pub latitude: f64,
+    pub longitude: f64,
+    /// Metres above sea level
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub altitude: Option<f64>,
+}
+
+/// What a photo's EXIF says about where, when and with what it was taken
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct PhotoMetadata {
+    /// `DateTimeOriginal`; taken as UTC when the camera recorded no offset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub captured_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_make: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub camera_model: Option<String>,
+    /// EXIF orientation, 1 (upright) to 8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub orientation: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gps: Option<GpsPosition>,
+}
+
+impl PhotoMetadata {
+    /// Reads EXIF from JPEG, PNG, WebP, TIFF or HEIF data. The data only has
+    /// to reach past the EXIF block, so the head of an upload is enough for
+    /// JPEGs. Images without readable EXIF yield empty metadata.
+    pub fn read(data: &[u8]) -> Self {
+        match exif::Reader::new().read_from_container(&mut std::io::Cursor::new(data)) {
+            Ok(exif) => Self::from_exif(&exif),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    fn from_exif(exif: &Exif) -> Self {
+        Self {
+            captured_at: captured_at(exif),
+            camera_make: ascii(exif, Tag::Make),
+            camera_model: ascii(exif, Tag::Model),
+            orientation: exif
+                .get_field(Tag::Orientation, In::PRIMARY)
+                .and_then(|f| f.value.get_uint(0)),
+            gps: gps(exif),
+        }
+    }
+}
+
+fn ascii(exif: &Exif, tag: Tag) -> Option<String> {
+    match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Ascii(values) => {
+            let text = String::from_utf8_lossy(values.first()?);
+            let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!text.is_empty()).then(|| text.to_string())
+        }
+        _ => None,
+    }
+}
+
+fn captured_at(exif: &Exif) -> Option<DateTime<Utc>> {
+    let (time_tag, offset_tag) = if exif.get_field(Tag::DateTimeOriginal, In::PRIMARY).is_some() {
+        (Tag::DateTimeOriginal, Tag::OffsetTimeOriginal)
+    } else {
+        (Tag::DateTime, Tag::OffsetTime)
+    };
+
+    let mut time = exif::DateTime::from_ascii(ascii(exif, time_tag)?.as_bytes()).ok()?;
+    if let Some(offset) = ascii(exif, offset_tag) {
+        let _ = time.parse_offset(offset.as_bytes());
+    }
+
+    let local = NaiveDate::from_ymd_opt(time.year.into(), time.month.into(), time.day.into())?
+        .and_hms_opt(time.hour.into(), time.minute.into(), time.second.into())?;
+    match time.offset {
+        Some(minutes) => FixedOffset::east_opt(i32::from(minutes) * 60)?
+            .from_local_datetime(&local)
+            .single()
+            .map(|t| t.with_timezone(&Utc)),
+        None => Some(local.and_utc()),
+    }
+}
+
+fn gps(exif: &Exif) -> Option<GpsPosition> {
+    let latitude = coordinate(exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, 'S')?;
+    let longitude = coordinate(exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, 'W')?;
+    let altitude = match &exif.get_field(Tag::GPSAltitude, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Rational(values)) => values.first().map(|r| {
+            let below_sea_level = exif
+                .get_field(Tag::GPSAltitudeRef, In::PRIMARY)
+                .and_then(|f| f.value.get_uint(0))
+                == Some(1);
+            if below_sea_level { -r.to_f64() } else { r.to_f64() }
+        }),
+        _ => None,
+    };
+
+    Some(GpsPosition {
+        latitude,
+        longitude,
+        altitude,
+    })
+}
+
+/// Degrees/minutes/seconds to signed decimal degrees
+fn coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative: char) -> Option<f64> {
+    let Value::Rational(dms) = &exif.get_field(tag, In::PRIMARY)?.value else {
+        return None;
+    };
+    let [degrees, minutes, seconds] = dms.get(..3)? else {
+        return None;
+    };
+    let value = degrees.to_f64() + minutes.to_f64() / 60.0 + seconds.to_f64() / 3600.0;
+    if !value.is_finite() {
+        return None;
+    }
+    let is_negative = ascii(exif, ref_tag).is_some_and(|r| r.starts_with(negative));
+    Some(if is_negative { -value } else { value })
+}
+
+// ============================================================================
+// GPS stripping
+// ============================================================================
+
+const EXIF_HEADER: &[u8] = b"Exif\0\0";
+
+/// Rewrites the EXIF segment of a JPEG without its GPS tags, keeping
+/// everything else (orientation in particular). `data` may be just the
+/// head of the file as long as it contains the whole segment. Returns
+/// `None` when the data isn't a JPEG, has no GPS tags or the segment
+/// can't be rewritten.
+pub fn strip_gps_jpeg(data: &[u8]) -> Option<Vec<u8>> {
+    let (start, end) = find_exif_segment(data)?;
+    let exif = exif::Reader::new()
+        .read_raw(data[start + 4 + EXIF_HEADER.len()..end].to_vec())
+        .ok()?;
+    let has_gps = exif.fields().any(|f| matches!(f.tag, Tag(exif::Context::Gps, _)));
+    if !has_gps {
+        return None;
+    }
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in exif.fields() {
+        let keep = field.ifd_num == In::PRIMARY
+            && !matches!(field.tag, Tag(exif::Context::Gps, _))
+            && !matches!(field.value, Value::Unknown(..));
+        if keep {
+            writer.push_field(field);
+        }
+    }
+    let mut tiff = std::io::Cursor::new(Vec::new());
+    writer.write(&mut tiff, exif.little_endian()).ok()?;
+    let tiff = tiff.into_inner();
+
+    let length = u16::try_from(2 + EXIF_HEADER.len() + tiff.len()).ok()?;
+    let mut stripped = Vec::with_capacity(data.len());
+    stripped.extend_from_slice(&data[..start]);
+    stripped.extend_from_slice(&[0xFF, 0xE1]);
+    stripped.extend_from_slice(&length.to_be_bytes());
+    stripped.extend_from_slice(EXIF_HEADER);
+    stripped.extend_from_slice(&tiff);
+    stripped.extend_from_slice(&data[end..]);
+    Some(stripped)
+}
+
+/// Byte range of the APP1 Exif segment, marker included
+fn find_exif_segment(data: &[u8]) -> Option<(usize, usize)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+    let mut pos = 2;
+    while pos + 4 <= data.len() {
+        if data[pos] != 0xFF {
+            return None;
+        }
+        let marker = data[pos + 1];
+        // Image data starts at SOS; metadata always comes before it
+        if marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let length = usize::from(u16::from_be_bytes([data[pos + 2], data[pos + 3]]));
+        let end = pos + 2 + length;
+        if end > data.len() {
+            return None;
+        }
+        if marker == 0xE1 && data[pos + 4..end].starts_with(EXIF_HEADER) {
+            return Some((pos, end));
+        }
+        pos = end;
+    }
+    None
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exif::{Field, Rational};
+
+    fn rational(n: u32) -> Rational {
+        Rational { num: n, denom: 1 }
+    }
+
+    fn ascii_field(tag: Tag, text: &str) -> Field {
+        Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value: Value::Ascii(vec![text.as_bytes().to_vec()]),
+        }
+    }
+
+    /// A minimal JPEG: SOI, an EXIF segment with the given fields, EOI
+    fn jpeg_with_exif(fields: &[Field]) -> Vec<u8> {
+        let mut writer = exif::experimental::Writer::new();
+        for field in fields {
+            writer.push_field(field);
+        }
+        let mut tiff = std::io::Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+        let tiff = tiff.into_inner();
+
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        jpeg.extend_from_slice(&((2 + EXIF_HEADER.len() + tiff.len()) as u16).to_be_bytes());
+        jpeg.extend_from_slice(EXIF_HEADER);
+        jpeg.extend_from_slice(&tiff);
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn site_photo() -> Vec<u8> {
+        jpeg_with_exif(&[
+            ascii_field(Tag::Make, "Canon"),
+            ascii_field(Tag::Model, "EOS R6"),
+            Field {
+                tag: Tag::Orientation,
+                ifd_num: In::PRIMARY,
+                value: Value::Short(vec![6]),
+            },
+            ascii_field(Tag::DateTimeOriginal, "2025:03:14 09:30:00"),
+            ascii_field(Tag::OffsetTimeOriginal, "+02:00"),
+            ascii_field(Tag::GPSLatitudeRef, "N"),
+            Field {
+                tag: Tag::GPSLatitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![rational(52), rational(30), rational(36)]),
+            },
+            ascii_field(Tag::GPSLongitudeRef, "W"),
+            Field {
+                tag: Tag::GPSLongitude,
+                ifd_num: In::PRIMARY,
+                value: Value::Rational(vec![rational(13), rational(15), rational(0)]),
+            },
+        ])
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        let metadata = PhotoMetadata::read(&site_photo());
+        assert_eq!(metadata.camera_make.as_deref(), Some("Canon"));
+        assert_eq!(metadata.camera_model.as_deref(), Some("EOS R6"));
+        assert_eq!(metadata.orientation, Some(6));
+        assert_eq!(
+            metadata.captured_at.unwrap().to_rfc3339(),
+            "2025-03-14T07:30:00+00:00"
+        );
+        let gps = metadata.gps.unwrap();
+        assert!((gps.latitude - 52.51).abs() < 1e-9);
+        assert!((gps.longitude + 13.25).abs() < 1e-9);
+
+        assert!(PhotoMetadata::read(b"not an image").is_empty());
+    }
+
+    #[test]
+    fn test_strip_gps_keeps_other_tags() {
+        let photo = site_photo();
+        let stripped = strip_gps_jpeg(&photo).unwrap();
+
+        let metadata = PhotoMetadata::read(&stripped);
+        assert_eq!(metadata.gps, None);
+        assert_eq!(metadata.orientation, Some(6));
+        assert_eq!(metadata.camera_model.as_deref(), Some("EOS R6"));
+        assert!(stripped.ends_with(&[0xFF, 0xD9]));
+
+        assert_eq!(strip_gps_jpeg(&stripped), None);
+    }
+}
@@ -0,0 +1,241 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+use crate::error::{AppError, Result};
+use crate::models::{NodeSummary, NodeType, SearchHit};
+use crate::tree::QUERY_LIMIT;
+
+// ============================================================================
+// Queries
+// ============================================================================
+
+/// Control characters `ts_headline` puts around matches. Names and
+/// descriptions are raw text, so the passage is HTML-escaped before these
+/// become `<mark>` tags; they are removed from the text beforehand.
+const MATCH_START: char = '\u{2}';
+const MATCH_END: char = '\u{3}';
+
+/// How `ts_headline` marks matches; fragments are joined with an ellipsis
+const HIGHLIGHT_OPTIONS: &str = "StartSel=\u{2}, StopSel=\u{3}, MaxFragments=2, MaxWords=20, MinWords=5, \
+                                 FragmentDelimiter=\" … \"";
+
+struct HitRow {
+    id: Uuid,
+    parent_id: Option<Uuid>,
+    name: Option<String>,
+    node_type: NodeType,
+    data: serde_json::Value,
+    created_at: DateTime<Utc>,
+    rank: f32,
+    highlight: String,
+}
+
+impl From<HitRow> for SearchHit {
+    fn from(row: HitRow) -> Self {
+        Self {
+            node: NodeSummary {
+                id: row.id,
+                parent_id: row.parent_id,
+                name: row.name,
+                node_type: row.node_type,
+                data: row.data,
+                created_at: row.created_at.to_rfc3339(),
+            },
+            rank: row.rank,
+            highlight: highlight_html(&row.highlight),
+        }
+    }
+}
+
+/// Escapes a passage for HTML and turns the match markers into `<mark>` tags
+fn highlight_html(passage: &str) -> String {
+    let mut html = String::with_capacity(passage.len());
+    for c in passage.chars() {
+        match c {
+            MATCH_START => html.push_str("<mark>"),
+            MATCH_END => html.push_str("</mark>"),
+            '&' => html.push_str("&amp;"),
+            '<' => html.push_str("&lt;"),
+            '>' => html.push_str("&gt;"),
+            '"' => html.push_str("&quot;"),
+            '\'' => html.push_str("&#39;"),
+            c => html.push(c),
+        }
+    }
+    html
+}
+
+/// Full-text search over node names, root titles, branch labels and
+/// descriptions, and image descriptions, within every subtree visible to
+/// `user`. `query` takes web search syntax, e.g. `drywall -ceiling` or
+/// `"water damage"`, and is stemmed in each node's own language. Images
+/// also match on cached descriptions in other languages. The indexes are
+/// searched with the query in every language at once, and the candidates
+/// then checked in their own.
+pub async fn search(db: &sqlx::PgPool, user: Option<&str>, query: &str) -> Result<Vec<SearchHit>> {
+    let user = user.ok_or_else(|| AppError::unauthorized("User is not identified"))?;
+    if query.trim().is_empty() {
+        return Err(AppError::bad_request("Search query must not be empty"));
+    }
+
+    let rows = sqlx::query_as!(
+        HitRow,
+        r#"
+        WITH RECURSIVE visible AS (
+            SELECT id FROM tree_nodes WHERE user_id::text = $1
+            UNION
+            SELECT node_id FROM node_access WHERE user_id = $1
+            UNION
+            SELECT t.id FROM tree_nodes t INNER JOIN visible v ON t.parent_id = v.id
+        ),
+        matches AS (
+            SELECT t.id,
+                   search_config(t.language) AS config,
+                   concat_ws(' ', t.name, t.data->>'title', t.data->>'label', t.data->>'description') AS document,
+                   ts_rank(t.search_vector, websearch_to_tsquery(search_config(t.language), $2)) AS rank
+            FROM tree_nodes t
+            INNER JOIN visible v ON v.id = t.id
+            WHERE t.search_vector @@ search_query_any($2)
+              AND t.search_vector @@ websearch_to_tsquery(search_config(t.language), $2)
+            UNION ALL
+            SELECT t.id,
+                   search_config(d.language),
+                   d.description,
+                   ts_rank(to_tsvector(search_config(d.language), d.description),
+                           websearch_to_tsquery(search_config(d.language), $2))
+            FROM tree_nodes t
+            INNER JOIN visible v ON v.id = t.id
+            INNER JOIN image_descriptions d ON d.image_hash = t.data->>'hash'
+            WHERE t.node_type = 'ImageLeaf' AND d.image_hash IS NOT NULL
+              AND to_tsvector(search_config(d.language), d.description) @@ search_query_any($2)
+              AND to_tsvector(search_config(d.language), d.description)
+                  @@ websearch_to_tsquery(search_config(d.language), $2)
+        ),
+        best AS (
+            SELECT DISTINCT ON (id) id, config, document, rank
+            FROM matches
+            ORDER BY id, rank DESC
+        )
+        SELECT t.id, t.parent_id, t.name, t.node_type as "node_type: NodeType", t.data, t.created_at,
+               b.rank AS "rank!",
+               ts_headline(b.config, translate(b.document, E'\x02\x03', ''), websearch_to_tsquery(b.config, $2), $3)
+                   AS "highlight!"
+        FROM best b
+        INNER JOIN tree_nodes t ON t.id = b.id
+        ORDER BY b.rank DESC, t.created_at
+        LIMIT $4
+        "#,
+        user,
+        query.trim(),
+        HIGHLIGHT_OPTIONS,
+        QUERY_LIMIT
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(SearchHit::from).collect())
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highlight_is_escaped() {
+        assert_eq!(
+            highlight_html("a \u{2}<b>\u{3} & \"c\" 'd'"),
+            "a <mark>&lt;b&gt;</mark> &amp; &quot;c&quot; &#39;d&#39;"
+        );
+    }
+
+    async fn insert_node(
+        db: &sqlx::PgPool,
+        user_id: &Uuid,
+        parent_id: &Uuid,
+        node_type: NodeType,
+        language: &str,
+        data: serde_json::Value,
+    ) -> Uuid {
+        sqlx::query_scalar!(
+            "INSERT INTO tree_nodes (user_id, parent_id, node_type, language, data) VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            user_id,
+            parent_id,
+            node_type as NodeType,
+            language,
+            data
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_search_visible_nodes_and_cached_descriptions() {
+        let state = crate::init::test_db_state(crate::AiConfig::default()).await;
+        let db = &state.db;
+        let (owner, stranger) = (Uuid::now_v7(), Uuid::now_v7());
+        let owner_root = crate::init::test_root(db, &owner).await;
+        let stranger_root = crate::init::test_root(db, &stranger).await;
+        let hash = format!("test-{}", Uuid::now_v7());
+
+        let described = insert_node(
+            db,
+            &owner,
+            &owner_root,
+            NodeType::ImageLeaf,
+            "en",
+            serde_json::json!({ "description": "Workers installing drywall panels in the hallway" }),
+        )
+        .await;
+        let undescribed =
+            insert_node(db, &owner, &owner_root, NodeType::ImageLeaf, "en", serde_json::json!({ "hash": hash })).await;
+        let storage = insert_node(
+            db,
+            &stranger,
+            &stranger_root,
+            NodeType::Branch,
+            "en",
+            serde_json::json!({ "label": "<img src=x onerror=alert(1)> Drywall \u{2}storage" }),
+        )
+        .await;
+        sqlx::query!(
+            "INSERT INTO image_descriptions (image_hash, model_name, prompt, language, description)
+             VALUES ($1, 'test', 'test', 'de', 'Trockenbauplatten im Flur')",
+            hash
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        let owner_text = owner.to_string();
+        let hits = search(db, Some(&owner_text), "drywall").await.unwrap();
+        assert_eq!(hits.iter().map(|hit| hit.node.id).collect::<Vec<_>>(), vec![described]);
+        assert!(hits[0].highlight.contains("<mark>drywall</mark>"), "{}", hits[0].highlight);
+
+        let hits = search(db, Some(&owner_text), "Flur").await.unwrap();
+        assert_eq!(hits.iter().map(|hit| hit.node.id).collect::<Vec<_>>(), vec![undescribed]);
+        assert!(hits[0].highlight.contains("<mark>Flur</mark>"), "{}", hits[0].highlight);
+
+        let stranger_text = stranger.to_string();
+        let hits = search(db, Some(&stranger_text), "drywall").await.unwrap();
+        assert_eq!(hits.iter().map(|hit| hit.node.id).collect::<Vec<_>>(), vec![storage]);
+        assert!(!hits[0].highlight.contains("<img"), "{}", hits[0].highlight);
+        assert!(hits[0].highlight.ends_with("&gt; <mark>Drywall</mark> storage"), "{}", hits[0].highlight);
+        assert!(search(db, Some(&stranger_text), "Flur").await.unwrap().is_empty());
+
+        for root_id in [owner_root, stranger_root] {
+            sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+                .execute(db)
+                .await
+                .unwrap();
+        }
+        sqlx::query!("DELETE FROM image_descriptions WHERE image_hash = $1", hash)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+}
@@ -2,39 +2,592 @@ use crate::error::*;
 use crate::models::*;
 use axum::{
     Json,
-    extract::{Multipart, Path, State},
+    extract::{Path, State},
     http::StatusCode,
 };
 use bytes::Bytes;
-use s3::bucket::Bucket;
-use s3::creds::Credentials;
-use s3::region::Region;
 use sha2::{Digest, Sha256};
 use std::str::FromStr;
 use std::sync::Arc;
 use uuid::Uuid;
 use crate::agents::master_agent::MasterAgent;
+use crate::agents::MockLlm;
+use crate::blob_store::{BlobStore, BlobStream, BlobUpload, LocalStore};
+use crate::handlers::CurrentUser;
+use crate::image_diff::{self, ImageDiff};
+use crate::image_import::ImportConfig;
+use crate::image_quality::ImageQuality;
+use crate::jobs::JobHub;
+use crate::tree;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::metadata::Orientation;
+use image::{ImageDecoder, ImageFormat};
+use crate::perceptual_hash;
+use std::collections::BTreeMap;
 
 // ============================================================================
 // AppState && AiConfig
 // ============================================================================
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AiRole {
+    Text,
+    Vision,
+    Chat,
+}
+
+#[derive(Debug, Clone, Default)]
+pub enum AiProvider {
+    #[default]
+    Ollama,
+    /// In-process scripted replies, for tests and offline development
+    Mock(Arc<MockLlm>),
+}
+
+impl FromStr for AiProvider {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "ollama" => Ok(Self::Ollama),
+            "mock" => Ok(Self::Mock(Arc::new(MockLlm::new()))),
+            other => Err(format!("unknown provider '{}'", other)),
+        }
+    }
+}
+
+/// How `MasterAgent` picks the work to do for a request
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AgentMode {
+    /// A tool-calling agent explores the tree on its own; needs a text
+    /// model that supports tools
+    Tools,
+    /// Keyword detection routes to a fixed specialist agent
+    #[default]
+    Keywords,
+}
+
+/// Ollama models that reject requests with tools
+const MODELS_WITHOUT_TOOLS: &[&str] = &["llava", "bakllava", "llava-llama3", "llava-phi3", "moondream"];
+
+fn supports_tools(model: &str) -> bool {
+    let name = model.split(':').next().unwrap_or(model);
+    !MODELS_WITHOUT_TOOLS.contains(&name)
+}
+
+impl FromStr for AgentMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tools" => Ok(Self::Tools),
+            "keywords" => Ok(Self::Keywords),
+            other => Err(format!("unknown agent mode '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelParams {
+    pub temperature: f64,
+    pub max_tokens: u64,
+    pub context_window: u64,
+}
+
+impl ModelParams {
+    fn from_env(prefix: &str, default: ModelParams) -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        Ok(Self {
+            temperature: env_or(&format!("{}_TEMPERATURE", prefix), default.temperature)?,
+            max_tokens: env_or(&format!("{}_MAX_TOKENS", prefix), default.max_tokens)?,
+            context_window: env_or(&format!("{}_CONTEXT_WINDOW", prefix), default.context_window)?,
+        })
+    }
+
+    fn validate(&self, role: &str, errors: &mut ValidationErrors) {
+        if !(0.0..=2.0).contains(&self.temperature) {
+            errors.add(ValidationError::new(
+                format!("{}_TEMPERATURE", role),
+                "Temperature must be between 0.0 and 2.0",
+            ));
+        }
+        if self.max_tokens == 0 {
+            errors.add(ValidationError::new(
+                format!("{}_MAX_TOKENS", role),
+                "Max tokens must be positive",
+            ));
+        }
+        if self.context_window < self.max_tokens {
+            errors.add(ValidationError::new(
+                format!("{}_CONTEXT_WINDOW", role),
+                "Context window must not be smaller than max tokens",
+            ));
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries per model before falling back to the next one
+    pub max_retries: u32,
+    pub initial_backoff: std::time::Duration,
+    pub max_backoff: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 2,
+            initial_backoff: std::time::Duration::from_millis(500),
+            max_backoff: std::time::Duration::from_secs(8),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before retry number `attempt` (0-based), doubling each time
+    pub fn backoff(&self, attempt: u32) -> std::time::Duration {
+        self.initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff)
+    }
+}
+
 #[derive(Clone)]
 pub struct AiConfig {
+    pub provider: AiProvider,
+    pub mode: AgentMode,
     pub url: String,
-    pub text_model: String,
-    pub vision_model: String,
-    pub chat_model: String,
+    /// Ordered model lists per role; later entries are fallbacks
+    pub text_models: Vec<String>,
+    pub vision_models: Vec<String>,
+    pub chat_models: Vec<String>,
+    pub text: ModelParams,
+    pub vision: ModelParams,
+    pub chat: ModelParams,
+    pub request_timeout: std::time::Duration,
+    pub retry: RetryPolicy,
+    /// How long Ollama keeps a model loaded, e.g. `5m`, `1h`, `0` or `-1`
+    pub keep_alive: String,
+    /// Re-prompts allowed when a structured reply fails schema validation
+    pub schema_retries: u32,
+}
+
+impl Default for AiConfig {
+    fn default() -> Self {
+        Self {
+            provider: AiProvider::Ollama,
+            mode: AgentMode::Keywords,
+            url: "http://127.0.0.1:11434".to_string(),
+            text_models: vec!["llava".to_string()],
+            vision_models: vec!["llama3.2-vision".to_string(), "llava".to_string()],
+            chat_models: vec!["llava".to_string()],
+            text: ModelParams {
+                temperature: 0.2,
+                max_tokens: 2048,
+                context_window: 8192,
+            },
+            vision: ModelParams {
+                temperature: 0.2,
+                max_tokens: 1024,
+                context_window: 8192,
+            },
+            chat: ModelParams {
+                temperature: 0.7,
+                max_tokens: 1024,
+                context_window: 4096,
+            },
+            request_timeout: std::time::Duration::from_secs(120),
+            retry: RetryPolicy::default(),
+            keep_alive: "5m".to_string(),
+            schema_retries: 2,
+        }
+    }
 }
+
 impl AiConfig {
     pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let default = Self::default();
+        let config = Self {
+            provider: env_or("AI_PROVIDER", default.provider)?,
+            mode: env_or("AI_AGENT_MODE", default.mode)?,
+            url: std::env::var("OLLAMA_URL")
+                .or_else(|_| std::env::var("AI_URL"))
+                .unwrap_or(default.url),
+            text_models: env_list("TEXT_MODEL").unwrap_or(default.text_models),
+            vision_models: env_list("VISION_MODEL").unwrap_or(default.vision_models),
+            chat_models: env_list("CHAT_MODEL").unwrap_or(default.chat_models),
+            text: ModelParams::from_env("TEXT", default.text)?,
+            vision: ModelParams::from_env("VISION", default.vision)?,
+            chat: ModelParams::from_env("CHAT", default.chat)?,
+            request_timeout: std::time::Duration::from_secs(env_or(
+                "AI_REQUEST_TIMEOUT_SECS",
+                default.request_timeout.as_secs(),
+            )?),
+            retry: RetryPolicy {
+                max_retries: env_or("AI_MAX_RETRIES", default.retry.max_retries)?,
+                initial_backoff: std::time::Duration::from_millis(env_or(
+                    "AI_RETRY_BACKOFF_MS",
+                    default.retry.initial_backoff.as_millis() as u64,
+                )?),
+                max_backoff: std::time::Duration::from_millis(env_or(
+                    "AI_RETRY_MAX_BACKOFF_MS",
+                    default.retry.max_backoff.as_millis() as u64,
+                )?),
+            },
+            keep_alive: std::env::var("AI_KEEP_ALIVE").unwrap_or(default.keep_alive),
+            schema_retries: env_or("AI_SCHEMA_RETRIES", default.schema_retries)?,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let mut errors = ValidationErrors::new();
+
+        match reqwest::Url::parse(&self.url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+            _ => errors.add(ValidationError::new("OLLAMA_URL", "Must be an http(s) URL")),
+        }
+        for (name, models) in [
+            ("TEXT_MODEL", &self.text_models),
+            ("VISION_MODEL", &self.vision_models),
+            ("CHAT_MODEL", &self.chat_models),
+        ] {
+            if models.is_empty() || models.iter().any(|m| m.trim().is_empty()) {
+                errors.add(ValidationError::new(name, "Model names must not be empty"));
+            }
+        }
+        if self.mode == AgentMode::Tools && !self.text_models.iter().all(|m| supports_tools(m)) {
+            errors.add(ValidationError::new(
+                "AI_AGENT_MODE",
+                "Tools mode needs text models that support tools, e.g. llama3.1 or qwen2.5",
+            ));
+        }
+        self.text.validate("TEXT", &mut errors);
+        self.vision.validate("VISION", &mut errors);
+        self.chat.validate("CHAT", &mut errors);
+        if self.request_timeout.is_zero() {
+            errors.add(ValidationError::new(
+                "AI_REQUEST_TIMEOUT_SECS",
+                "Timeout must be positive",
+            ));
+        }
+        if self.retry.max_backoff < self.retry.initial_backoff {
+            errors.add(ValidationError::new(
+                "AI_RETRY_MAX_BACKOFF_MS",
+                "Max backoff must not be smaller than the initial backoff",
+            ));
+        }
+        if !is_valid_keep_alive(&self.keep_alive) {
+            errors.add(ValidationError::new(
+                "AI_KEEP_ALIVE",
+                "Expected a number of seconds or a duration like 30s, 5m, 1h",
+            ));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.into_app_error())
+        }
+    }
+
+    /// Primary model for the role
+    pub fn model(&self, role: AiRole) -> &str {
+        &self.models(role)[0]
+    }
+
+    /// Models for the role in fallback order
+    pub fn models(&self, role: AiRole) -> &[String] {
+        match role {
+            AiRole::Text => &self.text_models,
+            AiRole::Vision => &self.vision_models,
+            AiRole::Chat => &self.chat_models,
+        }
+    }
+
+    pub fn params(&self, role: AiRole) -> &ModelParams {
+        match role {
+            AiRole::Text => &self.text,
+            AiRole::Vision => &self.vision,
+            AiRole::Chat => &self.chat,
+        }
+    }
+}
+
+pub(crate) fn env_or<T>(key: &str, default: T) -> std::result::Result<T, Box<dyn std::error::Error>>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .map_err(|e| format!("Invalid {}: {}", key, e).into()),
+        Err(_) => Ok(default),
+    }
+}
+
+/// Comma-separated list, e.g. `VISION_MODEL=llama3.2-vision,llava`
+pub(crate) fn env_list(key: &str) -> Option<Vec<String>> {
+    std::env::var(key).ok().map(|value| {
+        value
+            .split(',')
+            .map(|s| s.trim().to_string())
+            .filter(|s| !s.is_empty())
+            .collect()
+    })
+}
+
+fn is_valid_keep_alive(value: &str) -> bool {
+    let digits = value.strip_suffix(['s', 'm', 'h']).unwrap_or(value);
+    let digits = digits.strip_prefix('-').unwrap_or(digits);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+}
+
+/// What uploading an image that already exists under the same parent does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    /// Return the existing node instead of creating a new one
+    #[default]
+    Reuse,
+    /// Fail with 409 Conflict pointing at the existing node
+    Reject,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "reuse" => Ok(Self::Reuse),
+            "reject" => Ok(Self::Reject),
+            other => Err(format!("unknown duplicate policy '{}'", other)),
+        }
+    }
+}
+
+/// Encoding of a rendition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenditionFormat {
+    Jpeg,
+    /// Lossless; larger than JPEG for photos but keeps sharp edges
+    WebP,
+    Png,
+}
+
+impl RenditionFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::WebP => "webp",
+            Self::Png => "png",
+        }
+    }
+
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Jpeg => "image/jpeg",
+            Self::WebP => "image/webp",
+            Self::Png => "image/png",
+        }
+    }
+}
+
+impl FromStr for RenditionFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Ok(Self::Jpeg),
+            "webp" => Ok(Self::WebP),
+            "png" => Ok(Self::Png),
+            other => Err(format!("unknown rendition format '{}'", other)),
+        }
+    }
+}
+
+/// A downscaled copy generated for every upload, written as `name:max_size:format`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenditionSpec {
+    pub name: String,
+    /// Longest side in pixels
+    pub max_size: u32,
+    pub format: RenditionFormat,
+}
+
+impl FromStr for RenditionSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').map(str::trim).collect();
+        let [name, max_size, format] = parts[..] else {
+            return Err(format!("expected name:max_size:format, got '{}'", s));
+        };
+        if name.is_empty() || name == ORIGINAL_SIZE {
+            return Err(format!("invalid rendition name '{}'", name));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            max_size: max_size
+                .parse()
+                .ok()
+                .filter(|size| *size > 0)
+                .ok_or_else(|| format!("invalid rendition size '{}'", max_size))?,
+            format: format.parse()?,
+        })
+    }
+}
+
+/// `size` that selects the uploaded image itself
+pub const ORIGINAL_SIZE: &str = "original";
+
+/// Rendition vision models are given instead of the original, when present
+pub const PREVIEW_RENDITION: &str = "preview";
+
+/// What an upload's content must look like, whatever its file name says
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageLimits {
+    /// Formats accepted, as sniffed from magic bytes
+    pub allowed_formats: Vec<ImageFormat>,
+    pub max_width: u32,
+    pub max_height: u32,
+}
+
+impl Default for ImageLimits {
+    fn default() -> Self {
+        Self {
+            allowed_formats: vec![ImageFormat::Jpeg, ImageFormat::Png, ImageFormat::WebP, ImageFormat::Tiff],
+            max_width: 12_000,
+            max_height: 12_000,
+        }
+    }
+}
+
+impl ImageLimits {
+    fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let default = Self::default();
+        let allowed_formats = match env_list("UPLOAD_ALLOWED_FORMATS") {
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    ImageFormat::from_extension(name)
+                        .ok_or_else(|| format!("Invalid UPLOAD_ALLOWED_FORMATS: unknown format '{}'", name))
+                })
+                .collect::<std::result::Result<_, _>>()?,
+            None => default.allowed_formats,
+        };
         Ok(Self {
-            url: std::env::var("DATABASE_URL").unwrap_or_else(|_| "http://127.0.0.1:11434".to_string()),
-            text_model: std::env::var("TEXT_MODEL").unwrap_or_else(|_| "llava".to_string()),
-            vision_model: std::env::var("VISION_MODEL").unwrap_or_else(|_| "llama3.2-vision".to_string()),
-            chat_model: std::env::var("CHAT_MODEL").unwrap_or_else(|_| "llava".to_string()),
+            allowed_formats,
+            max_width: env_or("UPLOAD_MAX_WIDTH", default.max_width)?,
+            max_height: env_or("UPLOAD_MAX_HEIGHT", default.max_height)?,
         })
     }
+
+    pub(crate) fn check_format(&self, format: ImageFormat) -> Result<()> {
+        if self.allowed_formats.contains(&format) {
+            Ok(())
+        } else {
+            Err(AppError::new(
+                ErrorCode::UnsupportedMediaType,
+                format!("{} images are not accepted", format.to_mime_type()),
+            ))
+        }
+    }
+
+    /// Decoder limits that stop decompression bombs in formats whose
+    /// dimensions weren't checked up front
+    fn decoder_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UploadConfig {
+    pub duplicate_policy: DuplicatePolicy,
+    /// Largest accepted image, checked while the upload streams in
+    pub max_image_bytes: u64,
+    /// Lifetime of presigned upload URLs
+    pub presign_expiry_secs: u32,
+    pub renditions: Vec<RenditionSpec>,
+    /// Remove GPS tags from stored originals (JPEG only). The position is
+    /// still recorded on the node.
+    pub strip_gps: bool,
+    pub limits: ImageLimits,
+    /// Answer `/content` with a redirect to a short-lived signed URL
+    /// instead of proxying the bytes
+    pub content_redirect: bool,
+    /// `max-age` sent with image content
+    pub content_cache_secs: u32,
+    /// Lifetime of the signed URLs `/content` redirects to
+    pub content_url_expiry_secs: u32,
+    /// Imports from URLs; their size is capped by `max_image_bytes`
+    pub import: ImportConfig,
+    /// Perceptual hashes at most this many bits apart (of 64) count as
+    /// near-duplicates
+    pub near_duplicate_distance: u32,
 }
+
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            duplicate_policy: DuplicatePolicy::default(),
+            max_image_bytes: 10 * 1024 * 1024,
+            presign_expiry_secs: 15 * 60,
+            renditions: vec![
+                RenditionSpec {
+                    name: "thumb".to_string(),
+                    max_size: 256,
+                    format: RenditionFormat::Jpeg,
+                },
+                RenditionSpec {
+                    name: PREVIEW_RENDITION.to_string(),
+                    max_size: 1280,
+                    format: RenditionFormat::Jpeg,
+                },
+            ],
+            strip_gps: false,
+            limits: ImageLimits::default(),
+            content_redirect: false,
+            content_cache_secs: 60 * 60,
+            content_url_expiry_secs: 5 * 60,
+            import: ImportConfig::default(),
+            near_duplicate_distance: 10,
+        }
+    }
+}
+
+impl UploadConfig {
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let default = Self::default();
+        Ok(Self {
+            duplicate_policy: env_or("UPLOAD_DUPLICATE_POLICY", default.duplicate_policy)?,
+            max_image_bytes: env_or("UPLOAD_MAX_IMAGE_BYTES", default.max_image_bytes)?,
+            presign_expiry_secs: env_or("UPLOAD_PRESIGN_EXPIRY_SECS", default.presign_expiry_secs)?,
+            // e.g. `IMAGE_RENDITIONS=thumb:256:jpeg,preview:1280:webp`; empty disables renditions
+            renditions: match std::env::var("IMAGE_RENDITIONS") {
+                Ok(value) => value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(|s| s.parse().map_err(|e| format!("Invalid IMAGE_RENDITIONS: {}", e)))
+                    .collect::<std::result::Result<_, _>>()?,
+                Err(_) => default.renditions,
+            },
+            strip_gps: env_or("UPLOAD_STRIP_GPS", default.strip_gps)?,
+            limits: ImageLimits::from_env()?,
+            content_redirect: env_or("IMAGE_CONTENT_REDIRECT", default.content_redirect)?,
+            content_cache_secs: env_or("IMAGE_CONTENT_CACHE_SECS", default.content_cache_secs)?,
+            content_url_expiry_secs: env_or("IMAGE_CONTENT_URL_EXPIRY_SECS", default.content_url_expiry_secs)?,
+            import: ImportConfig::from_env()?,
+            near_duplicate_distance: env_or("NEAR_DUPLICATE_DISTANCE", default.near_duplicate_distance)?,
+        })
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
     pub db: sqlx::PgPool,
@@ -42,58 +595,44 @@ pub struct AppState {
     pub image_resolver: Arc<ImageUrlResolver>,
     pub image_processor: Arc<ImageProcessor>,
     pub master_agent: Arc<MasterAgent>,
+    pub jobs: Arc<JobHub>,
     pub ai_config: AiConfig,
+    pub upload_config: UploadConfig,
 }
 //pub redis: redis::aio::ConnectionManager,
 //pub agent: Arc<RwLock<AgentExecutor>>,
 //pub orchestrator: Arc<crate::rig_integration::AgentOrchestrator>,
 
 // ============================================================================
-// Storage Service with rust-s3
+// Storage Service
 // ============================================================================
 
 #[derive(Clone)]
 pub struct StorageService {
-    bucket: Bucket,
-    public_url_base: String,
+    store: Arc<dyn BlobStore>,
+    /// Set when blobs live on local disk and are served by `/files/`
+    local: Option<Arc<LocalStore>>,
 }
 
 impl StorageService {
-    /// Create from explicit configuration
-    pub fn new(
-        bucket_name: String,
-        region: String,
-        access_key: String,
-        secret_key: String,
-        public_url_base: String,
-        endpoint: Option<String>,
-    ) -> Result<Self> {
-        let region = if let Some(ep) = endpoint {
-            Region::Custom {
-                region: region.clone(),
-                endpoint: ep,
-            }
-        } else {
-            Region::from_str(&region)
-                .map_err(|e| AppError::internal(format!("Invalid region: {}", e)))?
-        };
-
-        let credentials = Credentials::new(Some(&access_key), Some(&secret_key), None, None, None)
-            .map_err(|e| AppError::internal(format!("Credentials error: {}", e)))?;
-
-        let mut bucket = Bucket::new(&bucket_name, region, credentials)
-            .map_err(|e| AppError::internal(format!("Bucket creation failed: {}", e)))?;
+    pub fn new(store: Arc<dyn BlobStore>) -> Self {
+        Self { store, local: None }
+    }
 
-        // Use path-style for compatibility with MinIO/LocalStack
-        bucket = bucket.with_path_style();
+    pub fn local(store: LocalStore) -> Self {
+        let store = Arc::new(store);
+        Self {
+            store: store.clone(),
+            local: Some(store),
+        }
+    }
 
-        Ok(Self {
-            bucket: *bucket,
-            public_url_base,
-        })
+    /// Local backend, if any, for verifying signed `/files/` URLs
+    pub fn local_store(&self) -> Option<&LocalStore> {
+        self.local.as_deref()
     }
 
-    /// Upload image to S3
+    /// Upload image to storage
     pub async fn upload_image(
         &self,
         user_id: &Uuid,
@@ -113,97 +652,104 @@ impl StorageService {
             .first_or_octet_stream()
             .to_string();
 
-        // Upload with rust-s3
-        self.bucket
-            .put_object(&storage_path, &image_data)
-            .await
-            .map_err(|e| {
-                AppError::new(ErrorCode::StorageError, format!("S3 upload failed: {}", e))
-            })?;
-
-        let public_url = format!(
-            "{}/{}",
-            self.public_url_base.trim_end_matches('/'),
-            storage_path
-        );
+        let size = image_data.len() as u64;
+        self.store.upload(&storage_path, image_data, &mime_type).await?;
 
         Ok(StorageResult {
+            public_url: self.store.public_url(&storage_path),
             storage_path,
-            public_url,
-            size: image_data.len() as u64,
+            size,
             mime_type,
             hash,
         })
     }
 
-    /// Download image from S3
+    /// Content-addressed key shared by every upload of the same bytes
+    pub fn content_path(hash: &str, format: ImageFormat) -> String {
+        format!("blobs/{}/{}.{}", &hash[..2], hash, format_extension(format))
+    }
+
+    /// Key of a rendition, next to every other rendition of the same blob
+    pub fn rendition_path(hash: &str, spec: &RenditionSpec) -> String {
+        format!("{}{}.{}", Self::rendition_prefix(hash), spec.name, spec.format.extension())
+    }
+
+    /// Key of the heatmap of changes from `before_hash` to `after_hash`,
+    /// kept with the renditions of the later image
+    pub fn diff_path(before_hash: &str, after_hash: &str) -> String {
+        format!("{}diff-{}.jpg", Self::rendition_prefix(after_hash), before_hash)
+    }
+
+    pub fn rendition_prefix(hash: &str) -> String {
+        format!("renditions/{}/{}/", &hash[..2], hash)
+    }
+
+    pub async fn list_renditions(&self, hash: &str) -> Result<Vec<String>> {
+        self.store.list(&Self::rendition_prefix(hash)).await
+    }
+
+    /// Start a streamed upload, see [`BlobUpload`]
+    pub async fn begin_upload(&self, mime_type: &str) -> Result<Box<dyn BlobUpload>> {
+        self.store.begin_upload(mime_type).await
+    }
+
+    /// Generate presigned upload URL
+    pub async fn generate_presigned_upload_url(
+        &self,
+        storage_path: &str,
+        mime_type: &str,
+        expires_in_secs: u32,
+    ) -> Result<String> {
+        self.store.presign_put(storage_path, mime_type, expires_in_secs).await
+    }
+
+    /// Upload a blob under an explicit key
+    pub async fn upload_blob(&self, storage_path: &str, data: Bytes, mime_type: &str) -> Result<()> {
+        self.store.upload(storage_path, data, mime_type).await
+    }
+
+    pub fn public_url(&self, storage_path: &str) -> String {
+        self.store.public_url(storage_path)
+    }
+
+    /// Download image from storage
     pub async fn download_image(&self, storage_path: &str) -> Result<Bytes> {
-        let response = self.bucket.get_object(storage_path).await.map_err(|e| {
-            AppError::new(
-                ErrorCode::StorageError,
-                format!("S3 download failed: {}", e),
-            )
-        })?;
+        self.store.download(storage_path).await
+    }
 
-        Ok(Bytes::from(response.bytes().to_vec()))
+    /// Stream image from storage, optionally only an inclusive byte range
+    pub async fn stream_image(&self, storage_path: &str, range: Option<(u64, u64)>) -> Result<BlobStream> {
+        self.store.download_stream(storage_path, range).await
     }
 
-    /// Delete image from S3
+    /// Delete image from storage
     pub async fn delete_image(&self, storage_path: &str) -> Result<()> {
-        self.bucket.delete_object(storage_path).await.map_err(|e| {
-            AppError::new(ErrorCode::StorageError, format!("S3 delete failed: {}", e))
-        })?;
-
-        Ok(())
+        self.store.delete(storage_path).await
     }
 
     /// Check if object exists
     pub async fn exists(&self, storage_path: &str) -> Result<bool> {
-        match self.bucket.head_object(storage_path).await {
-            Ok(_) => Ok(true),
-            Err(e) => {
-                let err_str = e.to_string();
-                if err_str.contains("404") || err_str.contains("NotFound") {
-                    Ok(false)
-                } else {
-                    Err(AppError::new(
-                        ErrorCode::StorageError,
-                        format!("S3 head failed: {}", e),
-                    ))
-                }
-            }
-        }
+        self.store.exists(storage_path).await
     }
 
     /// Get object metadata
     pub async fn get_metadata(&self, storage_path: &str) -> Result<ImageMetadata> {
-        let response = self.bucket.head_object(storage_path).await.map_err(|e| {
-            AppError::new(ErrorCode::StorageError, format!("S3 head failed: {}", e))
-        })?;
-
-        Ok(ImageMetadata {
-            size: response.0.content_length.unwrap_or(0) as u64,
-            content_type: response.0.content_type,
-            last_modified: response.0.last_modified,
-        })
+        self.store.metadata(storage_path).await
     }
 
     /// List all user images
     pub async fn list_user_images(&self, user_id: &Uuid) -> Result<Vec<String>> {
-        let prefix = format!("images/{}/", user_id);
-
-        let results = self.bucket.list(prefix, None).await.map_err(|e| {
-            AppError::new(ErrorCode::StorageError, format!("S3 list failed: {}", e))
-        })?;
+        self.store.list(&format!("images/{}/", user_id)).await
+    }
 
-        let mut paths = Vec::new();
-        for result in results {
-            for object in result.contents {
-                paths.push(object.key);
-            }
-        }
+    /// List every object under a prefix
+    pub async fn list_objects(&self, prefix: &str) -> Result<Vec<String>> {
+        self.store.list(prefix).await
+    }
 
-        Ok(paths)
+    /// Remove staging data of streamed uploads abandoned before `before`
+    pub async fn purge_staging(&self, before: chrono::DateTime<chrono::Utc>, dry_run: bool) -> Result<Vec<String>> {
+        self.store.purge_staging(before, dry_run).await
     }
 
     /// Generate presigned URL (for downloads)
@@ -212,28 +758,12 @@ impl StorageService {
         storage_path: &str,
         expires_in_secs: u32,
     ) -> Result<String> {
-        let url = self
-            .bucket
-            .presign_get(storage_path, expires_in_secs, None)
-            .await
-            .map_err(|e| AppError::internal(format!("Presigned URL failed: {}", e)))?;
-
-        Ok(url)
+        self.store.presign(storage_path, expires_in_secs).await
     }
 
-    /// Copy object within bucket
+    /// Copy object within storage
     pub async fn copy_image(&self, source_path: &str, dest_path: &str) -> Result<()> {
-        // Download then upload (rust-s3 doesn't have native copy)
-        let data = self.download_image(source_path).await?;
-
-        self.bucket
-            .put_object(dest_path, &data)
-            .await
-            .map_err(|e| {
-                AppError::new(ErrorCode::StorageError, format!("S3 copy failed: {}", e))
-            })?;
-
-        Ok(())
+        self.store.copy(source_path, dest_path).await
     }
 
     /// Batch delete
@@ -241,7 +771,7 @@ impl StorageService {
         let mut deleted = Vec::new();
 
         for path in paths {
-            match self.bucket.delete_object(&path).await {
+            match self.store.delete(&path).await {
                 Ok(_) => deleted.push(path),
                 Err(e) => {
                     log::warn!("Failed to delete {}: {}", path, e);
@@ -253,96 +783,152 @@ impl StorageService {
     }
 
     /// Compute SHA256 hash
-    fn compute_hash(&self, data: &Bytes) -> String {
+    pub fn compute_hash(&self, data: &Bytes) -> String {
         let mut hasher = Sha256::new();
         hasher.update(data);
         hex::encode(hasher.finalize())
     }
 }
 
+pub(crate) fn format_extension(format: ImageFormat) -> &'static str {
+    format.extensions_str().first().copied().unwrap_or("bin")
+}
+
 // ============================================================================
 // Image Processor
 // ============================================================================
 
 pub struct ImageProcessor {
     pub storage: Arc<StorageService>,
+    pub limits: ImageLimits,
+}
+
+/// What sniffing the start of an upload revealed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageInfo {
+    pub format: ImageFormat,
+    /// `None` when the header didn't fit into the inspected bytes
+    pub dimensions: Option<(u32, u32)>,
 }
 
 impl ImageProcessor {
-    pub fn new(storage: Arc<StorageService>) -> Self {
-        Self { storage }
+    pub fn new(storage: Arc<StorageService>, limits: ImageLimits) -> Self {
+        Self { storage, limits }
     }
 
-    /// Import external image
-    pub async fn import_external_image(
-        &self,
-        user_id: &Uuid,
-        node_id: &Uuid,
-        external_url: &str,
-    ) -> Result<StorageResult> {
-        let response = reqwest::get(external_url)
-            .await
-            .map_err(|e| AppError::internal(format!("Download failed: {}", e)))?;
+    /// Scale an image to fit `spec` and encode it. CPU-bound, so call it
+    /// from a blocking task.
+    pub fn render(&self, data: &[u8], spec: &RenditionSpec) -> Result<Bytes> {
+        let img = self.decode(data)?;
 
-        let bytes = response
-            .bytes()
-            .await
-            .map_err(|e| AppError::internal(format!("Read failed: {}", e)))?;
+        let scaled = if img.width().max(img.height()) > spec.max_size {
+            img.thumbnail(spec.max_size, spec.max_size)
+        } else {
+            img
+        };
 
-        let filename = external_url.split('/').next_back().unwrap_or("image.jpg");
+        let mut buffer = Vec::new();
+        let encoded = match spec.format {
+            RenditionFormat::Jpeg => scaled
+                .to_rgb8()
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, 85)),
+            RenditionFormat::WebP => scaled
+                .to_rgba8()
+                .write_with_encoder(WebPEncoder::new_lossless(&mut buffer)),
+            RenditionFormat::Png => scaled.write_to(&mut std::io::Cursor::new(&mut buffer), image::ImageFormat::Png),
+        };
+        encoded.map_err(|e| AppError::internal(format!("Encode failed: {}", e)))?;
 
-        self.storage
-            .upload_image(user_id, node_id, bytes, filename)
-            .await
+        Ok(Bytes::from(buffer))
     }
 
-    /// Create thumbnail
-    pub async fn create_thumbnail(
-        &self,
-        user_id: &Uuid,
-        _node_id: &Uuid,
-        original_path: &str,
-        max_width: u32,
-        max_height: u32,
-    ) -> Result<StorageResult> {
-        let data = self.storage.download_image(original_path).await?;
-        let img = image::load_from_memory(&data)
-            .map_err(|e| AppError::internal(format!("Invalid image: {}", e)))?;
+    /// Perceptual hash of an image as it is displayed, see
+    /// [`perceptual_hash::dhash`]. CPU-bound, so call it from a blocking task.
+    pub fn perceptual_hash(&self, data: &[u8]) -> Result<u64> {
+        Ok(perceptual_hash::dhash(&self.decode(data)?))
+    }
 
-        let thumbnail = img.thumbnail(max_width, max_height);
+    /// Blur, exposure and noise scores of an image. CPU-bound, so call it
+    /// from a blocking task.
+    pub fn analyze_quality(&self, data: &[u8]) -> Result<ImageQuality> {
+        Ok(ImageQuality::analyze(&self.decode(data)?))
+    }
 
+    /// The part of an image inside `shape`, with some surroundings for
+    /// context, scaled to fit `max_size` and encoded as JPEG. CPU-bound, so
+    /// call it from a blocking task.
+    pub fn crop(&self, data: &[u8], shape: &AnnotationShape, max_size: u32) -> Result<Bytes> {
+        let img = self.decode(data)?;
+        let (width, height) = (img.width() as f32, img.height() as f32);
+        let (x, y, w, h) = shape.bounds();
+        // A quarter of the region on every side, so the model sees where it is
+        let (pad_x, pad_y) = (w * 0.25, h * 0.25);
+        let left = (((x - pad_x).max(0.0) * width).round() as u32).min(img.width() - 1);
+        let top = (((y - pad_y).max(0.0) * height).round() as u32).min(img.height() - 1);
+        let right = (((x + w + pad_x).min(1.0) * width).round() as u32).max(left + 1);
+        let bottom = (((y + h + pad_y).min(1.0) * height).round() as u32).max(top + 1);
+        let region = img.crop_imm(left, top, right - left, bottom - top);
+
+        let scaled = if region.width().max(region.height()) > max_size {
+            region.thumbnail(max_size, max_size)
+        } else {
+            region
+        };
         let mut buffer = Vec::new();
-        thumbnail
-            .write_to(
-                &mut std::io::Cursor::new(&mut buffer),
-                image::ImageFormat::Jpeg, //   ImageOutputFormat::Jpeg(85),
-            )
+        scaled
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, 85))
             .map_err(|e| AppError::internal(format!("Encode failed: {}", e)))?;
+        Ok(Bytes::from(buffer))
+    }
 
-        let thumb_node_id = Uuid::now_v7();
-        self.storage
-            .upload_image(
-                user_id,
-                &thumb_node_id,
-                Bytes::from(buffer),
-                "thumbnail.jpg",
-            )
-            .await
+    /// Pixel changes between two photos, with the overlay encoded as JPEG.
+    /// CPU-bound, so call it from a blocking task.
+    pub fn compare(&self, before: &[u8], after: &[u8]) -> Result<(ImageDiff, Bytes)> {
+        let diff = image_diff::diff(&self.decode(before)?, &self.decode(after)?);
+        let mut buffer = Vec::new();
+        diff.overlay
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut buffer, 85))
+            .map_err(|e| AppError::internal(format!("Encode failed: {}", e)))?;
+        Ok((diff, Bytes::from(buffer)))
     }
 
-    /// Validate image
-    pub fn validate_image(&self, data: &Bytes, max_size_mb: u64) -> Result<()> {
-        if data.len() as u64 > max_size_mb * 1024 * 1024 {
-            return Err(AppError::bad_request(format!(
-                "Image too large (max {}MB)",
-                max_size_mb
-            )));
-        }
+    /// Decodes within the limits, with the EXIF orientation applied
+    fn decode(&self, data: &[u8]) -> Result<image::DynamicImage> {
+        let invalid = |e: image::ImageError| AppError::validation(format!("Invalid image: {}", e));
+        let mut reader = image::ImageReader::new(std::io::Cursor::new(data)).with_guessed_format()?;
+        reader.limits(self.limits.decoder_limits());
+        let mut decoder = reader.into_decoder().map_err(invalid)?;
+        let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+        let mut img = image::DynamicImage::from_decoder(decoder).map_err(invalid)?;
+        // Renditions carry no EXIF, so bake the orientation into the pixels
+        img.apply_orientation(orientation);
+        Ok(img)
+    }
 
-        image::load_from_memory(data)
-            .map_err(|e| AppError::validation(format!("Invalid image: {}", e)))?;
+    /// Identifies an upload by its magic bytes and checks it against the
+    /// limits. `head` is the start of the file; dimensions are checked when
+    /// the header fits into it and left to the decoder limits otherwise.
+    pub fn inspect(&self, head: &[u8]) -> Result<ImageInfo> {
+        let format = image::guess_format(head).map_err(|_| {
+            AppError::new(ErrorCode::UnsupportedMediaType, "Content is not a recognised image format")
+        })?;
+        self.limits.check_format(format)?;
+
+        let mut reader = image::ImageReader::with_format(std::io::Cursor::new(head), format);
+        reader.limits(self.limits.decoder_limits());
+        let dimensions = match reader.into_dimensions() {
+            Ok(dimensions) => Some(dimensions),
+            Err(image::ImageError::Limits(_)) => {
+                return Err(AppError::validation(format!(
+                    "Image exceeds {}x{} pixels",
+                    self.limits.max_width, self.limits.max_height
+                )));
+            }
+            Err(_) => None,
+        };
 
-        Ok(())
+        Ok(ImageInfo { format, dimensions })
     }
 }
 
@@ -393,149 +979,490 @@ impl ImageUrlResolver {
 // HTTP Handlers
 // ============================================================================
 
-pub async fn upload_image_handler(
-    State(state): State<Arc<AppState>>,
-    mut multipart: Multipart,
-) -> Result<Json<UploadResponse>> {
-    let user_id = Uuid::now_v7();
-    let node_id = Uuid::now_v7();
-
-    while let Some(field) = multipart
-        .next_field()
+pub(crate) async fn analyse_quality(state: &AppState, storage_path: &str) -> Result<ImageQuality> {
+    let data = state.storage.download_image(storage_path).await?;
+    let processor = state.image_processor.clone();
+    tokio::task::spawn_blocking(move || processor.analyze_quality(&data))
         .await
-        .map_err(|e| AppError::bad_request(format!("Multipart: {}", e)))?
-    {
-        if field.name() == Some("image") {
-            let filename = field
-                .file_name()
-                .ok_or_else(|| AppError::bad_request("Missing filename"))?
-                .to_string();
-
-            let data = field
-                .bytes()
-                .await
-                .map_err(|e| AppError::bad_request(format!("Read: {}", e)))?;
+        .map_err(|e| AppError::internal(format!("Quality task failed: {}", e)))?
+}
 
-            state.image_processor.validate_image(&data, 10)?;
+/// Analyses an image leaf again and records the result on every leaf
+/// sharing its content
+pub async fn update_image_quality(state: &AppState, image: &NodeSummary) -> Result<ImageQuality> {
+    let storage_path = image.data["storage_path"]
+        .as_str()
+        .ok_or_else(|| AppError::bad_request("No storage path"))?;
+    let quality = analyse_quality(state, storage_path).await?;
+
+    sqlx::query!(
+        r#"
+        UPDATE tree_nodes SET data = jsonb_set(data, '{quality}', $3)
+        WHERE node_type = 'ImageLeaf' AND (id = $1 OR data->>'hash' = $2)
+        "#,
+        image.id,
+        image.data["hash"].as_str(),
+        serde_json::to_value(&quality)?
+    )
+        .execute(&state.db)
+        .await?;
 
-            let result = state
-                .storage
-                .upload_image(&user_id, &node_id, data, &filename)
-                .await?;
+    Ok(quality)
+}
 
-            sqlx::query!(
-                r#"
-                INSERT INTO tree_nodes (id, user_id, parent_id, node_type, data)
-                VALUES ($1, $2, $3, $4, $5)
-                "#,
-                node_id,      // TODO id is auto!!
-                user_id,      // TODO it not need
-                None::<Uuid>, // TODO it must be!
-                NodeType::ImageLeaf as NodeType , //"node_type_enum: ImageLeaf", // NodeType::ImageLeaf
-                serde_json::json!({
-                    "url": result.public_url,
-                    "storage_path": result.storage_path,
-                    "size": result.size,
-                    "mime_type": result.mime_type,
-                    "hash": result.hash,
-                })
-            )
-                .execute(&state.db)
-                .await?;
+/// Generates the configured renditions and the perceptual hash of a blob
+/// and records them on every ImageLeaf that shares it. Renditions already
+/// in storage are reused.
+pub async fn generate_renditions(state: &AppState, hash: &str, original_path: &str) -> Result<()> {
+    let mut original: Option<Bytes> = None;
+    let unhashed = sqlx::query_scalar!(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM tree_nodes
+            WHERE node_type = 'ImageLeaf' AND data->>'hash' = $1 AND perceptual_hash IS NULL
+        ) AS "unhashed!"
+        "#,
+        hash
+    )
+        .fetch_one(&state.db)
+        .await?;
+    if unhashed {
+        let data = original.insert(state.storage.download_image(original_path).await?).clone();
+        let processor = state.image_processor.clone();
+        let perceptual_hash = tokio::task::spawn_blocking(move || processor.perceptual_hash(&data))
+            .await
+            .map_err(|e| AppError::internal(format!("Perceptual hash task failed: {}", e)))??;
+        sqlx::query!(
+            r#"
+            UPDATE tree_nodes SET perceptual_hash = $2
+            WHERE node_type = 'ImageLeaf' AND data->>'hash' = $1
+            "#,
+            hash,
+            perceptual_hash::to_db(perceptual_hash)
+        )
+            .execute(&state.db)
+            .await?;
+    }
+
+    let specs = &state.upload_config.renditions;
+    if specs.is_empty() {
+        return Ok(());
+    }
 
-            return Ok(Json(UploadResponse {
-                node_id,
-                url: result.public_url,
-                storage_path: result.storage_path,
-                size: result.size,
-            }));
+    let mut renditions = BTreeMap::new();
+    for spec in specs {
+        let storage_path = StorageService::rendition_path(hash, spec);
+        if !state.storage.exists(&storage_path).await? {
+            let data = match &original {
+                Some(data) => data.clone(),
+                None => original.insert(state.storage.download_image(original_path).await?).clone(),
+            };
+            let processor = state.image_processor.clone();
+            let task_spec = spec.clone();
+            let rendered = tokio::task::spawn_blocking(move || processor.render(&data, &task_spec))
+                .await
+                .map_err(|e| AppError::internal(format!("Rendition task failed: {}", e)))??;
+            state
+                .storage
+                .upload_blob(&storage_path, rendered, spec.format.mime_type())
+                .await?;
         }
+
+        renditions.insert(
+            spec.name.clone(),
+            Rendition {
+                url: state.storage.public_url(&storage_path),
+                storage_path,
+                mime_type: spec.format.mime_type().to_string(),
+                max_size: spec.max_size,
+            },
+        );
     }
 
-    Err(AppError::bad_request("No image field"))
+    sqlx::query!(
+        r#"
+        UPDATE tree_nodes SET data = jsonb_set(data, '{renditions}', $2)
+        WHERE node_type = 'ImageLeaf' AND data->>'hash' = $1
+        "#,
+        hash,
+        serde_json::to_value(&renditions)?
+    )
+        .execute(&state.db)
+        .await?;
+
+    Ok(())
 }
 
-pub async fn get_image_handler(
-    State(state): State<Arc<AppState>>,
-    Path(node_id): Path<Uuid>,
-) -> Result<Json<serde_json::Value>> {
-    let user_id = Uuid::now_v7();
+/// Node data of the original, or of the rendition named by `size`.
+/// Renditions are generated in the background; known sizes fall back to
+/// the original until theirs exists.
+pub(crate) fn image_variant<'a>(
+    state: &AppState,
+    data: &'a serde_json::Value,
+    size: Option<&str>,
+) -> Result<&'a serde_json::Value> {
+    let Some(size) = size else {
+        return Ok(data);
+    };
+    let rendition = &data["renditions"][size];
+    if rendition.is_object() {
+        Ok(rendition)
+    } else if size == ORIGINAL_SIZE || state.upload_config.renditions.iter().any(|spec| spec.name == size) {
+        Ok(data)
+    } else {
+        Err(AppError::bad_request(format!("Unknown image size '{}'", size)))
+    }
+}
 
-    let node = sqlx::query!(
-        r#"SELECT data FROM tree_nodes WHERE id = $1 AND user_id = $2 AND node_type = 'ImageLeaf'"#,
-        node_id,
-        user_id
+/// Compares two image leaves the user can access pixel by pixel. The
+/// result is stored per pair of blobs, so repeated comparisons and copies
+/// of the same photos are answered without redoing the work.
+pub async fn compare_images(
+    state: &AppState,
+    user: Option<&str>,
+    before_id: &Uuid,
+    after_id: &Uuid,
+) -> Result<ImageComparison> {
+    let before = tree::get_node(&state.db, user, before_id).await?;
+    let after = tree::get_node(&state.db, user, after_id).await?;
+    let hash = |node: &NodeSummary| {
+        if node.node_type != NodeType::ImageLeaf {
+            return Err(AppError::bad_request(format!("Node {} is not an image", node.id)));
+        }
+        node.data["hash"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| AppError::bad_request(format!("Image {} has no content hash", node.id)))
+    };
+    let (before_hash, after_hash) = (hash(&before)?, hash(&after)?);
+
+    let known = sqlx::query!(
+        r#"
+        SELECT storage_path, changed_percent, offset_x, offset_y FROM image_diffs
+        WHERE before_hash = $1 AND after_hash = $2
+        "#,
+        before_hash,
+        after_hash
     )
-        .fetch_one(&state.db)
+        .fetch_optional(&state.db)
+        .await?;
+    if let Some(known) = known {
+        return Ok(ImageComparison {
+            before: *before_id,
+            after: *after_id,
+            changed_percent: known.changed_percent,
+            overlay_url: state.storage.public_url(&known.storage_path),
+            offset: (known.offset_x, known.offset_y),
+        });
+    }
+
+    // Previews are plenty for a comparison at working size
+    let source_path = |node: &NodeSummary| -> Result<String> {
+        let data = image_variant(state, &node.data, Some(PREVIEW_RENDITION)).unwrap_or(&node.data);
+        data["storage_path"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| AppError::bad_request("No storage path"))
+    };
+    let before_data = state.storage.download_image(&source_path(&before)?).await?;
+    let after_data = state.storage.download_image(&source_path(&after)?).await?;
+    let processor = state.image_processor.clone();
+    let (diff, overlay) = tokio::task::spawn_blocking(move || processor.compare(&before_data, &after_data))
+        .await
+        .map_err(|e| AppError::internal(format!("Comparison task failed: {}", e)))??;
+
+    let storage_path = StorageService::diff_path(&before_hash, &after_hash);
+    state.storage.upload_blob(&storage_path, overlay, "image/jpeg").await?;
+    sqlx::query!(
+        r#"
+        INSERT INTO image_diffs (before_hash, after_hash, storage_path, changed_percent, offset_x, offset_y)
+        VALUES ($1, $2, $3, $4, $5, $6)
+        ON CONFLICT (before_hash, after_hash) DO UPDATE
+        SET storage_path = EXCLUDED.storage_path,
+            changed_percent = EXCLUDED.changed_percent,
+            offset_x = EXCLUDED.offset_x,
+            offset_y = EXCLUDED.offset_y,
+            created_at = now()
+        "#,
+        before_hash,
+        after_hash,
+        storage_path,
+        diff.changed_percent,
+        diff.offset.0,
+        diff.offset.1
+    )
+        .execute(&state.db)
         .await?;
 
-    Ok(Json(node.data))
+    Ok(ImageComparison {
+        before: *before_id,
+        after: *after_id,
+        changed_percent: diff.changed_percent,
+        overlay_url: state.storage.public_url(&storage_path),
+        offset: diff.offset,
+    })
+}
+
+/// Highlights what changed between two photos of the same spot
+///
+/// POST /api/images/compare
+pub async fn compare_images_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<CompareImagesRequest>,
+) -> Result<Json<ImageComparison>> {
+    let user = user_id.to_string();
+    let comparison = compare_images(&state, Some(&user), &request.before, &request.after).await?;
+    Ok(Json(comparison))
 }
 
+/// Deletes an image leaf; its blob goes with the last leaf showing it
+///
+/// DELETE /api/images/{node_id}
 pub async fn delete_image_handler(
     State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
     Path(node_id): Path<Uuid>,
 ) -> Result<StatusCode> {
-    let user_id = Uuid::now_v7();
+    tree::require_access(&state.db, Some(&user_id.to_string()), &node_id).await?;
 
+    // Seeing a shared tree doesn't allow deleting the owner's images
+    let mut tx = state.db.begin().await?;
     let node = sqlx::query!(
-        r#"SELECT data FROM tree_nodes WHERE id = $1 AND user_id = $2 AND node_type = 'ImageLeaf'"#,
+        r#"DELETE FROM tree_nodes WHERE id = $1 AND node_type = 'ImageLeaf' AND user_id = $2 RETURNING data"#,
         node_id,
         user_id
     )
-        .fetch_one(&state.db)
-        .await?;
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or_else(|| AppError::not_found("Image"))?;
+
+    let doomed = match node.data.get("hash").and_then(|v| v.as_str()) {
+        Some(hash) => release_blob(&state.storage, &mut tx, hash).await?,
+        // Leaves from before content addressing own their object
+        None => node
+            .data
+            .get("storage_path")
+            .and_then(|v| v.as_str())
+            .map(|storage_path| vec![storage_path.to_string()])
+            .unwrap_or_default(),
+    };
+
+    tx.commit().await?;
+    // Objects left behind are swept as orphans
+    if let Err(e) = state.storage.delete_batch(doomed).await {
+        log::warn!("Failed to delete objects of image {}: {}", node_id, e);
+    }
 
-    let storage_path = node
-        .data
-        .get("storage_path")
-        .and_then(|v| v.as_str())
-        .ok_or_else(|| AppError::bad_request("No storage path"))?;
+    Ok(StatusCode::NO_CONTENT)
+}
 
-    state.storage.delete_image(storage_path).await?;
+/// Drops a blob's rows once no ImageLeaf references it any more. Must run
+/// in the transaction that removed the reference, after the delete.
+/// Returns the objects to delete once that transaction has committed, so a
+/// failed commit leaves no rows pointing at deleted objects.
+pub async fn release_blob(
+    storage: &StorageService,
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    hash: &str,
+) -> Result<Vec<String>> {
+    let unreferenced = sqlx::query!(
+        r#"SELECT storage_path FROM image_blobs WHERE hash = $1 AND ref_count = 0 FOR UPDATE"#,
+        hash
+    )
+        .fetch_optional(&mut **tx)
+        .await?;
 
-    sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", node_id)
-        .execute(&state.db)
+    let Some(blob) = unreferenced else {
+        return Ok(Vec::new());
+    };
+
+    // Heatmaps against this blob live with the other image's renditions
+    let mut doomed = sqlx::query_scalar!(
+        "DELETE FROM image_diffs WHERE before_hash = $1 OR after_hash = $1 RETURNING storage_path",
+        hash
+    )
+        .fetch_all(&mut **tx)
+        .await?;
+    sqlx::query!("DELETE FROM image_descriptions WHERE image_hash = $1", hash)
+        .execute(&mut **tx)
+        .await?;
+    sqlx::query!("DELETE FROM image_blobs WHERE hash = $1", hash)
+        .execute(&mut **tx)
         .await?;
 
-    Ok(StatusCode::NO_CONTENT)
+    doomed.extend(storage.list_renditions(hash).await?);
+    doomed.push(blob.storage_path);
+    Ok(doomed)
 }
 
-pub async fn batch_upload_handler(
-    State(state): State<Arc<AppState>>,
-    mut multipart: Multipart,
-) -> Result<Json<Vec<UploadResponse>>> {
-    let user_id = Uuid::now_v7();
-    let mut responses = Vec::new();
 
-    while let Some(field) = multipart
-        .next_field()
-        .await
-        .map_err(|e| AppError::bad_request(format!("Multipart: {}", e)))?
-    {
-        if field.name() == Some("images") {
-            let filename = field.file_name().unwrap_or("image.jpg").to_string();
-            let data = field
-                .bytes()
-                .await
-                .map_err(|e| AppError::bad_request(format!("Read: {}", e)))?;
+// ============================================================================
+// Tests
+// ============================================================================
 
-            let node_id = Uuid::now_v7();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            if let Ok(result) = state
-                .storage
-                .upload_image(&user_id, &node_id, data, &filename)
-                .await
-            {
-                responses.push(UploadResponse {
-                    node_id,
-                    url: result.public_url.clone(),
-                    storage_path: result.storage_path.clone(),
-                    size: result.size,
-                });
+    #[test]
+    fn test_default_ai_config_is_valid() {
+        assert!(AiConfig::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_ai_config_validation_errors() {
+        let config = AiConfig {
+            url: "postgres://localhost/db".to_string(),
+            keep_alive: "forever".to_string(),
+            chat: ModelParams {
+                temperature: 3.0,
+                max_tokens: 4096,
+                context_window: 1024,
+            },
+            ..AiConfig::default()
+        };
+
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.code, ErrorCode::ValidationError);
+        let fields: Vec<String> = err.details.unwrap()["errors"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| e["field"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(
+            fields,
+            vec!["OLLAMA_URL", "CHAT_TEMPERATURE", "CHAT_CONTEXT_WINDOW", "AI_KEEP_ALIVE"]
+        );
+    }
+
+    #[test]
+    fn test_tools_mode_needs_tool_models() {
+        let config = AiConfig {
+            mode: AgentMode::Tools,
+            ..AiConfig::default()
+        };
+        let err = config.validate().unwrap_err();
+        assert_eq!(err.details.unwrap()["errors"][0]["field"], "AI_AGENT_MODE");
+
+        let config = AiConfig {
+            mode: AgentMode::Tools,
+            text_models: vec!["llama3.1:8b".to_string()],
+            ..AiConfig::default()
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_retry_backoff_is_capped() {
+        let retry = RetryPolicy {
+            max_retries: 5,
+            initial_backoff: std::time::Duration::from_millis(100),
+            max_backoff: std::time::Duration::from_millis(350),
+        };
+        let delays: Vec<u128> = (0..4).map(|n| retry.backoff(n).as_millis()).collect();
+        assert_eq!(delays, vec![100, 200, 350, 350]);
+    }
+
+    #[test]
+    fn test_keep_alive_formats() {
+        for value in ["5m", "30s", "1h", "0", "-1", "300"] {
+            assert!(is_valid_keep_alive(value), "{}", value);
+        }
+        for value in ["", "m", "5ms", "five"] {
+            assert!(!is_valid_keep_alive(value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn test_content_path_shards_by_hash() {
+        let hash = "ab12cd";
+        assert_eq!(StorageService::content_path(hash, ImageFormat::Jpeg), "blobs/ab/ab12cd.jpg");
+        assert_eq!(StorageService::content_path(hash, ImageFormat::WebP), "blobs/ab/ab12cd.webp");
+    }
+
+    #[test]
+    fn test_duplicate_policy_parsing() {
+        assert_eq!("reuse".parse::<DuplicatePolicy>().unwrap(), DuplicatePolicy::Reuse);
+        assert_eq!("Reject".parse::<DuplicatePolicy>().unwrap(), DuplicatePolicy::Reject);
+        assert!("ignore".parse::<DuplicatePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_rendition_spec_parsing() {
+        assert_eq!(
+            "thumb:256:webp".parse::<RenditionSpec>().unwrap(),
+            RenditionSpec {
+                name: "thumb".to_string(),
+                max_size: 256,
+                format: RenditionFormat::WebP,
             }
+        );
+        for invalid in ["thumb:256", "thumb:0:jpeg", "original:256:jpeg", "thumb:256:gif"] {
+            assert!(invalid.parse::<RenditionSpec>().is_err(), "{}", invalid);
         }
     }
 
-    Ok(Json(responses))
+    #[tokio::test]
+    async fn test_render_fits_longest_side() {
+        let state = crate::init::test_state(AiConfig::default());
+        let png = encode(400, 100, ImageFormat::Png);
+
+        for format in [RenditionFormat::Jpeg, RenditionFormat::WebP, RenditionFormat::Png] {
+            let spec = RenditionSpec {
+                name: "thumb".to_string(),
+                max_size: 200,
+                format,
+            };
+            let rendered = state.image_processor.render(&png, &spec).unwrap();
+            let img = image::load_from_memory(&rendered).unwrap();
+            assert_eq!((img.width(), img.height()), (200, 50), "{:?}", format);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_crop_pads_annotated_region() {
+        let state = crate::init::test_state(AiConfig::default());
+        let png = encode(400, 100, ImageFormat::Png);
+        let shape = AnnotationShape::Rectangle { x: 0.5, y: 0.2, width: 0.2, height: 0.4 };
+
+        let cropped = state.image_processor.crop(&png, &shape, 1000).unwrap();
+        let img = image::load_from_memory(&cropped).unwrap();
+        assert_eq!((img.width(), img.height()), (120, 60));
+
+        let cropped = state.image_processor.crop(&png, &shape, 100).unwrap();
+        let img = image::load_from_memory(&cropped).unwrap();
+        assert_eq!((img.width(), img.height()), (100, 50));
+    }
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut data = Vec::new();
+        image::DynamicImage::new_rgb8(width, height)
+            .write_to(&mut std::io::Cursor::new(&mut data), format)
+            .unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn test_inspect_sniffs_and_limits() {
+        let processor = ImageProcessor::new(
+            crate::init::test_state(AiConfig::default()).storage.clone(),
+            ImageLimits {
+                max_width: 50,
+                max_height: 50,
+                ..ImageLimits::default()
+            },
+        );
+
+        let info = processor.inspect(&encode(40, 10, ImageFormat::Png)).unwrap();
+        assert_eq!(info.format, ImageFormat::Png);
+        assert_eq!(info.dimensions, Some((40, 10)));
+
+        let err = processor.inspect(&encode(100, 10, ImageFormat::Png)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::ValidationError);
+
+        let err = processor.inspect(&encode(10, 10, ImageFormat::Gif)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnsupportedMediaType);
+    }
 }
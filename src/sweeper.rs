@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use uuid::Uuid;
+use crate::error::Result;
+use crate::storage::{env_or, release_blob};
+use crate::AppState;
+
+// ============================================================================
+// Configuration
+// ============================================================================
+
+/// Prefixes the database is the source of truth for
+const SWEPT_PREFIXES: [&str; 4] = ["images/", "blobs/", "renditions/", "uploads/"];
+
+#[derive(Debug, Clone)]
+pub struct SweepConfig {
+    /// Time between sweeps; zero disables the background sweeper
+    pub interval: Duration,
+    /// Orphans younger than this are reported but never deleted, so uploads
+    /// whose database rows are still being written survive
+    pub grace: Duration,
+    /// Delete orphans, unreferenced blobs and expired uploads instead of
+    /// only reporting them
+    pub delete: bool,
+}
+
+impl Default for SweepConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(24 * 60 * 60),
+            grace: Duration::from_secs(24 * 60 * 60),
+            delete: false,
+        }
+    }
+}
+
+impl SweepConfig {
+    pub fn from_env() -> std::result::Result<Self, Box<dyn std::error::Error>> {
+        let default = Self::default();
+        Ok(Self {
+            interval: Duration::from_secs(env_or("SWEEP_INTERVAL_SECS", default.interval.as_secs())?),
+            grace: Duration::from_secs(env_or("SWEEP_GRACE_SECS", default.grace.as_secs())?),
+            delete: env_or("SWEEP_DELETE_ORPHANS", default.delete)?,
+        })
+    }
+}
+
+// ============================================================================
+// Report
+// ============================================================================
+
+/// A stored object nothing in the database refers to
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanObject {
+    pub storage_path: String,
+    pub last_modified: Option<DateTime<Utc>>,
+    pub deleted: bool,
+}
+
+/// A database reference to an object that doesn't exist
+#[derive(Debug, Clone, Serialize)]
+pub struct DanglingReference {
+    pub storage_path: String,
+    pub hash: Option<String>,
+    /// Referencing leaf; `None` for the `image_blobs` row itself
+    pub node_id: Option<Uuid>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct SweepReport {
+    pub orphans: Vec<OrphanObject>,
+    pub dangling: Vec<DanglingReference>,
+    /// Hashes of blobs no leaf refers to any more
+    pub unreferenced_blobs: Vec<String>,
+    /// Presigned uploads that expired without being finalized
+    pub expired_uploads: Vec<Uuid>,
+    /// Abandoned staging data of streamed uploads
+    pub abandoned_staging: Vec<String>,
+    /// Whether the findings above were removed or only reported
+    pub deleted: bool,
+}
+
+impl SweepReport {
+    pub fn is_clean(&self) -> bool {
+        self.orphans.is_empty()
+            && self.dangling.is_empty()
+            && self.unreferenced_blobs.is_empty()
+            && self.expired_uploads.is_empty()
+            && self.abandoned_staging.is_empty()
+    }
+
+    fn log(&self) {
+        if self.is_clean() {
+            log::info!("🧹 Storage sweep: database and storage are consistent");
+            return;
+        }
+        for orphan in &self.orphans {
+            log::warn!("🧹 Orphaned object {} (deleted: {})", orphan.storage_path, orphan.deleted);
+        }
+        for reference in &self.dangling {
+            log::warn!(
+                "🧹 Dangling reference to {} (hash {:?}, node {:?})",
+                reference.storage_path, reference.hash, reference.node_id
+            );
+        }
+        log::info!(
+            "🧹 Storage sweep: {} orphans, {} dangling, {} unreferenced blobs, {} expired uploads, {} abandoned staging{}",
+            self.orphans.len(),
+            self.dangling.len(),
+            self.unreferenced_blobs.len(),
+            self.expired_uploads.len(),
+            self.abandoned_staging.len(),
+            if self.deleted { " (cleaned up)" } else { "" }
+        );
+    }
+}
+
+// ============================================================================
+// Sweeper
+// ============================================================================
+
+/// Runs [`sweep`] every `config.interval` until the process exits
+pub fn spawn(state: Arc<AppState>, config: SweepConfig) {
+    if config.interval.is_zero() {
+        return;
+    }
+    tokio::spawn(async move {
+        let start = tokio::time::Instant::now() + config.interval;
+        let mut interval = tokio::time::interval_at(start, config.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        loop {
+            interval.tick().await;
+            match sweep(&state, &config).await {
+                Ok(report) => report.log(),
+                Err(e) => log::error!("❌ Storage sweep failed: {}", e),
+            }
+        }
+    });
+}
+
+/// Compares stored objects with what the database refers to. Reports
+/// orphaned objects, references to missing objects, blobs without leaves,
+/// expired presigned uploads and abandoned upload staging. With
+/// `config.delete`, removes all but the dangling references once they are
+/// older than the grace period.
+pub async fn sweep(state: &AppState, config: &SweepConfig) -> Result<SweepReport> {
+    let cutoff = Utc::now() - config.grace;
+    let mut report = SweepReport {
+        deleted: config.delete,
+        ..SweepReport::default()
+    };
+
+    // Expired uploads first, so their staging objects are swept as orphans
+    report.expired_uploads = sqlx::query_scalar!(
+        "SELECT id FROM pending_uploads WHERE expires_at < $1",
+        cutoff
+    )
+        .fetch_all(&state.db)
+        .await?;
+    if config.delete && !report.expired_uploads.is_empty() {
+        sqlx::query!("DELETE FROM pending_uploads WHERE id = ANY($1)", &report.expired_uploads)
+            .execute(&state.db)
+            .await?;
+    }
+
+    report.unreferenced_blobs = sqlx::query_scalar!(
+        "SELECT hash FROM image_blobs WHERE ref_count = 0 AND created_at < $1",
+        cutoff
+    )
+        .fetch_all(&state.db)
+        .await?;
+    if config.delete {
+        for hash in &report.unreferenced_blobs {
+            let mut tx = state.db.begin().await?;
+            let doomed = release_blob(&state.storage, &mut tx, hash).await?;
+            tx.commit().await?;
+            state.storage.delete_batch(doomed).await?;
+        }
+    }
+
+    // List before reading references: an object stored in between then
+    // shows up as referenced rather than orphaned
+    let mut stored = HashSet::new();
+    for prefix in SWEPT_PREFIXES {
+        stored.extend(state.storage.list_objects(prefix).await?);
+    }
+
+    let references = sqlx::query!(
+        r#"
+        SELECT storage_path AS "storage_path!", hash AS "hash?", NULL::uuid AS "node_id?"
+        FROM image_blobs
+        UNION ALL
+        SELECT data ->> 'storage_path', data ->> 'hash', id
+        FROM tree_nodes
+        WHERE node_type = 'ImageLeaf' AND data ? 'storage_path'
+        UNION ALL
+        SELECT r.value ->> 'storage_path', t.data ->> 'hash', t.id
+        FROM tree_nodes t, jsonb_each(t.data -> 'renditions') r
+        WHERE t.node_type = 'ImageLeaf' AND jsonb_typeof(t.data -> 'renditions') = 'object'
+        UNION ALL
+        SELECT storage_path, after_hash, NULL
+        FROM image_diffs
+        "#
+    )
+        .fetch_all(&state.db)
+        .await?;
+    let pending = sqlx::query_scalar!("SELECT storage_path FROM pending_uploads")
+        .fetch_all(&state.db)
+        .await?;
+
+    let mut known: HashSet<String> = pending.into_iter().collect();
+    for reference in references {
+        if !stored.contains(&reference.storage_path)
+            && !state.storage.exists(&reference.storage_path).await?
+        {
+            report.dangling.push(DanglingReference {
+                storage_path: reference.storage_path.clone(),
+                hash: reference.hash,
+                node_id: reference.node_id,
+            });
+        }
+        known.insert(reference.storage_path);
+    }
+
+    let mut orphans: Vec<_> = stored.into_iter().filter(|path| !known.contains(path)).collect();
+    orphans.sort();
+    for storage_path in orphans {
+        let last_modified = match state.storage.get_metadata(&storage_path).await {
+            Ok(metadata) => metadata.last_modified.as_deref().and_then(parse_timestamp),
+            // Gone since it was listed
+            Err(e) if e.code.http_status() == 404 => continue,
+            Err(e) => return Err(e),
+        };
+        // Objects of unknown age are never old enough to delete
+        let deleted = config.delete && last_modified.is_some_and(|t| t < cutoff);
+        if deleted {
+            state.storage.delete_image(&storage_path).await?;
+        }
+        report.orphans.push(OrphanObject {
+            storage_path,
+            last_modified,
+            deleted,
+        });
+    }
+
+    report.abandoned_staging = state.storage.purge_staging(cutoff, !config.delete).await?;
+
+    Ok(report)
+}
+
+/// Object timestamps are RFC 3339 on local disk and in S3 listings, but
+/// RFC 2822 (HTTP dates) in S3 `HEAD` responses
+fn parse_timestamp(value: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(value)
+        .or_else(|_| DateTime::parse_from_rfc2822(value))
+        .map(|t| t.with_timezone(&Utc))
+        .ok()
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = "2025-03-14T07:30:00+00:00";
+        assert_eq!(parse_timestamp("2025-03-14T07:30:00Z").unwrap().to_rfc3339(), expected);
+        assert_eq!(parse_timestamp("Fri, 14 Mar 2025 07:30:00 GMT").unwrap().to_rfc3339(), expected);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    /// Stores an object in `state` and backdates it to `modified`
+    async fn store(state: &AppState, root: &std::path::Path, path: &str, modified: DateTime<Utc>) {
+        state
+            .storage
+            .upload_blob(path, bytes::Bytes::from_static(b"image"), "image/png")
+            .await
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(root.join(path))
+            .unwrap()
+            .set_modified(modified.into())
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_sweep_respects_grace_and_dry_run() {
+        let root = std::env::temp_dir().join(format!("cx58-sweep-{}", Uuid::now_v7()));
+        let mut state = (*crate::init::test_db_state(crate::AiConfig::default()).await).clone();
+        state.storage = Arc::new(crate::storage::StorageService::local(
+            crate::blob_store::LocalStore::new(&root, "http://localhost:3000".to_string(), b"test".to_vec()).unwrap(),
+        ));
+        let db = &state.db;
+        let old = Utc::now() - chrono::Duration::hours(2);
+        let id = Uuid::now_v7();
+
+        let old_orphan = format!("blobs/zz/{}-old.png", id);
+        let young_orphan = format!("blobs/zz/{}-young.png", id);
+        store(&state, &root, &old_orphan, old).await;
+        store(&state, &root, &young_orphan, Utc::now()).await;
+
+        let hash = format!("test-{}", id);
+        let blob_path = format!("blobs/zz/{}.png", hash);
+        store(&state, &root, &blob_path, old).await;
+        sqlx::query!(
+            "INSERT INTO image_blobs (hash, storage_path, size, mime_type, ref_count, created_at)
+             VALUES ($1, $2, 5, 'image/png', 0, $3)",
+            hash,
+            blob_path,
+            old
+        )
+        .execute(db)
+        .await
+        .unwrap();
+
+        let user_id = Uuid::now_v7();
+        let root_id = crate::init::test_root(db, &user_id).await;
+        let missing = format!("images/{}.png", id);
+        let leaf_id = sqlx::query_scalar!(
+            "INSERT INTO tree_nodes (user_id, parent_id, node_type, data) VALUES ($1, $2, 'ImageLeaf', $3) RETURNING id",
+            user_id,
+            root_id,
+            serde_json::json!({ "storage_path": missing })
+        )
+        .fetch_one(db)
+        .await
+        .unwrap();
+
+        let mut config = SweepConfig {
+            grace: Duration::from_secs(60 * 60),
+            ..SweepConfig::default()
+        };
+        let orphans = |report: &SweepReport| {
+            report
+                .orphans
+                .iter()
+                .map(|orphan| (orphan.storage_path.clone(), orphan.deleted))
+                .collect::<Vec<_>>()
+        };
+        let report = sweep(&state, &config).await.unwrap();
+        assert_eq!(orphans(&report), vec![(old_orphan.clone(), false), (young_orphan.clone(), false)]);
+        assert!(report.unreferenced_blobs.contains(&hash));
+        let dangling: Vec<_> = report.dangling.iter().filter(|r| r.storage_path == missing).collect();
+        assert_eq!(dangling.len(), 1);
+        assert_eq!(dangling[0].node_id, Some(leaf_id));
+        for path in [&old_orphan, &young_orphan, &blob_path] {
+            assert!(state.storage.exists(path).await.unwrap(), "{}", path);
+        }
+
+        config.delete = true;
+        let report = sweep(&state, &config).await.unwrap();
+        assert_eq!(orphans(&report), vec![(old_orphan.clone(), true), (young_orphan.clone(), false)]);
+        assert!(report.unreferenced_blobs.contains(&hash));
+        assert!(report.dangling.iter().any(|r| r.storage_path == missing));
+        assert!(!state.storage.exists(&old_orphan).await.unwrap());
+        assert!(state.storage.exists(&young_orphan).await.unwrap());
+        assert!(!state.storage.exists(&blob_path).await.unwrap());
+        let blob = sqlx::query_scalar!("SELECT hash FROM image_blobs WHERE hash = $1", hash)
+            .fetch_optional(db)
+            .await
+            .unwrap();
+        assert_eq!(blob, None);
+
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+}
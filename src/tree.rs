@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use chrono::{DateTime, NaiveDate, Utc};
+use uuid::Uuid;
+use crate::error::{AppError, Result};
+use crate::models::{NodeSummary, NodeType, RootSettings};
+use crate::perceptual_hash;
+
+// ============================================================================
+// Access Checks
+// ============================================================================
+
+/// Maximum number of rows returned by a single tree query
+pub const QUERY_LIMIT: i64 = 100;
+
+/// A user can see a node when they own it or were granted access to it or
+/// one of its ancestors through `node_access`.
+pub async fn can_access(db: &sqlx::PgPool, user: &str, node_id: &Uuid) -> Result<bool> {
+    let row = sqlx::query!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id, user_id FROM tree_nodes WHERE id = $1
+            UNION ALL
+            SELECT t.id, t.parent_id, t.user_id FROM tree_nodes t
+            INNER JOIN ancestors a ON t.id = a.parent_id
+        )
+        SELECT EXISTS (
+            SELECT 1 FROM ancestors a
+            WHERE a.user_id::text = $2
+               OR EXISTS (SELECT 1 FROM node_access na WHERE na.node_id = a.id AND na.user_id = $2)
+        ) AS "allowed!"
+        "#,
+        node_id,
+        user
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.allowed)
+}
+
+/// Fails with `Forbidden` unless `user` can see `node_id`
+pub async fn require_access(db: &sqlx::PgPool, user: Option<&str>, node_id: &Uuid) -> Result<()> {
+    let user = user.ok_or_else(|| AppError::unauthorized("User is not identified"))?;
+    if can_access(db, user, node_id).await? {
+        Ok(())
+    } else {
+        Err(AppError::forbidden(format!("No access to node {}", node_id)))
+    }
+}
+
+// ============================================================================
+// Queries
+// ============================================================================
+
+struct NodeRow {
+    id: Uuid,
+    parent_id: Option<Uuid>,
+    name: Option<String>,
+    node_type: NodeType,
+    data: serde_json::Value,
+    created_at: DateTime<Utc>,
+}
+
+impl From<NodeRow> for NodeSummary {
+    fn from(row: NodeRow) -> Self {
+        Self {
+            id: row.id,
+            parent_id: row.parent_id,
+            name: row.name,
+            node_type: row.node_type,
+            data: row.data,
+            created_at: row.created_at.to_rfc3339(),
+        }
+    }
+}
+
+pub async fn get_node(db: &sqlx::PgPool, user: Option<&str>, node_id: &Uuid) -> Result<NodeSummary> {
+    require_access(db, user, node_id).await?;
+
+    let row = sqlx::query_as!(
+        NodeRow,
+        r#"
+        SELECT id, parent_id, name, node_type as "node_type: NodeType", data, created_at
+        FROM tree_nodes WHERE id = $1
+        "#,
+        node_id
+    )
+    .fetch_one(db)
+    .await?;
+
+    Ok(row.into())
+}
+
+pub async fn list_children(
+    db: &sqlx::PgPool,
+    user: Option<&str>,
+    node_id: &Uuid,
+) -> Result<Vec<NodeSummary>> {
+    require_access(db, user, node_id).await?;
+
+    let rows = sqlx::query_as!(
+        NodeRow,
+        r#"
+        SELECT id, parent_id, name, node_type as "node_type: NodeType", data, created_at
+        FROM tree_nodes WHERE parent_id = $1
+        ORDER BY created_at
+        LIMIT $2
+        "#,
+        node_id,
+        QUERY_LIMIT
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(NodeSummary::from).collect())
+}
+
+/// Case-insensitive search over node names, branch labels and root titles
+/// within every subtree visible to `user`
+pub async fn find_nodes(db: &sqlx::PgPool, user: Option<&str>, name: &str) -> Result<Vec<NodeSummary>> {
+    let user = user.ok_or_else(|| AppError::unauthorized("User is not identified"))?;
+    if name.trim().is_empty() {
+        return Err(AppError::bad_request("Search name must not be empty"));
+    }
+
+    let rows = sqlx::query_as!(
+        NodeRow,
+        r#"
+        WITH RECURSIVE visible AS (
+            SELECT id FROM tree_nodes WHERE user_id::text = $1
+            UNION
+            SELECT node_id FROM node_access WHERE user_id = $1
+            UNION
+            SELECT t.id FROM tree_nodes t INNER JOIN visible v ON t.parent_id = v.id
+        )
+        SELECT t.id, t.parent_id, t.name, t.node_type as "node_type: NodeType", t.data, t.created_at
+        FROM tree_nodes t
+        INNER JOIN visible v ON v.id = t.id
+        WHERE t.name ILIKE '%' || $2 || '%'
+           OR t.data->>'label' ILIKE '%' || $2 || '%'
+           OR t.data->>'title' ILIKE '%' || $2 || '%'
+        ORDER BY t.created_at
+        LIMIT $3
+        "#,
+        user,
+        name.trim(),
+        QUERY_LIMIT
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(NodeSummary::from).collect())
+}
+
+/// Image leaves anywhere under `node_id`, oldest first, optionally limited
+/// to a time range. Photos are dated by EXIF capture time when they have
+/// one and by upload time otherwise.
+pub async fn get_images(
+    db: &sqlx::PgPool,
+    user: Option<&str>,
+    node_id: &Uuid,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+) -> Result<Vec<NodeSummary>> {
+    require_access(db, user, node_id).await?;
+
+    let rows = sqlx::query_as!(
+        NodeRow,
+        r#"
+        WITH RECURSIVE subtree AS (
+            SELECT id FROM tree_nodes WHERE id = $1
+            UNION ALL
+            SELECT t.id FROM tree_nodes t INNER JOIN subtree s ON t.parent_id = s.id
+        )
+        SELECT t.id, t.parent_id, t.name, t.node_type as "node_type: NodeType", t.data, t.created_at
+        FROM tree_nodes t
+        INNER JOIN subtree s ON s.id = t.id
+        WHERE t.node_type = 'ImageLeaf'
+          AND ($2::timestamptz IS NULL OR COALESCE(t.captured_at, t.created_at) >= $2)
+          AND ($3::timestamptz IS NULL OR COALESCE(t.captured_at, t.created_at) <= $3)
+        ORDER BY COALESCE(t.captured_at, t.created_at)
+        LIMIT $4
+        "#,
+        node_id,
+        from,
+        to,
+        QUERY_LIMIT
+    )
+    .fetch_all(db)
+    .await?;
+
+    Ok(rows.into_iter().map(NodeSummary::from).collect())
+}
+
+/// Settings of the tree `node_id` is in, defaults when it has no root
+pub async fn root_settings(conn: &mut sqlx::PgConnection, node_id: &Uuid) -> Result<RootSettings> {
+    let settings = sqlx::query_scalar!(
+        r#"
+        WITH RECURSIVE ancestors AS (
+            SELECT id, parent_id, node_type, data FROM tree_nodes WHERE id = $1
+            UNION ALL
+            SELECT t.id, t.parent_id, t.node_type, t.data FROM tree_nodes t
+            INNER JOIN ancestors a ON t.id = a.parent_id
+        )
+        SELECT data->'settings' AS settings FROM ancestors WHERE node_type = 'Root' LIMIT 1
+        "#,
+        node_id
+    )
+    .fetch_optional(conn)
+    .await?
+    .flatten();
+
+    Ok(settings
+        .and_then(|settings| serde_json::from_value(settings).ok())
+        .unwrap_or_default())
+}
+
+/// Settings of a root `user` can see
+pub async fn get_root_settings(db: &sqlx::PgPool, user: Option<&str>, root_id: &Uuid) -> Result<RootSettings> {
+    let root = get_node(db, user, root_id).await?;
+    if root.node_type != NodeType::Root {
+        return Err(AppError::bad_request(format!("Node {} is not a root", root_id)));
+    }
+    Ok(serde_json::from_value(root.data["settings"].clone()).unwrap_or_default())
+}
+
+/// Replaces a root's settings; only its owner may
+pub async fn update_root_settings(
+    db: &sqlx::PgPool,
+    user_id: &Uuid,
+    root_id: &Uuid,
+    settings: &RootSettings,
+) -> Result<RootSettings> {
+    get_root_settings(db, Some(&user_id.to_string()), root_id).await?;
+    let updated = sqlx::query!(
+        r#"
+        UPDATE tree_nodes SET data = jsonb_set(data, '{settings}', $3)
+        WHERE id = $1 AND user_id = $2 AND node_type = 'Root'
+        "#,
+        root_id,
+        user_id,
+        serde_json::to_value(settings)?
+    )
+    .execute(db)
+    .await?;
+    if updated.rows_affected() == 0 {
+        return Err(AppError::forbidden("Only the owner can change tree settings"));
+    }
+    Ok(settings.clone())
+}
+
+// ============================================================================
+// Duplication
+// ============================================================================
+
+/// A node created by [`duplicate_subtree`]
+#[derive(Debug, Clone)]
+pub struct CopiedNode {
+    pub id: Uuid,
+    pub parent_id: Option<Uuid>,
+    pub node_type: NodeType,
+    /// Storage path the copy still shares with its source
+    pub storage_path: Option<String>,
+    /// Whether the image is a content-addressed blob, which copies may share
+    pub content_addressed: bool,
+}
+
+/// Copies `source_id` and everything below it under `parent_id`, owned by
+/// `user_id`. Image leaves are skipped unless `include_images`; copied
+/// leaves keep their data, so they point at the same stored objects. The
+/// copy of `source_id` itself is relabelled when `label` is given.
+pub async fn duplicate_subtree(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    user_id: &Uuid,
+    source_id: &Uuid,
+    parent_id: &Uuid,
+    label: Option<&str>,
+    include_images: bool,
+) -> Result<Vec<CopiedNode>> {
+    let rows = sqlx::query!(
+        r#"
+        WITH RECURSIVE subtree AS (
+            SELECT id FROM tree_nodes WHERE id = $1
+            UNION ALL
+            SELECT t.id FROM tree_nodes t INNER JOIN subtree s ON t.parent_id = s.id
+            WHERE $5 OR t.node_type <> 'ImageLeaf'
+        ),
+        mapping AS (
+            SELECT id AS old_id, gen_random_uuid() AS new_id FROM subtree
+        )
+        INSERT INTO tree_nodes (id, user_id, parent_id, name, node_type, data, captured_at, latitude, longitude,
+                                language, perceptual_hash)
+        SELECT m.new_id,
+               $3,
+               CASE WHEN t.id = $1 THEN $2 ELSE pm.new_id END,
+               CASE WHEN t.id = $1 THEN COALESCE($4, t.name) ELSE t.name END,
+               t.node_type,
+               CASE WHEN t.id = $1 AND $4::text IS NOT NULL THEN jsonb_set(t.data, '{label}', to_jsonb($4::text))
+                    ELSE t.data END,
+               t.captured_at,
+               t.latitude,
+               t.longitude,
+               t.language,
+               t.perceptual_hash
+        FROM tree_nodes t
+        INNER JOIN mapping m ON m.old_id = t.id
+        LEFT JOIN mapping pm ON pm.old_id = t.parent_id
+        RETURNING id, parent_id, node_type as "node_type: NodeType",
+                  data ->> 'storage_path' AS storage_path, data ? 'hash' AS "content_addressed!"
+        "#,
+        source_id,
+        parent_id,
+        user_id,
+        label,
+        include_images
+    )
+    .fetch_all(&mut **tx)
+    .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| CopiedNode {
+            id: row.id,
+            parent_id: row.parent_id,
+            node_type: row.node_type,
+            storage_path: row.storage_path,
+            content_addressed: row.content_addressed,
+        })
+        .collect())
+}
+
+// ============================================================================
+// Near-duplicates
+// ============================================================================
+
+/// Most images compared pairwise when clustering one subtree
+pub const CLUSTER_LIMIT: i64 = 2000;
+
+/// Groups of near-identical image leaves under `node_id`, oldest first
+/// within each group; see [`perceptual_hash::clusters`]. Images whose hash
+/// hasn't been computed yet are left out.
+pub async fn near_duplicates(
+    db: &sqlx::PgPool,
+    user: Option<&str>,
+    node_id: &Uuid,
+    max_distance: u32,
+) -> Result<Vec<Vec<NodeSummary>>> {
+    require_access(db, user, node_id).await?;
+
+    let rows = sqlx::query!(
+        r#"
+        WITH RECURSIVE subtree AS (
+            SELECT id FROM tree_nodes WHERE id = $1
+            UNION ALL
+            SELECT t.id FROM tree_nodes t INNER JOIN subtree s ON t.parent_id = s.id
+        )
+        SELECT t.id, t.parent_id, t.name, t.node_type as "node_type: NodeType", t.data, t.created_at,
+               t.perceptual_hash as "perceptual_hash!"
+        FROM tree_nodes t
+        INNER JOIN subtree s ON s.id = t.id
+        WHERE t.node_type = 'ImageLeaf' AND t.perceptual_hash IS NOT NULL
+        ORDER BY COALESCE(t.captured_at, t.created_at)
+        LIMIT $2
+        "#,
+        node_id,
+        CLUSTER_LIMIT
+    )
+    .fetch_all(db)
+    .await?;
+
+    let hashes: Vec<_> = rows
+        .iter()
+        .map(|row| (row.id, perceptual_hash::from_db(row.perceptual_hash)))
+        .collect();
+    let mut nodes: HashMap<Uuid, NodeSummary> = rows
+        .into_iter()
+        .map(|row| {
+            let node = NodeSummary::from(NodeRow {
+                id: row.id,
+                parent_id: row.parent_id,
+                name: row.name,
+                node_type: row.node_type,
+                data: row.data,
+                created_at: row.created_at,
+            });
+            (node.id, node)
+        })
+        .collect();
+
+    Ok(perceptual_hash::clusters(&hashes, max_distance)
+        .into_iter()
+        .map(|ids| ids.iter().filter_map(|id| nodes.remove(id)).collect())
+        .collect())
+}
+
+/// Drops images within `max_distance` of an earlier one in the list, so
+/// only the first of each run of near-identical shots is left
+pub async fn without_near_duplicates(
+    db: &sqlx::PgPool,
+    images: Vec<NodeSummary>,
+    max_distance: u32,
+) -> Result<Vec<NodeSummary>> {
+    let ids: Vec<Uuid> = images.iter().map(|image| image.id).collect();
+    let hashes: HashMap<Uuid, u64> = sqlx::query!(
+        r#"SELECT id, perceptual_hash as "perceptual_hash!" FROM tree_nodes WHERE id = ANY($1) AND perceptual_hash IS NOT NULL"#,
+        &ids
+    )
+    .fetch_all(db)
+    .await?
+    .into_iter()
+    .map(|row| (row.id, perceptual_hash::from_db(row.perceptual_hash)))
+    .collect();
+
+    let images = images
+        .into_iter()
+        .map(|image| {
+            let hash = hashes.get(&image.id).copied();
+            (image, hash)
+        })
+        .collect();
+    Ok(perceptual_hash::distinct(images, max_distance))
+}
+
+/// Parses an RFC 3339 timestamp or a plain `YYYY-MM-DD` date (start of day, UTC)
+pub fn parse_time(value: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap_or_default().and_utc())
+        .map_err(|_| AppError::bad_request(format!("Invalid date '{}', expected YYYY-MM-DD or RFC 3339", value)))
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(
+            parse_time("2025-12-01").unwrap().to_rfc3339(),
+            "2025-12-01T00:00:00+00:00"
+        );
+        assert_eq!(
+            parse_time("2025-12-01T17:00:00+03:00").unwrap().to_rfc3339(),
+            "2025-12-01T14:00:00+00:00"
+        );
+        assert!(parse_time("01.12.2025").is_err());
+    }
+
+    async fn insert_node(
+        db: &sqlx::PgPool,
+        user_id: &Uuid,
+        parent_id: &Uuid,
+        node_type: NodeType,
+        data: serde_json::Value,
+    ) -> Uuid {
+        sqlx::query_scalar!(
+            "INSERT INTO tree_nodes (user_id, parent_id, node_type, data) VALUES ($1, $2, $3, $4) RETURNING id",
+            user_id,
+            parent_id,
+            node_type as NodeType,
+            data
+        )
+        .fetch_one(db)
+        .await
+        .unwrap()
+    }
+
+    async fn blob_refs(db: &sqlx::PgPool, hash: &str) -> i32 {
+        sqlx::query_scalar!("SELECT ref_count FROM image_blobs WHERE hash = $1", hash)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_duplicate_subtree() {
+        let state = crate::init::test_db_state(crate::AiConfig::default()).await;
+        let db = &state.db;
+        let user_id = Uuid::now_v7();
+        let root_id = crate::init::test_root(db, &user_id).await;
+        let hash = format!("test-{}", Uuid::now_v7());
+        sqlx::query!(
+            "INSERT INTO image_blobs (hash, storage_path, size, mime_type) VALUES ($1, $2, 1, 'image/png')",
+            hash,
+            format!("blobs/{}.png", hash)
+        )
+        .execute(db)
+        .await
+        .unwrap();
+        let branch = |label: &str| serde_json::json!({ "label": label });
+        let floor = insert_node(db, &user_id, &root_id, NodeType::Branch, branch("Floor 2")).await;
+        let room = insert_node(db, &user_id, &floor, NodeType::Branch, branch("Room 21")).await;
+        let image = serde_json::json!({ "url": "x", "storage_path": format!("blobs/{}.png", hash), "hash": hash });
+        insert_node(db, &user_id, &room, NodeType::ImageLeaf, image).await;
+        assert_eq!(blob_refs(db, &hash).await, 1);
+
+        let copier = Uuid::now_v7();
+        let mut tx = db.begin().await.unwrap();
+        let copies = duplicate_subtree(&mut tx, &copier, &floor, &root_id, Some("Floor 3"), true).await.unwrap();
+        tx.commit().await.unwrap();
+
+        assert_eq!(copies.len(), 3);
+        let copy_of = |node_type| copies.iter().find(|copy| copy.node_type == node_type).unwrap();
+        let leaf = copy_of(NodeType::ImageLeaf);
+        assert!(leaf.content_addressed);
+        let new_room = copies.iter().find(|copy| Some(copy.id) == leaf.parent_id).unwrap();
+        let new_floor = copies.iter().find(|copy| Some(copy.id) == new_room.parent_id).unwrap();
+        assert_eq!(new_floor.parent_id, Some(root_id));
+        let labels = sqlx::query!(
+            r#"SELECT data->>'label' AS "label!", user_id FROM tree_nodes WHERE id = ANY($1) ORDER BY data->>'label'"#,
+            &[new_floor.id, new_room.id][..]
+        )
+        .fetch_all(db)
+        .await
+        .unwrap();
+        assert_eq!(labels[0].label, "Floor 3");
+        assert_eq!(labels[1].label, "Room 21");
+        assert!(labels.iter().all(|row| row.user_id == Some(copier)));
+        // The copied leaf shares the blob, so it takes a reference
+        assert_eq!(blob_refs(db, &hash).await, 2);
+
+        let mut tx = db.begin().await.unwrap();
+        let copies = duplicate_subtree(&mut tx, &user_id, &floor, &root_id, None, false).await.unwrap();
+        tx.commit().await.unwrap();
+        assert_eq!(copies.len(), 2);
+        assert!(copies.iter().all(|copy| copy.node_type == NodeType::Branch));
+        assert_eq!(blob_refs(db, &hash).await, 2);
+
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(db)
+            .await
+            .unwrap();
+        assert_eq!(blob_refs(db, &hash).await, 0);
+        sqlx::query!("DELETE FROM image_blobs WHERE hash = $1", hash)
+            .execute(db)
+            .await
+            .unwrap();
+    }
+}
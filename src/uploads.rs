@@ -0,0 +1,1244 @@
+use std::sync::Arc;
+use axum::{
+    Json,
+    extract::{multipart::Field, Multipart, Path, Query, State},
+    http::StatusCode,
+};
+use bytes::Bytes;
+use futures::StreamExt;
+use image::ImageFormat;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use sqlx::Connection;
+use uuid::Uuid;
+use crate::blob_store::BlobUpload;
+use crate::error::*;
+use crate::handlers::{CurrentUser, RequestLanguage};
+use crate::image_import;
+use crate::image_quality::ImageQuality;
+use crate::models::*;
+use crate::photo_metadata::{self, PhotoMetadata};
+use crate::storage::*;
+use crate::{jobs, tree};
+
+// ============================================================================
+// Uploads
+// ============================================================================
+
+#[derive(Debug, Default, Deserialize)]
+pub struct UploadParams {
+    pub parent_id: Option<Uuid>,
+}
+
+pub async fn upload_image_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    RequestLanguage(language): RequestLanguage,
+    Query(params): Query<UploadParams>,
+    mut multipart: Multipart,
+) -> Result<Json<UploadResponse>> {
+    if let Some(parent_id) = &params.parent_id {
+        tree::require_access(&state.db, Some(&user_id.to_string()), parent_id).await?;
+    }
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| AppError::bad_request(format!("Multipart: {}", e)))?
+    {
+        if field.name() == Some("image") {
+            let filename = field
+                .file_name()
+                .ok_or_else(|| AppError::bad_request("Missing filename"))?
+                .to_string();
+
+            let staged = stage_upload(&state, field, &filename).await?;
+            let response = create_image_leaf(&state, &user_id, params.parent_id, staged, &language).await?;
+            return Ok(Json(response));
+        }
+    }
+
+    Err(AppError::bad_request("No image field"))
+}
+
+/// Leading bytes kept from each upload for format checks
+const HEAD_BYTES: usize = 128 * 1024;
+
+/// An upload streamed to staging storage and hashed on the way in
+pub struct StagedUpload {
+    upload: Box<dyn BlobUpload>,
+    /// Sniffed from the content, not taken from the file name
+    pub format: ImageFormat,
+    /// Hash and size of the stored bytes, which differ from the received
+    /// ones when GPS tags were stripped
+    pub hash: String,
+    pub size: u64,
+    pub metadata: PhotoMetadata,
+}
+
+impl StagedUpload {
+    pub fn mime_type(&self) -> &'static str {
+        self.format.to_mime_type()
+    }
+
+    /// Drops the staged object of an upload that won't be stored
+    async fn discard(self) {
+        if let Err(e) = self.upload.abort().await {
+            log::warn!("Failed to abort staged upload {}: {}", self.hash, e);
+        }
+    }
+}
+
+/// Reads the next chunk of an upload, enforcing `max_image_bytes` across
+/// the whole upload as the data arrives
+async fn next_chunk(
+    chunks: &mut (impl futures::Stream<Item = Result<Bytes>> + Unpin),
+    received: &mut u64,
+    max: u64,
+) -> Result<Option<Bytes>> {
+    let chunk = chunks.next().await.transpose()?;
+    if let Some(chunk) = &chunk {
+        *received += chunk.len() as u64;
+        if *received > max {
+            return Err(AppError::new(
+                ErrorCode::PayloadTooLarge,
+                format!("Image too large (max {} bytes)", max),
+            ));
+        }
+    }
+    Ok(chunk)
+}
+
+/// Streams a multipart field into storage without buffering it
+async fn stage_upload(state: &AppState, field: Field<'_>, filename: &str) -> Result<StagedUpload> {
+    let chunks = field.map(|chunk| chunk.map_err(|e| AppError::bad_request(format!("Read: {}", e))));
+    stage_stream(state, chunks, filename).await
+}
+
+/// Streams an upload into storage. Only the head is held back until it has
+/// been inspected and, if needed, rewritten.
+async fn stage_stream(
+    state: &AppState,
+    chunks: impl futures::Stream<Item = Result<Bytes>>,
+    filename: &str,
+) -> Result<StagedUpload> {
+    let mut chunks = std::pin::pin!(chunks);
+    let max = state.upload_config.max_image_bytes;
+    let mut received = 0u64;
+
+    let mut head = Vec::new();
+    let mut rest = Bytes::new();
+    while head.len() < HEAD_BYTES {
+        let Some(mut chunk) = next_chunk(&mut chunks, &mut received, max).await? else {
+            break;
+        };
+        let take = chunk.len().min(HEAD_BYTES - head.len());
+        head.extend_from_slice(&chunk.split_to(take));
+        rest = chunk;
+    }
+    let prepared = prepare_head(state, head)?;
+
+    let mut upload = state.storage.begin_upload(prepared.format.to_mime_type()).await?;
+    let mut writer = HashingWriter {
+        upload: upload.as_mut(),
+        hasher: Sha256::new(),
+        size: 0,
+    };
+    let streamed = async {
+        writer.write(Bytes::from(prepared.data)).await?;
+        if !rest.is_empty() {
+            writer.write(rest).await?;
+        }
+        while let Some(chunk) = next_chunk(&mut chunks, &mut received, max).await? {
+            writer.write(chunk).await?;
+        }
+        Ok::<_, AppError>((hex::encode(writer.hasher.finalize()), writer.size))
+    }
+    .await;
+
+    match streamed {
+        Ok((hash, size)) => Ok(StagedUpload {
+            upload,
+            format: prepared.format,
+            hash,
+            size,
+            metadata: prepared.metadata,
+        }),
+        Err(e) => {
+            if let Err(abort) = upload.abort().await {
+                log::warn!("Failed to abort staged upload of {}: {}", filename, abort);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Hashes and counts everything written to a staged upload
+struct HashingWriter<'a> {
+    upload: &'a mut dyn BlobUpload,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl HashingWriter<'_> {
+    async fn write(&mut self, data: Bytes) -> Result<()> {
+        self.hasher.update(&data);
+        self.size += data.len() as u64;
+        self.upload.write(data).await
+    }
+}
+
+/// The start of an upload, ready to be stored
+struct PreparedHead {
+    data: Vec<u8>,
+    format: ImageFormat,
+    metadata: PhotoMetadata,
+}
+
+/// Inspects the start of an upload, reads its EXIF and, when configured,
+/// removes GPS tags from the bytes that get stored
+fn prepare_head(state: &AppState, head: Vec<u8>) -> Result<PreparedHead> {
+    let info = state.image_processor.inspect(&head)?;
+    let metadata = PhotoMetadata::read(&head);
+
+    let data = if state.upload_config.strip_gps && metadata.gps.is_some() {
+        photo_metadata::strip_gps_jpeg(&head).unwrap_or_else(|| {
+            log::warn!("Could not strip GPS tags from upload, storing it unchanged");
+            head
+        })
+    } else {
+        head
+    };
+
+    Ok(PreparedHead {
+        data,
+        format: info.format,
+        metadata,
+    })
+}
+
+/// Stages an image that is already in memory
+async fn stage_bytes(state: &AppState, data: Vec<u8>) -> Result<StagedUpload> {
+    let prepared = prepare_head(state, data)?;
+    let stored = Bytes::from(prepared.data);
+    let mut upload = state.storage.begin_upload(prepared.format.to_mime_type()).await?;
+    if let Err(e) = upload.write(stored.clone()).await {
+        let _ = upload.abort().await;
+        return Err(e);
+    }
+    Ok(StagedUpload {
+        upload,
+        format: prepared.format,
+        hash: state.storage.compute_hash(&stored),
+        size: stored.len() as u64,
+        metadata: prepared.metadata,
+    })
+}
+
+/// Stores an upload as an ImageLeaf, sharing the blob with any earlier
+/// upload of the same content. `language` is the uploader's, for a
+/// description queued by the tree's settings.
+async fn create_image_leaf(
+    state: &Arc<AppState>,
+    user_id: &Uuid,
+    parent_id: Option<Uuid>,
+    staged: StagedUpload,
+    language: &str,
+) -> Result<UploadResponse> {
+    let mut tx = state.db.begin().await?;
+    let mut leaf = insert_image_leaf(&mut tx, state, user_id, parent_id, staged, language).await?;
+    if let Err(e) = tx.commit().await {
+        discard_new_blobs(state, std::slice::from_ref(&leaf)).await;
+        return Err(e.into());
+    }
+
+    leaf.score_quality(state).await;
+    leaf.spawn_background(state);
+    Ok(leaf.response)
+}
+
+/// An ImageLeaf inserted by a transaction that may not have committed yet
+struct InsertedLeaf {
+    response: UploadResponse,
+    hash: String,
+    /// Whether this insert stored the blob, which then has to be removed
+    /// again if the transaction rolls back
+    new_blob: bool,
+    /// Whether the content is new to the server and still has to be
+    /// analysed for quality
+    unscored: bool,
+}
+
+impl InsertedLeaf {
+    /// Analyses new content once the leaf is committed, so the upload's
+    /// transaction doesn't hold the blob row while the image is downloaded
+    /// and decoded. Images that can't be analysed stay unscored rather
+    /// than failing an upload that is already stored.
+    async fn score_quality(&mut self, state: &AppState) {
+        if !self.unscored {
+            return;
+        }
+        match analyse_quality(state, &self.response.storage_path).await {
+            Ok(quality) => {
+                let stored = async {
+                    sqlx::query!(
+                        "UPDATE tree_nodes SET data = jsonb_set(data, '{quality}', $2) WHERE id = $1",
+                        self.response.node_id,
+                        serde_json::to_value(&quality)?
+                    )
+                    .execute(&state.db)
+                    .await?;
+                    Ok::<_, AppError>(())
+                }
+                .await;
+                if let Err(e) = stored {
+                    log::warn!("Storing quality of {} failed: {}", self.hash, e);
+                }
+                self.response.warnings = quality.warnings;
+            }
+            Err(e) => log::warn!("Quality analysis of {} failed: {}", self.hash, e),
+        }
+    }
+
+    /// Renditions and the perceptual hash are generated once the leaf is
+    /// committed, and a queued description can be picked up
+    fn spawn_background(&self, state: &Arc<AppState>) {
+        if self.response.describe_job.is_some() {
+            state.jobs.wake();
+        }
+        if self.response.duplicate {
+            return;
+        }
+        let background = state.clone();
+        let (hash, original_path) = (self.hash.clone(), self.response.storage_path.clone());
+        tokio::spawn(async move {
+            if let Err(e) = generate_renditions(&background, &hash, &original_path).await {
+                log::warn!("Renditions of {} failed: {}", hash, e);
+            }
+        });
+    }
+}
+
+/// Deletes blobs stored by leaves whose transaction didn't commit
+async fn discard_new_blobs(state: &AppState, leaves: &[InsertedLeaf]) {
+    let paths = leaves
+        .iter()
+        .filter(|leaf| leaf.new_blob)
+        .map(|leaf| leaf.response.storage_path.clone())
+        .collect();
+    if let Err(e) = state.storage.delete_batch(paths).await {
+        log::warn!("Failed to discard uploaded blobs: {}", e);
+    }
+}
+
+async fn insert_image_leaf(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    state: &AppState,
+    user_id: &Uuid,
+    parent_id: Option<Uuid>,
+    staged: StagedUpload,
+    language: &str,
+) -> Result<InsertedLeaf> {
+    let mime_type = staged.mime_type();
+    let StagedUpload { upload, format, hash, size, metadata } = staged;
+
+    let existing = sqlx::query!(
+        r#"
+        SELECT id, data FROM tree_nodes
+        WHERE node_type = 'ImageLeaf'
+          AND data->>'hash' = $1
+          AND user_id IS NOT DISTINCT FROM $2
+          AND parent_id IS NOT DISTINCT FROM $3
+        LIMIT 1
+        "#,
+        hash,
+        user_id,
+        parent_id
+    )
+        .fetch_optional(&mut **tx)
+        .await?;
+
+    if let Some(existing) = existing {
+        upload.abort().await?;
+        let url = existing.data["url"].as_str().unwrap_or_default().to_string();
+        return match state.upload_config.duplicate_policy {
+            DuplicatePolicy::Reuse => Ok(InsertedLeaf {
+                response: UploadResponse {
+                    node_id: existing.id,
+                    url,
+                    storage_path: existing.data["storage_path"].as_str().unwrap_or_default().to_string(),
+                    size: existing.data["size"].as_u64().unwrap_or_default(),
+                    duplicate: true,
+                    warnings: serde_json::from_value::<ImageQuality>(existing.data["quality"].clone())
+                        .map(|quality| quality.warnings)
+                        .unwrap_or_default(),
+                    describe_job: None,
+                },
+                hash,
+                new_blob: false,
+                unscored: false,
+            }),
+            DuplicatePolicy::Reject => Err(AppError::conflict("Image already uploaded")
+                .with_details(serde_json::json!({ "node_id": existing.id, "url": url }))),
+        };
+    }
+
+    // The upsert locks the blob row, so a concurrent delete of its last
+    // reference can't remove the object while this leaf is being added
+    let blob = sqlx::query!(
+        r#"
+        INSERT INTO image_blobs (hash, storage_path, size, mime_type)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (hash) DO UPDATE SET hash = EXCLUDED.hash
+        RETURNING storage_path, (xmax = 0) AS "inserted!"
+        "#,
+        hash,
+        StorageService::content_path(&hash, format),
+        size as i64,
+        mime_type
+    )
+        .fetch_one(&mut **tx)
+        .await?;
+
+    if blob.inserted {
+        upload.commit(&blob.storage_path).await?;
+    } else {
+        upload.abort().await?;
+    }
+
+    let node_id = Uuid::now_v7();
+    let url = state.storage.public_url(&blob.storage_path);
+    let mut leaf = InsertedLeaf {
+        response: UploadResponse {
+            node_id,
+            url: url.clone(),
+            storage_path: blob.storage_path.clone(),
+            size,
+            duplicate: false,
+            warnings: Vec::new(),
+            describe_job: None,
+        },
+        hash: hash.clone(),
+        new_blob: blob.inserted,
+        unscored: false,
+    };
+
+    // The blob may be stored by now, so every failure from here on has to
+    // discard it
+    let inserted = async {
+        let quality = known_quality(tx, &hash).await?;
+        let mut data = serde_json::json!({
+            "url": url,
+            "storage_path": blob.storage_path,
+            "size": size,
+            "mime_type": mime_type,
+            "hash": hash,
+        });
+        if !metadata.is_empty() {
+            data["exif"] = serde_json::to_value(&metadata)?;
+        }
+        if let Some(quality) = &quality {
+            data["quality"] = serde_json::to_value(quality)?;
+        }
+        sqlx::query!(
+            r#"
+            INSERT INTO tree_nodes (id, user_id, parent_id, node_type, data, captured_at, latitude, longitude,
+                                    language, perceptual_hash)
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9,
+                    (SELECT perceptual_hash FROM tree_nodes
+                     WHERE node_type = 'ImageLeaf' AND data->>'hash' = $5::jsonb->>'hash'
+                       AND perceptual_hash IS NOT NULL
+                     LIMIT 1))
+            "#,
+            node_id,
+            user_id,
+            parent_id,
+            NodeType::ImageLeaf as NodeType,
+            data,
+            metadata.captured_at,
+            metadata.gps.map(|gps| gps.latitude),
+            metadata.gps.map(|gps| gps.longitude),
+            language
+        )
+            .execute(&mut **tx)
+            .await?;
+        let describe_job = jobs::auto_describe(tx, user_id, parent_id, &node_id, language).await?;
+        Ok::<_, AppError>((quality, describe_job))
+    }
+    .await;
+
+    match inserted {
+        Ok((quality, describe_job)) => {
+            match quality {
+                Some(quality) => leaf.response.warnings = quality.warnings,
+                None => leaf.unscored = true,
+            }
+            leaf.response.describe_job = describe_job;
+            Ok(leaf)
+        }
+        Err(e) => {
+            discard_new_blobs(state, std::slice::from_ref(&leaf)).await;
+            Err(e)
+        }
+    }
+}
+
+/// Quality recorded for another leaf showing the same content
+async fn known_quality(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, hash: &str) -> Result<Option<ImageQuality>> {
+    let known = sqlx::query_scalar!(
+        r#"
+        SELECT data->'quality' AS "quality!" FROM tree_nodes
+        WHERE node_type = 'ImageLeaf' AND data->>'hash' = $1 AND data ? 'quality'
+        LIMIT 1
+        "#,
+        hash
+    )
+        .fetch_optional(&mut **tx)
+        .await?;
+    Ok(known.and_then(|quality| serde_json::from_value(quality).ok()))
+}
+
+// ============================================================================
+// Batch Uploads
+// ============================================================================
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BatchUploadParams {
+    pub parent_id: Option<Uuid>,
+    /// Keep nothing unless every file succeeds
+    #[serde(default)]
+    pub atomic: bool,
+}
+
+/// Uploads several images, reporting on each. Files are staged in storage
+/// as they arrive and their leaves inserted afterwards, in one short
+/// transaction, so no blob rows stay locked while the client is sending.
+/// A failing file is rolled back to its savepoint, or in atomic mode rolls
+/// back the whole batch including stored blobs.
+///
+/// POST /api/images/batch?parent_id=...&atomic=true
+pub async fn batch_upload_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    RequestLanguage(language): RequestLanguage,
+    Query(params): Query<BatchUploadParams>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<BatchUploadResponse>)> {
+    if let Some(parent_id) = &params.parent_id {
+        tree::require_access(&state.db, Some(&user_id.to_string()), parent_id).await?;
+    }
+
+    let mut items = Vec::new();
+    let mut staged: Vec<(usize, StagedUpload)> = Vec::new();
+    // The first failure and the index of its file
+    let mut failure: Option<(usize, AppError)> = None;
+
+    let received = async {
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| AppError::bad_request(format!("Multipart: {}", e)))?
+        {
+            if field.name() != Some("images") {
+                continue;
+            }
+            let filename = field.file_name().unwrap_or("image.jpg").to_string();
+            if params.atomic && failure.is_some() {
+                items.push(BatchUploadItem::new(filename, BatchItemStatus::Skipped));
+                continue;
+            }
+
+            match stage_upload(&state, field, &filename).await {
+                Ok(upload) => {
+                    staged.push((items.len(), upload));
+                    items.push(BatchUploadItem::new(filename, BatchItemStatus::Created));
+                }
+                Err(e) => {
+                    log::warn!("Batch upload of {} failed: {}", filename, e);
+                    let mut item = BatchUploadItem::new(filename, BatchItemStatus::Failed);
+                    item.error = Some(e.clone());
+                    failure.get_or_insert((items.len(), e));
+                    items.push(item);
+                }
+            }
+        }
+        Ok::<_, AppError>(())
+    }
+    .await;
+
+    let begun = match received {
+        Ok(()) if params.atomic && failure.is_some() => Ok(None),
+        Ok(()) => state.db.begin().await.map(Some).map_err(AppError::from),
+        Err(e) => Err(e),
+    };
+    let mut tx = match begun {
+        Ok(tx) => tx,
+        Err(e) => {
+            for (_, upload) in staged {
+                upload.discard().await;
+            }
+            return Err(e);
+        }
+    };
+
+    let mut inserted: Vec<(usize, InsertedLeaf)> = Vec::new();
+    let mut pending = staged.into_iter();
+    if let Some(tx) = &mut tx {
+        for (index, upload) in pending.by_ref() {
+            match insert_in_savepoint(tx, &state, &user_id, params.parent_id, upload, &language).await {
+                Ok(leaf) => {
+                    let item = &mut items[index];
+                    if leaf.response.duplicate {
+                        item.status = BatchItemStatus::Duplicate;
+                    }
+                    item.node_id = Some(leaf.response.node_id);
+                    item.url = Some(leaf.response.url.clone());
+                    item.warnings = leaf.response.warnings.clone();
+                    item.describe_job = leaf.response.describe_job;
+                    inserted.push((index, leaf));
+                }
+                Err(e) => {
+                    let item = &mut items[index];
+                    log::warn!("Batch upload of {} failed: {}", item.filename, e);
+                    item.status = BatchItemStatus::Failed;
+                    item.error = Some(e.clone());
+                    failure.get_or_insert((index, e));
+                    if params.atomic {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    if let Some((failed_at, e)) = failure.filter(|_| params.atomic) {
+        // A failed rollback still leaves nothing committed, so the stored
+        // objects are cleaned up either way
+        if let Some(tx) = tx
+            && let Err(e) = tx.rollback().await
+        {
+            log::warn!("Failed to roll back batch upload: {}", e);
+        }
+        // Files received before the failure are undone, later ones were
+        // never tried
+        for (index, upload) in pending {
+            upload.discard().await;
+            items[index].status = if index < failed_at {
+                BatchItemStatus::RolledBack
+            } else {
+                BatchItemStatus::Skipped
+            };
+        }
+        let leaves: Vec<InsertedLeaf> = inserted
+            .into_iter()
+            .map(|(index, leaf)| {
+                let item = &mut items[index];
+                item.status = BatchItemStatus::RolledBack;
+                item.node_id = None;
+                item.url = None;
+                item.warnings.clear();
+                item.describe_job = None;
+                leaf
+            })
+            .collect();
+        discard_new_blobs(&state, &leaves).await;
+
+        let status = StatusCode::from_u16(e.code.http_status()).unwrap_or(StatusCode::BAD_REQUEST);
+        return Ok((status, Json(BatchUploadResponse { committed: false, items })));
+    }
+
+    if let Some(tx) = tx
+        && let Err(e) = tx.commit().await
+    {
+        let leaves: Vec<InsertedLeaf> = inserted.into_iter().map(|(_, leaf)| leaf).collect();
+        discard_new_blobs(&state, &leaves).await;
+        return Err(e.into());
+    }
+    for (index, leaf) in &mut inserted {
+        leaf.score_quality(&state).await;
+        items[*index].warnings = leaf.response.warnings.clone();
+        leaf.spawn_background(&state);
+    }
+
+    Ok((StatusCode::OK, Json(BatchUploadResponse { committed: true, items })))
+}
+
+/// Inserts a staged batch file under a savepoint, so that its failure
+/// undoes only this file
+async fn insert_in_savepoint(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    state: &AppState,
+    user_id: &Uuid,
+    parent_id: Option<Uuid>,
+    staged: StagedUpload,
+    language: &str,
+) -> Result<InsertedLeaf> {
+    let mut savepoint = match tx.begin().await {
+        Ok(savepoint) => savepoint,
+        Err(e) => {
+            staged.discard().await;
+            return Err(e.into());
+        }
+    };
+    let leaf = insert_image_leaf(&mut savepoint, state, user_id, parent_id, staged, language).await?;
+    if let Err(e) = savepoint.commit().await {
+        discard_new_blobs(state, std::slice::from_ref(&leaf)).await;
+        return Err(e.into());
+    }
+    Ok(leaf)
+}
+
+// ============================================================================
+// Direct Uploads and Imports
+// ============================================================================
+
+/// Starts a direct-to-storage upload into a branch
+///
+/// POST /api/images/uploads
+pub async fn presign_upload_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    Json(request): Json<PresignUploadRequest>,
+) -> Result<Json<PresignedUpload>> {
+    tree::require_access(&state.db, Some(&user_id.to_string()), &request.parent_id).await?;
+
+    let parent = sqlx::query!(
+        r#"SELECT node_type as "node_type: NodeType" FROM tree_nodes WHERE id = $1"#,
+        request.parent_id
+    )
+        .fetch_one(&state.db)
+        .await?;
+    if parent.node_type == NodeType::ImageLeaf {
+        return Err(AppError::bad_request("Images can't have children"));
+    }
+
+    let max = state.upload_config.max_image_bytes;
+    if request.size.is_some_and(|size| size > max) {
+        return Err(AppError::new(
+            ErrorCode::PayloadTooLarge,
+            format!("Image too large (max {} bytes)", max),
+        ));
+    }
+
+    // Only the name is known yet; finalize sniffs the actual content
+    let format = ImageFormat::from_path(&request.filename).map_err(|_| {
+        AppError::new(
+            ErrorCode::UnsupportedMediaType,
+            format!("'{}' is not a recognised image file name", request.filename),
+        )
+    })?;
+    state.upload_config.limits.check_format(format)?;
+    let mime_type = format.to_mime_type().to_string();
+
+    let upload_id = Uuid::now_v7();
+    let storage_path = format!("uploads/{}.{}", upload_id, format_extension(format));
+    let expiry = state.upload_config.presign_expiry_secs;
+    let url = state
+        .storage
+        .generate_presigned_upload_url(&storage_path, &mime_type, expiry)
+        .await?;
+    let expires_at = chrono::Utc::now() + chrono::Duration::seconds(i64::from(expiry));
+
+    sqlx::query!(
+        r#"
+        INSERT INTO pending_uploads
+            (id, user_id, parent_id, filename, mime_type, storage_path, expected_size, expected_hash, expires_at)
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        "#,
+        upload_id,
+        user_id,
+        request.parent_id,
+        request.filename,
+        mime_type,
+        storage_path,
+        request.size.map(|size| size as i64),
+        request.hash.map(|hash| hash.to_lowercase()),
+        expires_at
+    )
+        .execute(&state.db)
+        .await?;
+
+    Ok(Json(PresignedUpload {
+        upload_id,
+        url,
+        content_type: mime_type,
+        expires_at: expires_at.to_rfc3339(),
+    }))
+}
+
+/// Checks a direct upload against what was declared and turns it into an ImageLeaf
+///
+/// POST /api/images/uploads/{upload_id}/finalize
+pub async fn finalize_upload_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    RequestLanguage(language): RequestLanguage,
+    Path(upload_id): Path<Uuid>,
+) -> Result<Json<UploadResponse>> {
+    let pending = sqlx::query!(
+        r#"
+        SELECT parent_id, filename, mime_type, storage_path, expected_size, expected_hash
+        FROM pending_uploads WHERE id = $1 AND user_id = $2
+        "#,
+        upload_id,
+        user_id
+    )
+        .fetch_optional(&state.db)
+        .await?
+        .ok_or_else(|| AppError::not_found("Upload"))?;
+    // Access may have been revoked since the upload was started
+    tree::require_access(&state.db, Some(&user_id.to_string()), &pending.parent_id).await?;
+
+    let metadata = state
+        .storage
+        .get_metadata(&pending.storage_path)
+        .await
+        .map_err(|e| match e.code {
+            ErrorCode::NotFound => AppError::bad_request("Nothing has been uploaded yet"),
+            _ => e,
+        })?;
+
+    let max = state.upload_config.max_image_bytes;
+    if metadata.size > max {
+        return Err(AppError::new(
+            ErrorCode::PayloadTooLarge,
+            format!("Image too large (max {} bytes)", max),
+        ));
+    }
+    if let Some(expected) = pending.expected_size
+        && metadata.size != expected as u64
+    {
+        return Err(AppError::validation(format!(
+            "Uploaded {} bytes, expected {}",
+            metadata.size, expected
+        )));
+    }
+    if let Some(content_type) = &metadata.content_type
+        && *content_type != pending.mime_type
+    {
+        return Err(AppError::validation(format!(
+            "Uploaded as {}, expected {}",
+            content_type, pending.mime_type
+        )));
+    }
+
+    // Size and type are known from the metadata; the content is only read
+    // up front when there is a declared hash to check it against
+    let staged = match &pending.expected_hash {
+        Some(expected) => {
+            let data = state.storage.download_image(&pending.storage_path).await?;
+            if state.storage.compute_hash(&data) != *expected {
+                return Err(AppError::validation("Uploaded content does not match the declared hash"));
+            }
+            stage_bytes(&state, data.into()).await?
+        }
+        None => {
+            let chunks = state.storage.stream_image(&pending.storage_path, None).await?;
+            stage_stream(&state, chunks, &pending.filename).await?
+        }
+    };
+    let response = create_image_leaf(&state, &user_id, Some(pending.parent_id), staged, &language).await?;
+
+    sqlx::query!("DELETE FROM pending_uploads WHERE id = $1", upload_id)
+        .execute(&state.db)
+        .await?;
+    state.storage.delete_image(&pending.storage_path).await?;
+
+    Ok(Json(response))
+}
+
+/// Imports an image from a URL into `parent_id`
+///
+/// POST /api/images/import
+pub async fn import_image_handler(
+    State(state): State<Arc<AppState>>,
+    CurrentUser(user_id): CurrentUser,
+    RequestLanguage(language): RequestLanguage,
+    Json(request): Json<ImportImageRequest>,
+) -> Result<Json<UploadResponse>> {
+    tree::require_access(&state.db, Some(&user_id.to_string()), &request.parent_id).await?;
+
+    let parent = sqlx::query!(
+        r#"SELECT node_type as "node_type: NodeType" FROM tree_nodes WHERE id = $1"#,
+        request.parent_id
+    )
+        .fetch_one(&state.db)
+        .await?;
+    if parent.node_type == NodeType::ImageLeaf {
+        return Err(AppError::bad_request("Images can't have children"));
+    }
+
+    let config = &state.upload_config;
+    let data = image_import::fetch_image(&config.import, &request.url, config.max_image_bytes).await?;
+    let staged = stage_bytes(&state, data.to_vec()).await?;
+    let response = create_image_leaf(&state, &user_id, Some(request.parent_id), staged, &language).await?;
+
+    Ok(Json(response))
+}
+
+// ============================================================================
+// Tests
+// ============================================================================
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header;
+
+    async fn multipart(field: &str, filename: &str, data: &[u8]) -> Multipart {
+        multipart_files(field, &[(filename, data)]).await
+    }
+
+    async fn multipart_files(field: &str, files: &[(&str, &[u8])]) -> Multipart {
+        use axum::extract::FromRequest;
+
+        let boundary = "cx58-boundary";
+        let mut body = Vec::new();
+        for (filename, data) in files {
+            body.extend_from_slice(
+                format!(
+                    "--{boundary}\r\nContent-Disposition: form-data; name=\"{field}\"; filename=\"{filename}\"\r\n\r\n"
+                )
+                .as_bytes(),
+            );
+            body.extend_from_slice(data);
+            body.extend_from_slice(b"\r\n");
+        }
+        body.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+
+        let request = axum::http::Request::builder()
+            .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={boundary}"))
+            .body(axum::body::Body::from(body))
+            .unwrap();
+        Multipart::from_request(request, &()).await.unwrap()
+    }
+
+    fn upload_state(max_image_bytes: u64) -> Arc<AppState> {
+        let mut state = (*crate::init::test_state(AiConfig::default())).clone();
+        state.upload_config.max_image_bytes = max_image_bytes;
+        Arc::new(state)
+    }
+
+    #[tokio::test]
+    async fn test_oversized_upload_is_rejected_while_streaming() {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.resize(1024, 0);
+
+        let err = upload_image_handler(
+            State(upload_state(512)),
+            CurrentUser(Uuid::now_v7()),
+            RequestLanguage("en".to_string()),
+            Query(UploadParams::default()),
+            multipart("image", "big.png", &png).await,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::PayloadTooLarge);
+    }
+
+    #[tokio::test]
+    async fn test_non_image_upload_is_rejected() {
+        let err = upload_image_handler(
+            State(upload_state(512)),
+            CurrentUser(Uuid::now_v7()),
+            RequestLanguage("en".to_string()),
+            Query(UploadParams::default()),
+            multipart("image", "notes.jpg", b"just some text").await,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnsupportedMediaType);
+    }
+
+    /// A PNG no other test run uploads, so it gets a blob of its own
+    fn unique_png() -> Vec<u8> {
+        let seed = Uuid::now_v7();
+        let image = image::RgbImage::from_fn(64, 64, |x, y| {
+            let noise = seed.as_bytes()[((x + y) % 16) as usize];
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, noise])
+        });
+        let mut png = std::io::Cursor::new(Vec::new());
+        image.write_to(&mut png, ImageFormat::Png).unwrap();
+        png.into_inner()
+    }
+
+    async fn upload(state: &Arc<AppState>, user_id: Uuid, parent_id: Option<Uuid>, png: &[u8]) -> UploadResponse {
+        upload_image_handler(
+            State(state.clone()),
+            CurrentUser(user_id),
+            RequestLanguage("en".to_string()),
+            Query(UploadParams { parent_id }),
+            multipart("image", "photo.png", png).await,
+        )
+        .await
+        .unwrap()
+        .0
+    }
+
+    async fn blob_refs(state: &AppState, storage_path: &str) -> Option<i32> {
+        sqlx::query_scalar!("SELECT ref_count FROM image_blobs WHERE storage_path = $1", storage_path)
+            .fetch_optional(&state.db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_deleting_a_leaf_keeps_a_shared_blob() {
+        let state = crate::init::test_db_state(AiConfig::default()).await;
+        let user_id = Uuid::now_v7();
+        let root_id = crate::init::test_root(&state.db, &user_id).await;
+        let png = unique_png();
+        let first = upload(&state, user_id, Some(root_id), &png).await;
+        let second = upload(&state, user_id, None, &png).await;
+        assert!(!second.duplicate);
+        assert_eq!(first.storage_path, second.storage_path);
+
+        let delete = |user_id, node_id| delete_image_handler(State(state.clone()), CurrentUser(user_id), Path(node_id));
+        let err = delete(Uuid::now_v7(), first.node_id).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::Forbidden);
+        let member = Uuid::now_v7();
+        sqlx::query!("INSERT INTO node_access (user_id, node_id) VALUES ($1, $2)", member.to_string(), root_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        let err = delete(member, first.node_id).await.unwrap_err();
+        assert_eq!(err.code, ErrorCode::NotFound);
+        assert_eq!(blob_refs(&state, &first.storage_path).await, Some(2));
+
+        assert_eq!(delete(user_id, first.node_id).await.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!(blob_refs(&state, &first.storage_path).await, Some(1));
+        assert!(state.storage.exists(&first.storage_path).await.unwrap());
+
+        assert_eq!(delete(user_id, second.node_id).await.unwrap(), StatusCode::NO_CONTENT);
+        assert_eq!(blob_refs(&state, &first.storage_path).await, None);
+        assert!(!state.storage.exists(&first.storage_path).await.unwrap());
+
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+
+    async fn batch(
+        state: &Arc<AppState>,
+        user_id: Uuid,
+        parent_id: Uuid,
+        atomic: bool,
+        files: &[(&str, &[u8])],
+    ) -> (StatusCode, BatchUploadResponse) {
+        let (status, Json(response)) = batch_upload_handler(
+            State(state.clone()),
+            CurrentUser(user_id),
+            RequestLanguage("en".to_string()),
+            Query(BatchUploadParams { parent_id: Some(parent_id), atomic }),
+            multipart_files("images", files).await,
+        )
+        .await
+        .unwrap();
+        (status, response)
+    }
+
+    async fn leaves_under(state: &AppState, parent_id: Uuid) -> i64 {
+        sqlx::query_scalar!(r#"SELECT count(*) AS "count!" FROM tree_nodes WHERE parent_id = $1"#, parent_id)
+            .fetch_one(&state.db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_atomic_batch_rolls_back_stored_blobs() {
+        let state = crate::init::test_db_state(AiConfig::default()).await;
+        let mut state = (*state).clone();
+        state.upload_config.duplicate_policy = DuplicatePolicy::Reject;
+        let state = Arc::new(state);
+        let user_id = Uuid::now_v7();
+        let root_id = crate::init::test_root(&state.db, &user_id).await;
+        let (first, last) = (unique_png(), unique_png());
+
+        // The second copy conflicts with the first, whose blob is stored by then
+        let files: [(&str, &[u8]); 3] = [("a.png", &first), ("again.png", &first), ("b.png", &last)];
+        let (status, response) = batch(&state, user_id, root_id, true, &files).await;
+
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(!response.committed);
+        let statuses: Vec<_> = response.items.iter().map(|item| item.status).collect();
+        assert_eq!(
+            statuses,
+            vec![BatchItemStatus::RolledBack, BatchItemStatus::Failed, BatchItemStatus::Skipped]
+        );
+        assert_eq!(response.items[1].error.as_ref().unwrap().code, ErrorCode::Conflict);
+        assert!(response.items[0].node_id.is_none());
+        assert_eq!(leaves_under(&state, root_id).await, 0);
+        let hash = state.storage.compute_hash(&Bytes::from(first));
+        let stored = StorageService::content_path(&hash, ImageFormat::Png);
+        assert_eq!(blob_refs(&state, &stored).await, None);
+        assert!(!state.storage.exists(&stored).await.unwrap());
+
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_batch_keeps_successes_around_failures() {
+        let state = crate::init::test_db_state(AiConfig::default()).await;
+        let user_id = Uuid::now_v7();
+        let root_id = crate::init::test_root(&state.db, &user_id).await;
+        let (first, last) = (unique_png(), unique_png());
+
+        let files: [(&str, &[u8]); 3] = [("a.png", &first), ("notes.jpg", b"just some text"), ("b.png", &last)];
+        let (status, response) = batch(&state, user_id, root_id, false, &files).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert!(response.committed);
+        let statuses: Vec<_> = response.items.iter().map(|item| item.status).collect();
+        assert_eq!(
+            statuses,
+            vec![BatchItemStatus::Created, BatchItemStatus::Failed, BatchItemStatus::Created]
+        );
+        assert_eq!(response.items[1].error.as_ref().unwrap().code, ErrorCode::UnsupportedMediaType);
+        assert_eq!(leaves_under(&state, root_id).await, 2);
+
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        let hashes = [first, last].map(|png| state.storage.compute_hash(&Bytes::from(png)));
+        sqlx::query!("DELETE FROM image_blobs WHERE hash = ANY($1)", &hashes[..])
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+
+    async fn describe_jobs(state: &AppState, user_id: Uuid) -> Vec<serde_json::Value> {
+        sqlx::query_scalar!("SELECT payload FROM jobs WHERE user_id = $1 AND kind = 'describe'", user_id)
+            .fetch_all(&state.db)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_uploads_queue_descriptions_when_the_root_asks() {
+        let state = crate::init::test_db_state(AiConfig::default()).await;
+        let mut state = (*state).clone();
+        state.upload_config.duplicate_policy = DuplicatePolicy::Reject;
+        let state = Arc::new(state);
+        let user_id = Uuid::now_v7();
+        let (described, plain) = (
+            crate::init::test_root(&state.db, &user_id).await,
+            crate::init::test_root(&state.db, &user_id).await,
+        );
+        sqlx::query!(
+            r#"UPDATE tree_nodes SET data = data || '{"settings": {"auto_describe": true}}' WHERE id = $1"#,
+            described
+        )
+        .execute(&state.db)
+        .await
+        .unwrap();
+        let upload_to = |parent_id, png: Vec<u8>| {
+            let state = state.clone();
+            async move {
+                upload_image_handler(
+                    State(state),
+                    CurrentUser(user_id),
+                    RequestLanguage("de".to_string()),
+                    Query(UploadParams { parent_id: Some(parent_id) }),
+                    multipart("image", "photo.png", &png).await,
+                )
+                .await
+                .unwrap()
+                .0
+            }
+        };
+
+        let response = upload_to(plain, unique_png()).await;
+        assert!(response.describe_job.is_none());
+        assert!(describe_jobs(&state, user_id).await.is_empty());
+
+        let response = upload_to(described, unique_png()).await;
+        assert!(response.describe_job.is_some());
+        assert_eq!(
+            describe_jobs(&state, user_id).await,
+            vec![serde_json::json!({ "type": "describe", "node_id": response.node_id, "language": "de" })]
+        );
+
+        // Jobs of a batch that rolls back go with it
+        let png = unique_png();
+        let files: [(&str, &[u8]); 2] = [("a.png", &png), ("again.png", &png)];
+        let (status, _) = batch(&state, user_id, described, true, &files).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(describe_jobs(&state, user_id).await.len(), 1);
+
+        sqlx::query!("DELETE FROM jobs WHERE user_id = $1", user_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        for root_id in [described, plain] {
+            sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+                .execute(&state.db)
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs DATABASE_URL"]
+    async fn test_finalize_checks_access_again() {
+        let state = crate::init::test_db_state(AiConfig::default()).await;
+        let (owner, member) = (Uuid::now_v7(), Uuid::now_v7());
+        let root_id = crate::init::test_root(&state.db, &owner).await;
+        let grant = || {
+            sqlx::query!("INSERT INTO node_access (user_id, node_id) VALUES ($1, $2)", member.to_string(), root_id)
+                .execute(&state.db)
+        };
+        grant().await.unwrap();
+
+        let request = PresignUploadRequest {
+            parent_id: root_id,
+            filename: "photo.png".to_string(),
+            size: None,
+            hash: None,
+        };
+        let presigned = presign_upload_handler(State(state.clone()), CurrentUser(member), Json(request))
+            .await
+            .unwrap();
+        let png = unique_png();
+        let storage_path = format!("uploads/{}.png", presigned.upload_id);
+        state
+            .storage
+            .upload_blob(&storage_path, Bytes::from(png.clone()), "image/png")
+            .await
+            .unwrap();
+
+        let finalize = || {
+            finalize_upload_handler(
+                State(state.clone()),
+                CurrentUser(member),
+                RequestLanguage("en".to_string()),
+                Path(presigned.upload_id),
+            )
+        };
+        sqlx::query!("DELETE FROM node_access WHERE node_id = $1", root_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+        assert_eq!(finalize().await.unwrap_err().code, ErrorCode::Forbidden);
+        assert_eq!(leaves_under(&state, root_id).await, 0);
+
+        grant().await.unwrap();
+        let response = finalize().await.unwrap().0;
+        assert_eq!(response.size, png.len() as u64);
+        assert_eq!(leaves_under(&state, root_id).await, 1);
+        assert!(!state.storage.exists(&storage_path).await.unwrap());
+
+        sqlx::query!("DELETE FROM tree_nodes WHERE id = $1", root_id)
+            .execute(&state.db)
+            .await
+            .unwrap();
+    }
+}